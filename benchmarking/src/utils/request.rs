@@ -1,4 +1,4 @@
-use reqwest::Client;
+use reqwest::{header::ACCEPT, Client};
 use serde_derive::{Deserialize, Serialize};
 use std::{fmt, str::FromStr, time::Duration};
 use tokio::time::{sleep, Instant};
@@ -121,17 +121,24 @@ async fn make_request(
         ("base_image", base_image),
     ];
 
-    let resp = client.post(url).form(&payload).send().await?;
+    let mut request = client.post(url).header(ACCEPT, "application/json");
+    // Servers with API keys configured reject requests without one.
+    if let Ok(api_key) = std::env::var("NEBULA_API_KEY") {
+        request = request.header("X-API-Key", api_key);
+    }
+
+    let resp = request.form(&payload).send().await?;
 
     if resp.status().is_success() {
         let response = resp.json::<FunctionResponse>().await?;
         return Ok(response.results);
     }
 
-    let error = resp.error_for_status();
+    let status = resp.status();
+    let error = resp.json::<ErrorResponse>().await;
     eprintln!(
-        "Request for input {} failed, message: {:?}",
-        input_value, error
+        "Request for input {} failed with {}, message: {:?}",
+        input_value, status, error
     );
 
     Ok(vec![])
@@ -142,6 +149,17 @@ pub struct FunctionResponse {
     results: Vec<FunctionResult>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    error: ErrorBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+    code: String,
+    message: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FunctionResult {
     pub metrics: Option<Metrics>,
@@ -150,6 +168,8 @@ pub struct FunctionResult {
     pub func_name: String,
     pub input: String,
     pub base_image: String,
+    #[serde(default)]
+    pub error: Option<String>,
 }
 
 impl fmt::Display for FunctionResult {
@@ -2,24 +2,27 @@ use anyhow::{Context, Error, Result};
 
 use std::{
     any::type_name,
-    fmt::Display,
+    fmt::{Display, Write},
     io::{stdin, BufRead},
+    process,
     str::FromStr,
 };
 
+/// Version of the output frame understood by `nebula_lib::protocol`.
+const PROTOCOL_VERSION: u32 = 1;
+
 mod docker {
     use std::time::{SystemTime, UNIX_EPOCH};
 
-    pub fn get_epoch_timestamp() -> Option<String> {
+    pub fn get_epoch_timestamp() -> Option<u64> {
         let now = SystemTime::now();
         let dur_since_epoch = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
-        let msecs_since_epoch = dur_since_epoch.as_micros();
-        Some(format!("{}", msecs_since_epoch))
+        Some(dur_since_epoch.as_micros() as u64)
     }
 }
 
 mod wasm {
-    pub fn get_epoch_timestamp() -> Option<String> {
+    pub fn get_epoch_timestamp() -> Option<u64> {
         None
     }
 }
@@ -44,11 +47,54 @@ fn get_stdin<T: FromStr>() -> Result<T> {
         .map_err(|_| Error::msg(format!("Failed to parse input as {}", type_name::<T>())))
 }
 
-fn print_stdout<T: Display>(output: T, timestamp: Option<String>) {
-    match timestamp {
-        Some(timestamp) => println!("{}|{}", output, timestamp),
-        None => println!("{}", output),
+/// Output frame printed as the last line of stdout, see `nebula_lib::protocol`.
+struct OutputFrame<'a> {
+    result: &'a str,
+    started_at: Option<u64>,
+    finished_at: Option<u64>,
+    error: Option<&'a str>,
+    exit_code: i32,
+}
+
+impl Display for OutputFrame<'_> {
+    // Written by hand to keep serde out of the function binaries, which would skew the size and
+    // startup measurements.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{\"nebula\":{},\"result\":{},\"started_at\":{},\"finished_at\":{},\"error\":{},\"exit_code\":{}}}",
+            PROTOCOL_VERSION,
+            json_string(self.result),
+            json_number(self.started_at),
+            json_number(self.finished_at),
+            self.error.map_or("null".to_string(), json_string),
+            self.exit_code
+        )
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
     }
+    escaped.push('"');
+    escaped
+}
+
+fn json_number(value: Option<u64>) -> String {
+    value.map_or("null".to_string(), |value| value.to_string())
 }
 
 pub enum FunctionType {
@@ -56,18 +102,76 @@ pub enum FunctionType {
     Wasm,
 }
 
+impl FunctionType {
+    fn get_epoch_timestamp(&self) -> Option<u64> {
+        match self {
+            FunctionType::Docker => docker::get_epoch_timestamp(),
+            FunctionType::Wasm => wasm::get_epoch_timestamp(),
+        }
+    }
+}
+
 pub fn run_function<T, F, R>(func: F, func_type: FunctionType)
 where
     T: FromStr,
     F: Fn(T) -> R,
     R: std::fmt::Display,
 {
-    let timestamp = match func_type {
-        FunctionType::Docker => docker::get_epoch_timestamp(),
-        FunctionType::Wasm => wasm::get_epoch_timestamp(),
-    };
-
-    let input: T = get_stdin().expect("To parse correctly");
-    let result = func(input);
-    print_stdout(result, timestamp)
+    let started_at = func_type.get_epoch_timestamp();
+
+    // Lets the host time the container start on its own clock, by when this first line arrives.
+    if let FunctionType::Docker = func_type {
+        println!(
+            "{{\"nebula\":{},\"event\":\"start\",\"started_at\":{}}}",
+            PROTOCOL_VERSION,
+            json_number(started_at)
+        );
+    }
+
+    match get_stdin::<T>() {
+        Ok(input) => {
+            let result = func(input).to_string();
+            let frame = OutputFrame {
+                result: &result,
+                started_at,
+                finished_at: func_type.get_epoch_timestamp(),
+                error: None,
+                exit_code: 0,
+            };
+            println!("{}", frame);
+        }
+        Err(err) => {
+            let error = format!("{:#}", err);
+            let frame = OutputFrame {
+                result: "",
+                started_at,
+                finished_at: func_type.get_epoch_timestamp(),
+                error: Some(&error),
+                exit_code: 1,
+            };
+            println!("{}", frame);
+            process::exit(frame.exit_code);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prints_output_frame() {
+        let frame = OutputFrame {
+            result: "top | \"bottom\"\n",
+            started_at: Some(10),
+            finished_at: None,
+            error: None,
+            exit_code: 0,
+        };
+
+        assert_eq!(
+            frame.to_string(),
+            "{\"nebula\":1,\"result\":\"top | \\\"bottom\\\"\\n\",\"started_at\":10,\"finished_at\":null,\"error\":null,\"exit_code\":0}"
+        );
+    }
 }
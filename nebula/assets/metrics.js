@@ -203,3 +203,65 @@ function map_metricsF(metric, module) {
 
   console.log(waasm, doocker);
 }
+
+function emptyAggregated() {
+  return {
+    invocations: 0,
+    avg_startup_time: 0,
+    avg_runtime: 0,
+    avg_total_runtime: 0,
+  };
+}
+
+function addToAverages(aggregated, startup, runtime, total) {
+  const invocations = aggregated.invocations + 1;
+  aggregated.avg_startup_time +=
+    (startup - aggregated.avg_startup_time) / invocations;
+  aggregated.avg_runtime += (runtime - aggregated.avg_runtime) / invocations;
+  aggregated.avg_total_runtime +=
+    (total - aggregated.avg_total_runtime) / invocations;
+  aggregated.invocations = invocations;
+}
+
+// Adds a result streamed from /api/results/stream to the data the charts are drawn from:
+// averages per function in milliseconds, per input and function in microseconds, and the
+// minimum, maximum and average per function and input in microseconds.
+function addResult(metrics, metricsG, metricsF, result) {
+  if (!result.metrics) {
+    return;
+  }
+  const type = result.func_type === "Docker" ? "docker" : "wasm";
+  const startup = result.metrics.startup_time;
+  const total = result.metrics.total_runtime;
+  const runtime = total - startup;
+  const empty = () => ({ docker: emptyAggregated(), wasm: emptyAggregated() });
+
+  metrics[result.func_name] ??= empty();
+  addToAverages(
+    metrics[result.func_name][type],
+    startup / 1000,
+    runtime / 1000,
+    total / 1000,
+  );
+
+  metricsG[result.input] ??= {};
+  metricsG[result.input][result.func_name] ??= empty();
+  const byInput = metricsG[result.input][result.func_name][type];
+  addToAverages(byInput, startup, runtime, total);
+
+  metricsF[result.func_name] ??= { docker: {}, wasm: {} };
+  const stats = (metricsF[result.func_name][type][result.input] ??= {
+    startup: [startup, startup, 0],
+    runtime: [runtime, runtime, 0],
+    total_time: [total, total, 0],
+  });
+  [
+    [stats.startup, startup, byInput.avg_startup_time],
+    [stats.runtime, runtime, byInput.avg_runtime],
+    [stats.total_time, total, byInput.avg_total_runtime],
+  ].forEach(([minMaxAvg, value, avg]) => {
+    minMaxAvg[0] = Math.min(minMaxAvg[0], value);
+    minMaxAvg[1] = Math.max(minMaxAvg[1], value);
+    minMaxAvg[2] = Math.round(avg);
+  });
+}
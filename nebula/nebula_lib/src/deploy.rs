@@ -0,0 +1,228 @@
+//! Validation of uploaded Wasm functions.
+//!
+//! An upload is only accepted if it is a WASI command: it has to export `_start` and may only
+//! import from `wasi_snapshot_preview1`, as that is all the
+//! [`WasmRuntime`](crate::runtime::WasmRuntime) links. Accepted modules are precompiled and stored
+//! by the [`ModuleStore`](crate::module_store::ModuleStore).
+
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{bail, Context, Result};
+use wasmtime::{Engine, ExternType, Module};
+
+use crate::wasm_runner::engine_config;
+
+pub const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+#[derive(Debug)]
+pub enum DeployError {
+    /// The function name or alias can't be used as a file name.
+    InvalidName(String),
+    /// The upload isn't a WASI command the runtime can run.
+    InvalidModule(anyhow::Error),
+    /// No module has been deployed for the function.
+    UnknownFunction(String),
+    /// The function has no version or alias matching the reference.
+    UnknownVersion(String, String),
+    /// A shortened version matches more than one version of the function.
+    AmbiguousVersion(String, String),
+    /// The function's `nebula.toml` can't be parsed.
+    InvalidManifest(anyhow::Error),
+    /// The module was valid, but storing it failed.
+    Storage(anyhow::Error),
+}
+
+impl Display for DeployError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeployError::InvalidName(message) => write!(f, "{}", message),
+            DeployError::InvalidModule(err) => write!(f, "Invalid Wasm module: {:#}", err),
+            DeployError::UnknownFunction(name) => write!(f, "Unknown wasm function {}", name),
+            DeployError::UnknownVersion(name, version) => {
+                write!(f, "{} has no version or alias {}", name, version)
+            }
+            DeployError::AmbiguousVersion(name, version) => write!(
+                f,
+                "{} matches several versions of {}, use a longer prefix",
+                version, name
+            ),
+            DeployError::InvalidManifest(err) => write!(f, "Invalid manifest: {:#}", err),
+            DeployError::Storage(err) => write!(f, "Failed to store module: {:#}", err),
+        }
+    }
+}
+
+impl Error for DeployError {}
+
+/// Where deployed modules are written to.
+#[derive(Debug, Clone)]
+pub struct DeployDirs {
+    /// Precompiled modules the Wasm runtime invokes, as `{name}/{version}.cwasm`.
+    pub serialized_dir: PathBuf,
+    /// Every uploaded module as it was uploaded, as `{name}/{version}.wasm`.
+    pub archive_dir: PathBuf,
+}
+
+/// Names end up in file names, so they're limited to `[a-zA-Z0-9_-]`.
+pub fn validate_name(kind: &str, name: &str) -> Result<(), DeployError> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !valid {
+        return Err(DeployError::InvalidName(format!(
+            "Invalid {} {:?}, use 1 to 64 letters, digits, dashes or underscores",
+            kind, name
+        )));
+    }
+
+    Ok(())
+}
+
+/// Compiles `bytes` and checks that the result is a WASI command.
+pub fn validate_wasm_module(engine: &Engine, bytes: &[u8]) -> Result<Module> {
+    let module = Module::new(engine, bytes).context("Failed to compile module")?;
+
+    if let Some(import) = module
+        .imports()
+        .find(|import| import.module() != WASI_MODULE)
+    {
+        bail!(
+            "Module imports {}::{}, only {} imports are allowed",
+            import.module(),
+            import.name(),
+            WASI_MODULE
+        );
+    }
+
+    let is_command = module
+        .exports()
+        .any(|export| export.name() == "_start" && matches!(export.ty(), ExternType::Func(_)));
+
+    if !is_command {
+        bail!("Module is not a WASI command, it doesn't export a _start function");
+    }
+
+    Ok(module)
+}
+
+/// Validates `bytes` and serializes the compiled module for the Wasm runtime.
+pub fn precompile(bytes: &[u8]) -> Result<Vec<u8>, DeployError> {
+    let engine = Engine::new(&engine_config()).map_err(DeployError::Storage)?;
+    let module = validate_wasm_module(&engine, bytes).map_err(DeployError::InvalidModule)?;
+
+    module.serialize().map_err(DeployError::InvalidModule)
+}
+
+/// Writes through a temporary file renamed over `path`, so readers never see a half written file
+/// and a crash leaves the previous contents. Every write has its own temporary file, so concurrent
+/// writers of the same path don't clobber each other's, the last rename wins.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+
+    let parent = path.parent().context("Path has no parent directory")?;
+    let file_name = path.file_name().context("Path has no file name")?;
+    fs::create_dir_all(parent).with_context(|| format!("Failed to create {:?}", parent))?;
+
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp_path = parent.join(tmp_name);
+
+    let result = fs::write(&tmp_path, contents)
+        .with_context(|| format!("Failed to write {:?}", tmp_path))
+        .and_then(|_| {
+            fs::rename(&tmp_path, path)
+                .with_context(|| format!("Failed to move {:?} to {:?}", tmp_path, path))
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const COMMAND: &str = r#"
+        (module
+          (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+          (memory (export "memory") 1)
+          (func (export "_start")))
+    "#;
+
+    #[test]
+    fn accepts_wasi_commands() {
+        assert!(validate_wasm_module(&Engine::default(), COMMAND.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_modules() {
+        let engine = Engine::default();
+
+        let reactor = r#"(module (func (export "add") (param i32 i32) (result i32) local.get 0))"#;
+        let err = validate_wasm_module(&engine, reactor.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Module is not a WASI command, it doesn't export a _start function"
+        );
+
+        let host_import = r#"
+            (module
+              (import "env" "log" (func))
+              (func (export "_start")))
+        "#;
+        let err = validate_wasm_module(&engine, host_import.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Module imports env::log, only wasi_snapshot_preview1 imports are allowed"
+        );
+
+        assert!(validate_wasm_module(&engine, b"not wasm").is_err());
+    }
+
+    #[test]
+    fn validates_names() {
+        assert!(validate_name("function name", "fibonacci-recursive_2").is_ok());
+        assert!(validate_name("function name", "").is_err());
+        assert!(validate_name("function name", "../factorial").is_err());
+        assert!(validate_name("alias", "1.0").is_err());
+    }
+
+    #[test]
+    fn writes_concurrently_without_temporary_leftovers() {
+        let dir = std::env::temp_dir().join(format!("nebula-write-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("usage.json");
+
+        std::thread::scope(|scope| {
+            for writer in 0..8 {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        write_atomic(path, writer.to_string().as_bytes()).unwrap();
+                    }
+                });
+            }
+        });
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.parse::<u8>().is_ok_and(|writer| writer < 8));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
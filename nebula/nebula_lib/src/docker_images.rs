@@ -0,0 +1,429 @@
+//! Discovery and provisioning of the `nebula-function-{name}-{base}` Docker images.
+//!
+//! Images are shipped to the server as `{name}-{base}.tar.gz` tarballs (see the `build_docker`
+//! target in `examples/Makefile`). Provisioning loads every tarball whose image is missing from the
+//! local engine and records the image digest, so the server knows which Docker functions it can
+//! actually run.
+//!
+//! Invocations look their image up in a listing of the engine's images that is kept for a few
+//! seconds, and dropped whenever an image is loaded or removed, rather than asking the engine every
+//! time.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{list_files::list_files, runtime::RuntimeUnavailable};
+
+pub const IMAGE_PREFIX: &str = "nebula-function-";
+const TARBALL_EXTENSION: &str = ".tar.gz";
+/// How long a listing of the engine's images is used to resolve invocations.
+const LISTING_TTL: Duration = Duration::from_secs(5);
+
+static LISTING: Mutex<Option<(Instant, Vec<DockerImage>)>> = Mutex::new(None);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DockerImage {
+    pub func_name: String,
+    pub base_image: String,
+    pub image_name: String,
+    /// Image id as reported by `docker image inspect`, `None` if the image is not loaded.
+    pub digest: Option<String>,
+}
+
+impl DockerImage {
+    pub fn new(func_name: &str, base_image: &str) -> Self {
+        DockerImage {
+            func_name: func_name.to_string(),
+            base_image: base_image.to_string(),
+            image_name: image_name(func_name, base_image),
+            digest: None,
+        }
+    }
+
+    /// Parses a `{name}-{base}.tar.gz` tarball name. The base image is everything after the last
+    /// dash, as function names may contain dashes themselves (`fibonacci-recursive-debian`).
+    pub fn from_tarball(path: &Path) -> Option<Self> {
+        let file_name = path.file_name()?.to_str()?;
+        let stem = file_name.strip_suffix(TARBALL_EXTENSION)?;
+        let (func_name, base_image) = stem.rsplit_once('-')?;
+
+        if func_name.is_empty() || base_image.is_empty() {
+            return None;
+        }
+
+        Some(DockerImage::new(func_name, base_image))
+    }
+
+    /// Parses a `nebula-function-{name}-{base}` image name, as listed by the container engine.
+    pub fn from_image_name(image_name: &str) -> Option<Self> {
+        let (func_name, base_image) = image_name.strip_prefix(IMAGE_PREFIX)?.rsplit_once('-')?;
+
+        if func_name.is_empty() || base_image.is_empty() {
+            return None;
+        }
+
+        Some(DockerImage::new(func_name, base_image))
+    }
+}
+
+pub fn image_name(func_name: &str, base_image: &str) -> String {
+    format!("{}{}-{}", IMAGE_PREFIX, func_name, base_image)
+}
+
+/// Returns the image id of `image_name`, or `None` if the engine doesn't have the image.
+pub fn image_digest(image_name: &str) -> Result<Option<String>> {
+    let output = Command::new("docker")
+        .args(["image", "inspect", "--format", "{{.Id}}", image_name])
+        .output()
+        .context("Failed to run docker, is it installed?")?;
+
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let digest = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    Ok(Some(digest).filter(|digest| !digest.is_empty()))
+}
+
+pub fn load_image(tarball: &Path) -> Result<()> {
+    forget_listing();
+    let output = Command::new("docker")
+        .arg("load")
+        .arg("-i")
+        .arg(tarball)
+        .output()
+        .context("Failed to run docker, is it installed?")?;
+
+    if !output.status.success() {
+        bail!(
+            "docker load of {:?} failed: {}",
+            tarball,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Removes `image_name` from the engine, doing nothing if it isn't there.
+pub fn remove_image(image_name: &str) -> Result<()> {
+    forget_listing();
+    if image_digest(image_name)?.is_none() {
+        return Ok(());
+    }
+
+    let output = Command::new("docker")
+        .args(["image", "rm", image_name])
+        .output()
+        .context("Failed to run docker, is it installed?")?;
+
+    if !output.status.success() {
+        bail!(
+            "docker image rm {} failed: {}",
+            image_name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Lists the `nebula-function-*` images the container engine has available. Fails with
+/// [`RuntimeUnavailable`] if the engine can't be asked.
+pub fn list_function_images() -> Result<Vec<DockerImage>> {
+    let output = Command::new("docker")
+        .args([
+            "image",
+            "ls",
+            "--filter",
+            &format!("reference={}*", IMAGE_PREFIX),
+            "--no-trunc",
+            "--format",
+            "{{.Repository}}\t{{.ID}}",
+        ])
+        .output()
+        .map_err(|err| {
+            RuntimeUnavailable(format!("Failed to run docker, is it installed? {}", err))
+        })?;
+
+    if !output.status.success() {
+        return Err(RuntimeUnavailable(format!(
+            "docker image ls failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+        .into());
+    }
+
+    let images = parse_image_listing(&String::from_utf8_lossy(&output.stdout));
+    *lock_listing() = Some((Instant::now(), images.clone()));
+
+    Ok(images)
+}
+
+/// The images of the last listing if it's recent enough, a new listing otherwise.
+fn cached_function_images() -> Result<Vec<DockerImage>> {
+    if let Some((listed_at, images)) = &*lock_listing() {
+        if listed_at.elapsed() < LISTING_TTL {
+            return Ok(images.clone());
+        }
+    }
+
+    list_function_images()
+}
+
+fn forget_listing() {
+    *lock_listing() = None;
+}
+
+fn lock_listing() -> std::sync::MutexGuard<'static, Option<(Instant, Vec<DockerImage>)>> {
+    LISTING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn parse_image_listing(listing: &str) -> Vec<DockerImage> {
+    let mut images: Vec<DockerImage> = listing
+        .lines()
+        .filter_map(|line| {
+            let (repository, id) = line.trim().split_once('\t')?;
+            let mut image = DockerImage::from_image_name(repository)?;
+            image.digest = Some(id.to_string());
+            Some(image)
+        })
+        .collect();
+
+    images.sort_by(|a, b| a.image_name.cmp(&b.image_name));
+    images.dedup_by(|a, b| a.image_name == b.image_name);
+
+    images
+}
+
+/// Function names keyed by the base images they are built on.
+pub fn group_by_base_image(images: &[DockerImage]) -> BTreeMap<String, Vec<String>> {
+    let mut grouped: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for image in images {
+        grouped
+            .entry(image.base_image.clone())
+            .or_default()
+            .push(image.func_name.clone());
+    }
+
+    grouped
+}
+
+/// Base images keyed by the functions built on them.
+pub fn group_by_function(images: &[DockerImage]) -> BTreeMap<String, Vec<String>> {
+    let mut grouped: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for image in images {
+        grouped
+            .entry(image.func_name.clone())
+            .or_default()
+            .push(image.base_image.clone());
+    }
+
+    grouped
+}
+
+/// Looks up the image for `func_name` on `base_image`, failing with the available alternatives if
+/// the engine doesn't have it. An image missing from a cached listing is looked up in a new one,
+/// in case it was loaded since.
+pub fn resolve_image(func_name: &str, base_image: &str) -> Result<DockerImage> {
+    let images = cached_function_images()?;
+    if let Ok(image) = find_image(&images, func_name, base_image) {
+        return Ok(image);
+    }
+
+    find_image(&list_function_images()?, func_name, base_image)
+}
+
+fn find_image(images: &[DockerImage], func_name: &str, base_image: &str) -> Result<DockerImage> {
+    if let Some(image) = images
+        .iter()
+        .find(|image| image.func_name == func_name && image.base_image == base_image)
+    {
+        return Ok(image.clone());
+    }
+
+    match group_by_function(images).get(func_name) {
+        Some(base_images) => bail!(
+            "Unknown docker image {}, {} is only available on: {}",
+            image_name(func_name, base_image),
+            func_name,
+            base_images.join(", ")
+        ),
+        None => bail!("Unknown docker function {}", func_name),
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProvisionReport {
+    /// Every image found in the module directory, with digests for the ones that are loaded.
+    pub images: Vec<DockerImage>,
+    /// Image names that were missing and loaded from their tarball during this run.
+    pub loaded: Vec<String>,
+    /// Image names that could not be loaded, with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+impl ProvisionReport {
+    /// Images that are present in the engine and can be run.
+    pub fn available(&self) -> impl Iterator<Item = &DockerImage> {
+        self.images.iter().filter(|image| image.digest.is_some())
+    }
+
+    pub fn functions(&self) -> BTreeSet<String> {
+        self.available()
+            .map(|image| image.func_name.clone())
+            .collect()
+    }
+
+    pub fn base_images(&self) -> BTreeSet<String> {
+        self.available()
+            .map(|image| image.base_image.clone())
+            .collect()
+    }
+}
+
+pub fn find_tarballs(module_dir: &Path) -> Result<Vec<(PathBuf, DockerImage)>> {
+    let files = list_files(
+        module_dir
+            .to_str()
+            .context("Module dir is not valid UTF-8")?,
+    )
+    .with_context(|| format!("Failed to list docker modules in {:?}", module_dir))?;
+
+    let mut tarballs: Vec<(PathBuf, DockerImage)> = files
+        .into_iter()
+        .filter_map(|path| DockerImage::from_tarball(&path).map(|image| (path, image)))
+        .collect();
+
+    tarballs.sort_by(|(_, a), (_, b)| a.image_name.cmp(&b.image_name));
+
+    Ok(tarballs)
+}
+
+/// Scans `module_dir` for image tarballs and loads the ones whose image is missing locally.
+pub fn provision_docker_modules(module_dir: &Path) -> Result<ProvisionReport> {
+    let mut report = ProvisionReport::default();
+
+    for (tarball, mut image) in find_tarballs(module_dir)? {
+        image.digest = image_digest(&image.image_name)?;
+
+        if image.digest.is_none() {
+            match load_image(&tarball).and_then(|_| image_digest(&image.image_name)) {
+                Ok(Some(digest)) => {
+                    image.digest = Some(digest);
+                    report.loaded.push(image.image_name.clone());
+                }
+                Ok(None) => report.failed.push((
+                    image.image_name.clone(),
+                    format!("{:?} does not contain {}", tarball, image.image_name),
+                )),
+                Err(err) => report
+                    .failed
+                    .push((image.image_name.clone(), err.to_string())),
+            }
+        }
+
+        report.images.push(image);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tarball_names() {
+        let image = DockerImage::from_tarball(Path::new(
+            "/modules/docker/fibonacci-recursive-debian.tar.gz",
+        ))
+        .unwrap();
+
+        assert_eq!(image.func_name, "fibonacci-recursive");
+        assert_eq!(image.base_image, "debian");
+        assert_eq!(
+            image.image_name,
+            "nebula-function-fibonacci-recursive-debian"
+        );
+        assert_eq!(image.digest, None);
+    }
+
+    #[test]
+    fn ignores_other_files() {
+        assert_eq!(DockerImage::from_tarball(Path::new("factorial.wasm")), None);
+        assert_eq!(
+            DockerImage::from_tarball(Path::new("factorial.tar.gz")),
+            None
+        );
+        assert_eq!(DockerImage::from_tarball(Path::new("-debian.tar.gz")), None);
+    }
+
+    #[test]
+    fn parses_engine_listing() {
+        let listing = "nebula-function-factorial-debian\tabc123\n\
+                       nebula-function-factorial-ubuntu\tdef456\n\
+                       nebula-function-prime-number-debian\t789abc\n\
+                       some-other-image\t000000\n";
+
+        let images = parse_image_listing(listing);
+
+        assert_eq!(images.len(), 3);
+        assert_eq!(images[0].digest.as_deref(), Some("abc123"));
+        assert_eq!(
+            group_by_base_image(&images),
+            BTreeMap::from([
+                (
+                    "debian".to_string(),
+                    vec!["factorial".to_string(), "prime-number".to_string()]
+                ),
+                ("ubuntu".to_string(), vec!["factorial".to_string()]),
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_images() {
+        let images = vec![DockerImage::new("factorial", "debian")];
+
+        assert!(find_image(&images, "factorial", "debian").is_ok());
+
+        let err = find_image(&images, "factorial", "archlinux").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown docker image nebula-function-factorial-archlinux, factorial is only available on: debian"
+        );
+
+        let err = find_image(&images, "add", "debian").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown docker function add");
+    }
+
+    #[test]
+    fn report_only_lists_loaded_images() {
+        let mut loaded = DockerImage::new("factorial", "ubuntu");
+        loaded.digest = Some("sha256:abc".to_string());
+        let report = ProvisionReport {
+            images: vec![loaded, DockerImage::new("exponential", "debian")],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            report.functions(),
+            BTreeSet::from(["factorial".to_string()])
+        );
+        assert_eq!(report.base_images(), BTreeSet::from(["ubuntu".to_string()]));
+    }
+}
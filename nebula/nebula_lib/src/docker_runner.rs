@@ -1,89 +1,340 @@
 use std::{
-    io::{Error, Result, Write},
-    process::{Command, Stdio},
+    io::{Error, ErrorKind, Read, Result, Write},
+    process::{ChildStdout, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
     time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::models::{FunctionResult, Metrics, ModuleType};
+use tracing::{debug, info_span};
+
+use crate::{
+    manifest::ExecutionLimits,
+    models::{FunctionResult, Metrics, ModuleType},
+    protocol::{is_start_frame, parse_output},
+};
+
+static CONTAINERS: AtomicUsize = AtomicUsize::new(0);
 
 pub fn run_docker_image(
     image_name: &str,
     input: &str,
     func_name: String,
     base_image: String,
+    limits: &ExecutionLimits,
 ) -> Result<FunctionResult> {
     let start_since_epoch = current_micros()?;
     let start = Instant::now();
 
-    println!("{}", image_name);
+    debug!("running image {}", image_name);
+    let execute = info_span!("execute").entered();
+
+    // Named, so the watchdog can kill it once the timeout is up.
+    let container_name = format!(
+        "nebula-{}-{}",
+        std::process::id(),
+        CONTAINERS.fetch_add(1, Ordering::Relaxed)
+    );
 
     let mut child = Command::new("docker")
-        .args(["run", "--rm", "-i", image_name])
+        .args(docker_args(image_name, &container_name, limits))
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()?;
 
+    let spawned = Instant::now();
     let cmd_start = current_micros()?;
 
-    child.stdin.as_mut().unwrap().write_all(input.as_bytes())?;
+    let (done, watchdog) = mpsc::channel::<()>();
+    let timed_out = limits.timeout.map(|timeout| {
+        let container_name = container_name.clone();
+        thread::spawn(move || match watchdog.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                let _ = Command::new("docker")
+                    .args(["kill", &container_name])
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status();
+                true
+            }
+            _ => false,
+        })
+    });
+
+    // Feed stdin and drain stderr on their own threads, so the first byte on stdout is seen as
+    // soon as it arrives.
+    let mut stdin = child.stdin.take().expect("stdin to be piped");
+    let input_bytes = input.as_bytes().to_vec();
+    let writer = thread::spawn(move || stdin.write_all(&input_bytes));
+
+    let mut stderr = child.stderr.take().expect("stderr to be piped");
+    let stderr_reader = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        stderr.read_to_end(&mut buffer).map(|_| buffer)
+    });
+
+    let (stdout, first_byte) =
+        read_stdout(child.stdout.take().expect("stdout to be piped"), spawned)?;
+
+    let status = child.wait()?;
+    let elapsed_since_spawn = spawned.elapsed().as_micros();
+
+    drop(done);
+    let timed_out = timed_out.is_some_and(|watchdog| watchdog.join().unwrap_or(false));
+
+    match writer.join().expect("stdin writer to not panic") {
+        // The container is free to exit without reading its input.
+        Err(err) if err.kind() != ErrorKind::BrokenPipe => return Err(err),
+        _ => {}
+    }
+    let stderr = stderr_reader.join().expect("stderr reader to not panic")?;
 
-    let output = child.wait_with_output()?;
+    execute.exit();
+    let output_parse = info_span!("output_parse").entered();
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stdout = String::from_utf8_lossy(&stdout);
 
-    let (result, actual_startup) = parse_output(&stdout, cmd_start)?;
+    let ready_marker = stdout.lines().next().is_some_and(is_start_frame);
+
+    let parsed = parse_output(&stdout);
 
     let total_runtime = start.elapsed().as_micros();
 
-    println!(
-        "result: {:?}, cmd_start: {:?}, actual_startup: {:?}",
-        result, cmd_start, actual_startup
+    let timeout_error = timed_out.then(|| {
+        format!(
+            "Timed out after {}ms",
+            limits.timeout.unwrap_or_default().as_millis()
+        )
+    });
+
+    let error = timeout_error.or(parsed.error).or_else(|| {
+        (!status.success()).then(|| {
+            let stderr = String::from_utf8_lossy(&stderr);
+            match stderr.trim().lines().last() {
+                Some(line) => format!("Container {}: {}", status, line),
+                None => format!("Container {}", status),
+            }
+        })
+    });
+
+    let startup = measure_startup(
+        first_byte,
+        ready_marker,
+        parsed.started_at,
+        cmd_start,
+        elapsed_since_spawn,
+    );
+
+    output_parse.exit();
+
+    debug!(
+        "result: {:?}, cmd_start: {:?}, startup: {:?}",
+        parsed.result, cmd_start, startup
     );
 
     Ok(FunctionResult {
-        result,
+        result: parsed.result,
         metrics: Some(Metrics {
-            startup_time: actual_startup,
+            startup_time: startup.startup_time,
             start_since_epoch,
             total_runtime,
             end_since_epoch: start_since_epoch + total_runtime,
-            startup_percentage: ((actual_startup as f64 / total_runtime as f64) * 100.0).round(),
+            startup_percentage: ((startup.startup_time as f64 / total_runtime as f64) * 100.0)
+                .round(),
+            container_startup_time: startup.container_startup_time,
+            startup_anomaly: startup.anomaly,
+            queue_time: None,
         }),
         func_type: ModuleType::Docker,
         func_name,
         input: input.to_string(),
         base_image,
+        error,
+        exit_code: parsed.exit_code.or(status.code()),
+        version: None,
+        trigger: Default::default(),
     })
 }
 
+fn docker_args(image_name: &str, container_name: &str, limits: &ExecutionLimits) -> Vec<String> {
+    let mut args: Vec<String> = ["run", "--rm", "-i", "--name", container_name]
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+    if let Some(memory_limit) = limits.memory_limit {
+        args.extend(["--memory".to_string(), memory_limit.to_string()]);
+    }
+    for (key, value) in &limits.env {
+        args.extend(["--env".to_string(), format!("{}={}", key, value)]);
+    }
+
+    args.push(image_name.to_string());
+    args
+}
+
 pub fn current_micros() -> std::io::Result<u128> {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .map(|duration| duration.as_micros())
-        .map_err(|err| Error::new(std::io::ErrorKind::Other, err))
+        .map_err(Error::other)
 }
 
-fn parse_output(output: &str, cmd_startup: u128) -> Result<(String, u128)> {
-    let mut parts = output.trim().split('|');
-    let result = parts.next().ok_or(Error::new(
-        std::io::ErrorKind::Other,
-        "No result part in output",
-    ))?;
-    // Time inside the docker image what microsecond since epoch it started running, counting as
-    // the duration of the cold start.
-    let actual_startup = parts
-        .next()
-        .ok_or(Error::new(
-            std::io::ErrorKind::Other,
-            "No timestamp part in output",
-        ))?
-        .parse::<u128>()
-        .map_err(|_| {
-            Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Timestamp is not a valid u128",
-            )
-        })?;
-
-    Ok((result.to_string(), (actual_startup) - cmd_startup))
+/// Reads stdout to the end, returning it along with the microseconds from `spawned` until its
+/// first byte arrived.
+fn read_stdout(mut stdout: ChildStdout, spawned: Instant) -> Result<(Vec<u8>, Option<u128>)> {
+    let mut output = Vec::new();
+    let mut buffer = [0; 4096];
+    let mut first_byte = None;
+
+    loop {
+        match stdout.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => {
+                first_byte.get_or_insert_with(|| spawned.elapsed().as_micros());
+                output.extend_from_slice(&buffer[..read]);
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok((output, first_byte))
+}
+
+#[derive(Debug, PartialEq)]
+struct StartupMeasurement {
+    startup_time: u128,
+    container_startup_time: Option<u128>,
+    anomaly: Option<String>,
+}
+
+/// The cold start is the time from spawning `docker run` until the container's first byte reaches
+/// stdout, measured on the host's monotonic clock. Functions built on the output protocol print a
+/// start frame before doing any work, which makes that first byte the moment the container is up.
+///
+/// The container's own timestamp is kept as a cross-check, and is only used as the startup time for
+/// legacy images without a start frame. It is dropped and flagged when it is impossible, i.e.
+/// before the container was spawned or after it exited.
+fn measure_startup(
+    first_byte: Option<u128>,
+    ready_marker: bool,
+    container_started_at: Option<u128>,
+    cmd_start: u128,
+    elapsed_since_spawn: u128,
+) -> StartupMeasurement {
+    let mut anomaly = None;
+
+    let container_startup_time =
+        container_started_at.and_then(|started_at| match started_at.checked_sub(cmd_start) {
+            None => {
+                anomaly = Some(format!(
+                    "Container clock is at least {}µs behind the host",
+                    cmd_start - started_at
+                ));
+                None
+            }
+            Some(startup) if startup > elapsed_since_spawn => {
+                anomaly = Some(format!(
+                    "Container clock is at least {}µs ahead of the host",
+                    startup - elapsed_since_spawn
+                ));
+                None
+            }
+            Some(startup) => Some(startup),
+        });
+
+    let startup_time = match (first_byte, ready_marker) {
+        (Some(first_byte), true) => first_byte,
+        (first_byte, _) => container_startup_time.or(first_byte).unwrap_or(0),
+    };
+
+    StartupMeasurement {
+        startup_time,
+        container_startup_time,
+        anomaly,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn passes_limits_to_docker() {
+        let limits = ExecutionLimits {
+            timeout: Some(Duration::from_secs(1)),
+            memory_limit: Some(64 * 1024 * 1024),
+            env: BTreeMap::from([("MODE".to_string(), "fast".to_string())]),
+        };
+
+        assert_eq!(
+            docker_args("nebula-function-factorial-debian", "nebula-1-0", &limits),
+            [
+                "run",
+                "--rm",
+                "-i",
+                "--name",
+                "nebula-1-0",
+                "--memory",
+                "67108864",
+                "--env",
+                "MODE=fast",
+                "nebula-function-factorial-debian"
+            ]
+        );
+    }
+
+    #[test]
+    fn uses_first_byte_when_function_signals_start() {
+        let startup = measure_startup(Some(800), true, Some(1_000_700), 1_000_000, 5_000);
+
+        assert_eq!(
+            startup,
+            StartupMeasurement {
+                startup_time: 800,
+                container_startup_time: Some(700),
+                anomaly: None,
+            }
+        );
+    }
+
+    #[test]
+    fn flags_container_clock_behind_host() {
+        let startup = measure_startup(Some(800), true, Some(999_000), 1_000_000, 5_000);
+
+        assert_eq!(startup.startup_time, 800);
+        assert_eq!(startup.container_startup_time, None);
+        assert_eq!(
+            startup.anomaly.as_deref(),
+            Some("Container clock is at least 1000µs behind the host")
+        );
+    }
+
+    #[test]
+    fn flags_container_clock_ahead_of_host() {
+        let startup = measure_startup(Some(800), true, Some(1_009_000), 1_000_000, 5_000);
+
+        assert_eq!(startup.container_startup_time, None);
+        assert_eq!(
+            startup.anomaly.as_deref(),
+            Some("Container clock is at least 4000µs ahead of the host")
+        );
+    }
+
+    #[test]
+    fn legacy_images_fall_back_to_container_clock() {
+        let startup = measure_startup(Some(4_900), false, Some(1_000_700), 1_000_000, 5_000);
+        assert_eq!(startup.startup_time, 700);
+
+        let startup = measure_startup(Some(4_900), false, Some(999_000), 1_000_000, 5_000);
+        assert_eq!(startup.startup_time, 4_900);
+        assert!(startup.anomaly.is_some());
+    }
 }
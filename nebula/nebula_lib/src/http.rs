@@ -0,0 +1,140 @@
+//! Envelope between HTTP-triggered functions and the server.
+//!
+//! A request to `/fn/{name}/{path}` reaches the function as a request envelope on stdin:
+//!
+//! ```text
+//! {"method":"POST","path":"/users/42","query":"verbose=1","headers":{"content-type":"application/json"},"body":"{\"name\":\"Ada\"}"}
+//! ```
+//!
+//! The function answers with a response envelope as its result:
+//!
+//! ```text
+//! {"status":201,"headers":{"content-type":"application/json"},"body":"{\"id\":42}"}
+//! ```
+//!
+//! Bodies that aren't UTF-8 are base64 encoded, with `"body_encoding":"base64"`. Only `status` is
+//! required in a response. A result that isn't a response envelope is sent as a `text/plain`
+//! body with status 200, so any function can be triggered over HTTP.
+
+use std::collections::BTreeMap;
+
+use base64::{engine::general_purpose::STANDARD, DecodeError, Engine};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyEncoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+impl BodyEncoding {
+    fn is_utf8(&self) -> bool {
+        *self == BodyEncoding::Utf8
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HttpRequestEnvelope {
+    pub method: String,
+    /// Path after the function name, starting with `/`.
+    pub path: String,
+    /// Query string without the `?`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    /// Lower case header names, the values of repeated headers are joined with `, `.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+    #[serde(default, skip_serializing_if = "BodyEncoding::is_utf8")]
+    pub body_encoding: BodyEncoding,
+}
+
+impl HttpRequestEnvelope {
+    /// Sets the body, base64 encoded if it isn't UTF-8.
+    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+        (self.body, self.body_encoding) = match String::from_utf8(body) {
+            Ok(body) => (body, BodyEncoding::Utf8),
+            Err(err) => (STANDARD.encode(err.into_bytes()), BodyEncoding::Base64),
+        };
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpResponseEnvelope {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+    #[serde(default)]
+    pub body_encoding: BodyEncoding,
+}
+
+impl HttpResponseEnvelope {
+    /// The response a function's result stands for.
+    pub fn from_result(result: &str) -> Self {
+        serde_json::from_str(result.trim()).unwrap_or_else(|_| HttpResponseEnvelope {
+            status: 200,
+            headers: BTreeMap::from([(
+                "content-type".to_string(),
+                "text/plain; charset=utf-8".to_string(),
+            )]),
+            body: result.to_string(),
+            body_encoding: BodyEncoding::Utf8,
+        })
+    }
+
+    pub fn body_bytes(&self) -> Result<Vec<u8>, DecodeError> {
+        match self.body_encoding {
+            BodyEncoding::Utf8 => Ok(self.body.clone().into_bytes()),
+            BodyEncoding::Base64 => STANDARD.decode(&self.body),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_binary_request_bodies() {
+        let request = HttpRequestEnvelope {
+            method: "POST".to_string(),
+            path: "/".to_string(),
+            query: None,
+            headers: BTreeMap::new(),
+            body: String::new(),
+            body_encoding: BodyEncoding::Utf8,
+        };
+
+        let text = request.clone().with_body(b"hello".to_vec());
+        assert_eq!(
+            serde_json::to_string(&text).unwrap(),
+            r#"{"method":"POST","path":"/","headers":{},"body":"hello"}"#
+        );
+
+        let binary = request.with_body(vec![0xff, 0x00]);
+        assert_eq!(binary.body, "/wA=");
+        assert_eq!(binary.body_encoding, BodyEncoding::Base64);
+    }
+
+    #[test]
+    fn reads_response_envelopes_and_plain_results() {
+        let response = HttpResponseEnvelope::from_result(
+            r#"{"status":201,"headers":{"location":"/users/42"},"body":"AQI=","body_encoding":"base64"}"#,
+        );
+        assert_eq!(response.status, 201);
+        assert_eq!(response.headers["location"], "/users/42");
+        assert_eq!(response.body_bytes().unwrap(), vec![1, 2]);
+
+        // Other JSON is a plain result, not a response without a body.
+        let plain = HttpResponseEnvelope::from_result(r#"{"status":200,"id":42}"#);
+        assert_eq!(plain.body, r#"{"status":200,"id":42}"#);
+        assert_eq!(plain.headers["content-type"], "text/plain; charset=utf-8");
+    }
+}
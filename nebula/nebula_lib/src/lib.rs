@@ -1,5 +1,12 @@
+pub mod deploy;
+pub mod docker_images;
 pub mod docker_runner;
+pub mod http;
 pub mod list_files;
+pub mod manifest;
+pub mod module_store;
+pub mod protocol;
+pub mod runtime;
 pub mod wasm_runner;
 
 pub mod models;
@@ -12,8 +12,11 @@ mod tests {
 
     #[test]
     fn it_works() {
-        match list_files(".") {
-            Ok(list) => assert_eq!(list.len(), 4),
+        let files = list_files("./src").unwrap();
+        assert!(files.contains(&PathBuf::from("./src/lib.rs")));
+
+        match list_files("./does-not-exist") {
+            Ok(_list) => panic!("listing a missing directory should fail"),
             Err(err) => assert_eq!(err.to_string(), "No such file or directory (os error 2)"),
         }
     }
@@ -0,0 +1,320 @@
+//! Per function manifests.
+//!
+//! A function can ship a `nebula.toml` next to its module, named `{name}.nebula.toml` in the
+//! module directory, describing the input it accepts and the limits it runs under:
+//!
+//! ```toml
+//! description = "Computes the factorial of n"
+//! timeout_ms = 5000
+//! memory_limit_mb = 64
+//! examples = ["5", "20"]
+//!
+//! [input]
+//! type = "integer"
+//! min = 0
+//! max = 130
+//!
+//! [env]
+//! RUST_BACKTRACE = "1"
+//! ```
+//!
+//! Requests are validated against the input schema before they reach a runtime, and the runtimes
+//! enforce the [`ExecutionLimits`]. Functions without a manifest accept any input up to
+//! [`UNDECLARED_INPUT`] and run without limits. An optional `[output]` section describes the result with the same schema, so pipelines
+//! can check that a stage's output fits the next stage's input.
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::{Display, Formatter},
+    fs,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+/// File name of a manifest stored next to its module.
+pub const MANIFEST_FILE: &str = "nebula.toml";
+
+/// The input of functions without a manifest, any string of at most 65536 characters.
+pub const UNDECLARED_INPUT: InputSchema = InputSchema::String {
+    max_length: Some(64 * 1024),
+};
+
+/// Suffix of a manifest in a directory holding the modules of several functions.
+pub const MANIFEST_SUFFIX: &str = ".nebula.toml";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FunctionManifest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub input: InputSchema,
+    /// The result the function prints, unknown if `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<InputSchema>,
+    /// Milliseconds an invocation may run before it's stopped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_limit_mb: Option<u64>,
+    /// Environment variables set for every invocation.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Inputs shown as suggestions in the UI, they have to match the input schema.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<String>,
+}
+
+/// The input a function accepts, its stdin.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum InputSchema {
+    Integer {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min: Option<i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max: Option<i64>,
+    },
+    String {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_length: Option<usize>,
+    },
+    Json,
+    /// Binary input, sent base64 encoded and passed to the function as that base64 text, it's up to
+    /// the function to decode it.
+    Bytes {
+        /// Largest decoded size in bytes.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_size: Option<usize>,
+    },
+}
+
+impl Default for InputSchema {
+    fn default() -> Self {
+        InputSchema::String { max_length: None }
+    }
+}
+
+/// Why an input doesn't match a function's [`InputSchema`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputError(pub String);
+
+impl Display for InputError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for InputError {}
+
+impl InputSchema {
+    pub fn validate(&self, input: &str) -> Result<(), InputError> {
+        match self {
+            InputSchema::Integer { min, max } => {
+                let value = input.trim().parse::<i64>().map_err(|_| {
+                    InputError(format!("Expected an integer, got {:?}", truncate(input)))
+                })?;
+
+                if let Some(min) = min.filter(|min| value < *min) {
+                    return Err(InputError(format!(
+                        "Input must be at least {}, got {}",
+                        min, value
+                    )));
+                }
+                if let Some(max) = max.filter(|max| value > *max) {
+                    return Err(InputError(format!(
+                        "Input must be at most {}, got {}",
+                        max, value
+                    )));
+                }
+            }
+            InputSchema::String { max_length } => {
+                let length = input.chars().count();
+                if let Some(max_length) = max_length.filter(|max_length| length > *max_length) {
+                    return Err(InputError(format!(
+                        "Input must be at most {} characters, got {}",
+                        max_length, length
+                    )));
+                }
+            }
+            InputSchema::Json => {
+                serde_json::from_str::<serde_json::Value>(input)
+                    .map_err(|err| InputError(format!("Expected JSON: {}", err)))?;
+            }
+            InputSchema::Bytes { max_size } => {
+                let bytes = STANDARD
+                    .decode(input.trim())
+                    .map_err(|err| InputError(format!("Expected base64 encoded bytes: {}", err)))?;
+
+                if let Some(max_size) = max_size.filter(|max_size| bytes.len() > *max_size) {
+                    return Err(InputError(format!(
+                        "Input must be at most {} bytes, got {}",
+                        max_size,
+                        bytes.len()
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether results described by `output` can be passed as input of this type. Only the types
+    /// are compared, bounds are left to [`InputSchema::validate`] when the input is known.
+    pub fn accepts(&self, output: &InputSchema) -> bool {
+        matches!(
+            (self, output),
+            (InputSchema::String { .. }, _)
+                | (InputSchema::Integer { .. }, InputSchema::Integer { .. })
+                | (
+                    InputSchema::Json,
+                    InputSchema::Json | InputSchema::Integer { .. }
+                )
+                | (InputSchema::Bytes { .. }, InputSchema::Bytes { .. })
+        )
+    }
+}
+
+/// What a runtime has to enforce for an invocation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExecutionLimits {
+    pub timeout: Option<Duration>,
+    /// Bytes.
+    pub memory_limit: Option<u64>,
+    pub env: BTreeMap<String, String>,
+}
+
+impl FunctionManifest {
+    /// Parses and checks a manifest, including that its examples match the input schema.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let manifest: FunctionManifest = toml::from_str(contents)?;
+
+        for example in &manifest.examples {
+            manifest
+                .input
+                .validate(example)
+                .with_context(|| format!("Example {:?} doesn't match the input", example))?;
+        }
+
+        Ok(manifest)
+    }
+
+    /// Loads the manifest at `path`, `None` if there is none.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(path)?;
+        FunctionManifest::parse(&contents)
+            .map(Some)
+            .with_context(|| format!("Invalid manifest {:?}", path))
+    }
+
+    pub fn limits(&self) -> ExecutionLimits {
+        ExecutionLimits {
+            timeout: self.timeout_ms.map(Duration::from_millis),
+            memory_limit: self
+                .memory_limit_mb
+                .map(|megabytes| megabytes * 1024 * 1024),
+            env: self.env.clone(),
+        }
+    }
+}
+
+fn truncate(input: &str) -> String {
+    input.chars().take(32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FACTORIAL: &str = r#"
+        description = "Computes the factorial of n"
+        timeout_ms = 5000
+        memory_limit_mb = 64
+        examples = ["5", "20"]
+
+        [input]
+        type = "integer"
+        min = 0
+        max = 130
+
+        [env]
+        RUST_BACKTRACE = "1"
+    "#;
+
+    #[test]
+    fn parses_manifests() {
+        let manifest = FunctionManifest::parse(FACTORIAL).unwrap();
+
+        assert_eq!(
+            manifest.input,
+            InputSchema::Integer {
+                min: Some(0),
+                max: Some(130)
+            }
+        );
+        assert_eq!(
+            manifest.limits(),
+            ExecutionLimits {
+                timeout: Some(Duration::from_secs(5)),
+                memory_limit: Some(64 * 1024 * 1024),
+                env: BTreeMap::from([("RUST_BACKTRACE".to_string(), "1".to_string())]),
+            }
+        );
+
+        assert_eq!(
+            FunctionManifest::parse("").unwrap().input,
+            InputSchema::String { max_length: None }
+        );
+        assert!(FunctionManifest::parse("timeout = 5").is_err());
+        assert!(FunctionManifest::parse(&FACTORIAL.replace("\"20\"", "\"200\"")).is_err());
+    }
+
+    #[test]
+    fn validates_input() {
+        let integer = InputSchema::Integer {
+            min: Some(0),
+            max: Some(40),
+        };
+        assert!(integer.validate(" 40\n").is_ok());
+        assert_eq!(
+            integer.validate("41").unwrap_err().to_string(),
+            "Input must be at most 40, got 41"
+        );
+        assert_eq!(
+            integer.validate("forty").unwrap_err().to_string(),
+            "Expected an integer, got \"forty\""
+        );
+
+        let string = InputSchema::String {
+            max_length: Some(3),
+        };
+        assert!(string.validate("abc").is_ok());
+        assert!(string.validate("abcd").is_err());
+
+        assert!(InputSchema::Json.validate(r#"{"n": 5}"#).is_ok());
+        assert!(InputSchema::Json.validate("{").is_err());
+
+        let bytes = InputSchema::Bytes { max_size: Some(2) };
+        assert!(bytes.validate("AAE=").is_ok());
+        assert!(bytes.validate("AAEC").is_err());
+        assert!(bytes.validate("not base64!").is_err());
+
+        assert!(integer.accepts(&InputSchema::Integer {
+            min: Some(2),
+            max: None
+        }));
+        assert!(string.accepts(&bytes));
+        assert!(InputSchema::Json.accepts(&integer));
+        assert!(!integer.accepts(&InputSchema::Json));
+        assert!(!bytes.accepts(&string));
+    }
+}
@@ -1,11 +1,11 @@
-use std::fmt::{Display, Formatter, Result};
+use std::{
+    fmt::{Display, Formatter, Result},
+    str::FromStr,
+};
 
 use serde::{Deserialize, Serialize};
 
-// pub mod docker_module;
-pub mod wasm_module;
-
-#[derive(Serialize, Clone, Deserialize, Debug)]
+#[derive(Serialize, Clone, Copy, Deserialize, Debug, PartialEq, Eq, Hash)]
 pub enum ModuleType {
     Docker,
     Wasm,
@@ -19,11 +19,135 @@ pub struct FunctionResult {
     pub func_name: String,
     pub input: String,
     pub base_image: String,
+    /// Error reported by the function or its runtime, `None` if it ran successfully.
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    /// Version of the code that ran: the module hash for Wasm, the image id for Docker.
+    #[serde(default)]
+    pub version: Option<String>,
+    /// What started the invocation.
+    #[serde(default)]
+    pub trigger: Trigger,
+}
+
+#[cfg(any(test, feature = "test-fixtures"))]
+impl FunctionResult {
+    /// A successful Wasm invocation of `factorial` with the input `5`, for tests.
+    pub fn test_fixture(startup_time: u128, total_runtime: u128) -> Self {
+        FunctionResult {
+            metrics: Some(Metrics {
+                startup_time,
+                start_since_epoch: 0,
+                total_runtime,
+                end_since_epoch: total_runtime,
+                startup_percentage: 0.0,
+                container_startup_time: None,
+                startup_anomaly: None,
+                queue_time: None,
+            }),
+            result: "120".to_string(),
+            func_type: ModuleType::Wasm,
+            func_name: "factorial".to_string(),
+            input: "5".to_string(),
+            base_image: "debian".to_string(),
+            error: None,
+            exit_code: Some(0),
+            version: None,
+            trigger: Trigger::default(),
+        }
+    }
+}
+
+/// What started an invocation.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// A request to the invocation API.
+    #[default]
+    Api,
+    /// An asynchronous job.
+    Job,
+    /// A request to an HTTP-triggered function.
+    Http,
+    /// A schedule.
+    Cron,
+    /// An MQTT message.
+    Mqtt,
+    /// A stage of a pipeline.
+    Pipeline,
+}
+
+impl Display for Trigger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Trigger::Api => write!(f, "api"),
+            Trigger::Job => write!(f, "job"),
+            Trigger::Http => write!(f, "http"),
+            Trigger::Cron => write!(f, "cron"),
+            Trigger::Mqtt => write!(f, "mqtt"),
+            Trigger::Pipeline => write!(f, "pipeline"),
+        }
+    }
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "api" => Ok(Trigger::Api),
+            "job" => Ok(Trigger::Job),
+            "http" => Ok(Trigger::Http),
+            "cron" => Ok(Trigger::Cron),
+            "mqtt" => Ok(Trigger::Mqtt),
+            "pipeline" => Ok(Trigger::Pipeline),
+            _ => Err(format!("Unknown trigger {:?}", s)),
+        }
+    }
+}
+
+impl Display for ModuleType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            ModuleType::Docker => write!(f, "Docker"),
+            ModuleType::Wasm => write!(f, "Wasm"),
+        }
+    }
+}
+
+impl FromStr for ModuleType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "docker" => Ok(ModuleType::Docker),
+            "wasm" => Ok(ModuleType::Wasm),
+            _ => Err(format!("Unknown module type {:?}", s)),
+        }
+    }
+}
+
+impl FunctionResult {
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// The version shortened for display, like a short git hash.
+    pub fn short_version(&self) -> Option<&str> {
+        self.version.as_deref().map(|version| {
+            let version = version.strip_prefix("sha256:").unwrap_or(version);
+            &version[..version.len().min(12)]
+        })
+    }
 }
 
 impl Display for FunctionResult {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        if let Some(ref metrics) = self.metrics {
+        if let Some(ref error) = self.error {
+            write!(f, "Function failed: {}", error)
+        } else if let Some(ref metrics) = self.metrics {
             write!(f, "Result was: {}\n{}", self.result, metrics)
         } else {
             write!(f, "Result was: {}", self.result)
@@ -38,6 +162,16 @@ pub struct Metrics {
     pub total_runtime: u128,
     pub end_since_epoch: u128,
     pub startup_percentage: f64,
+    /// Startup as reported by the function's own clock, kept as a cross-check of `startup_time`.
+    #[serde(default)]
+    pub container_startup_time: Option<u128>,
+    /// Why a startup measurement was discarded as implausible, e.g. clock skew in the container.
+    #[serde(default)]
+    pub startup_anomaly: Option<String>,
+    /// Microseconds the invocation waited for a free slot before it started, not part of
+    /// `startup_time` or `total_runtime`.
+    #[serde(default)]
+    pub queue_time: Option<u128>,
 }
 
 impl Display for Metrics {
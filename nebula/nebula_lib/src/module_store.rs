@@ -0,0 +1,740 @@
+//! Content addressed store of deployed Wasm functions.
+//!
+//! Every distinct module deployed for a function is kept as an immutable version, named by the
+//! sha256 of its bytes, so a result can always be tied back to the exact code that produced it.
+//! Aliases such as `latest` or `stable` point at a version and can be moved, which is all a
+//! rollback is. Functions are referenced as `name` (the `latest` alias), `name@alias` or
+//! `name@version`, where versions can be shortened to a unique prefix of at least 7 characters.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock, RwLockWriteGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use wasmtime::Engine;
+
+use crate::{
+    deploy::{precompile, validate_name, write_atomic, DeployDirs, DeployError},
+    list_files::list_files,
+    manifest::{FunctionManifest, MANIFEST_FILE, MANIFEST_SUFFIX},
+    wasm_runner::engine_config,
+};
+
+/// Alias moved to every newly deployed version.
+pub const LATEST: &str = "latest";
+const MIN_VERSION_PREFIX: usize = 7;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeployedModule {
+    pub name: String,
+    /// Hex encoded sha256 of the module.
+    pub version: String,
+    /// Size of the uploaded module in bytes.
+    pub size: usize,
+    /// Seconds since epoch.
+    pub deployed_at: u64,
+}
+
+impl DeployedModule {
+    pub fn short_version(&self) -> &str {
+        &self.version[..self.version.len().min(12)]
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FunctionVersions {
+    /// Oldest first.
+    pub versions: Vec<DeployedModule>,
+    /// Alias names and the version they point at.
+    pub aliases: BTreeMap<String, String>,
+}
+
+impl FunctionVersions {
+    pub fn latest(&self) -> Option<&DeployedModule> {
+        self.aliases.get(LATEST).and_then(|version| {
+            self.versions
+                .iter()
+                .find(|module| &module.version == version)
+        })
+    }
+
+    /// Resolves an alias, a full version or an unambiguous version prefix.
+    fn find(&self, name: &str, reference: &str) -> Result<&DeployedModule, DeployError> {
+        let version = self
+            .aliases
+            .get(reference)
+            .map_or(reference, String::as_str);
+
+        let mut matches = self.versions.iter().filter(|module| {
+            module.version == version
+                || (version.len() >= MIN_VERSION_PREFIX && module.version.starts_with(version))
+        });
+
+        match (matches.next(), matches.next()) {
+            (Some(module), None) => Ok(module),
+            (Some(_), Some(_)) => Err(DeployError::AmbiguousVersion(
+                name.to_string(),
+                reference.to_string(),
+            )),
+            (None, _) => Err(DeployError::UnknownVersion(
+                name.to_string(),
+                reference.to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModuleRegistry {
+    pub functions: BTreeMap<String, FunctionVersions>,
+}
+
+impl ModuleRegistry {
+    /// Loads the registry from `path`, starting an empty one if it doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(ModuleRegistry::default());
+        }
+
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+
+        serde_json::from_str(&contents).with_context(|| format!("Failed to parse {:?}", path))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_atomic(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    pub fn get(&self, name: &str) -> Result<&FunctionVersions, DeployError> {
+        self.functions
+            .get(name)
+            .ok_or_else(|| DeployError::UnknownFunction(name.to_string()))
+    }
+
+    /// Resolves `name`, `name@alias` or `name@version`.
+    pub fn resolve(&self, reference: &str) -> Result<&DeployedModule, DeployError> {
+        let (name, version) = parse_reference(reference);
+
+        self.get(name)?.find(name, version.unwrap_or(LATEST))
+    }
+
+    /// Points `alias` at the version `reference` resolves to.
+    pub fn set_alias(
+        &mut self,
+        name: &str,
+        alias: &str,
+        reference: &str,
+    ) -> Result<&FunctionVersions, DeployError> {
+        validate_name("alias", alias)?;
+
+        let version = self.get(name)?.find(name, reference)?.version.clone();
+        let function = self.functions.get_mut(name).expect("function was found");
+        function.aliases.insert(alias.to_string(), version);
+
+        Ok(function)
+    }
+
+    /// Points `latest` at `reference`, or at the version deployed before the current `latest`.
+    pub fn rollback(
+        &mut self,
+        name: &str,
+        reference: Option<&str>,
+    ) -> Result<&FunctionVersions, DeployError> {
+        let function = self.get(name)?;
+
+        let version = match reference {
+            Some(reference) => function.find(name, reference)?.version.clone(),
+            None => {
+                let current = function
+                    .versions
+                    .iter()
+                    .position(|module| Some(&module.version) == function.aliases.get(LATEST))
+                    .unwrap_or(function.versions.len());
+
+                current
+                    .checked_sub(1)
+                    .and_then(|previous| function.versions.get(previous))
+                    .map(|module| module.version.clone())
+                    .ok_or_else(|| {
+                        DeployError::UnknownVersion(name.to_string(), "before latest".to_string())
+                    })?
+            }
+        };
+
+        self.set_alias(name, LATEST, &version)
+    }
+
+    /// Removes the version `reference` resolves to along with the aliases pointing at it. If it
+    /// was `latest`, `latest` moves to the newest version left. Returns the removed version and
+    /// the function, `None` if that was its last version and the function is gone.
+    pub fn remove_version(
+        &mut self,
+        name: &str,
+        reference: &str,
+    ) -> Result<(DeployedModule, Option<FunctionVersions>), DeployError> {
+        let removed = self.get(name)?.find(name, reference)?.clone();
+        let function = self.functions.get_mut(name).expect("function was found");
+
+        function
+            .versions
+            .retain(|module| module.version != removed.version);
+        let was_latest = function.aliases.get(LATEST) == Some(&removed.version);
+        function
+            .aliases
+            .retain(|_, version| *version != removed.version);
+
+        let Some(newest) = function.versions.last() else {
+            self.functions.remove(name);
+            return Ok((removed, None));
+        };
+        if was_latest {
+            function
+                .aliases
+                .insert(LATEST.to_string(), newest.version.clone());
+        }
+
+        Ok((removed, Some(function.clone())))
+    }
+
+    /// Adds `module` as a new version, unless the function already has it. Returns the version
+    /// as registered.
+    fn register(&mut self, module: DeployedModule) -> DeployedModule {
+        let function = self.functions.entry(module.name.clone()).or_default();
+
+        match function
+            .versions
+            .iter()
+            .find(|existing| existing.version == module.version)
+        {
+            Some(existing) => existing.clone(),
+            None => {
+                function.versions.push(module.clone());
+                module
+            }
+        }
+    }
+}
+
+/// Splits `name@version` into its parts, the version is optional.
+pub fn parse_reference(reference: &str) -> (&str, Option<&str>) {
+    match reference.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (reference, None),
+    }
+}
+
+pub fn content_version(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// The [`ModuleRegistry`] together with the modules on disk, shared between the API and the Wasm
+/// runtime. Every change is persisted before it returns.
+#[derive(Debug, Clone)]
+pub struct ModuleStore {
+    registry: Arc<RwLock<ModuleRegistry>>,
+    /// One lock per precompiled module being compiled again, so concurrent invocations compile it
+    /// once.
+    compiling: Arc<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>>,
+    dirs: DeployDirs,
+    registry_path: PathBuf,
+    /// Identifies the engine configuration modules are precompiled for, part of their file name so
+    /// a new configuration or wasmtime release compiles them again.
+    engine_tag: String,
+}
+
+impl ModuleStore {
+    pub fn open(dirs: DeployDirs, registry_path: PathBuf) -> Result<Self> {
+        let engine = Engine::new(&engine_config())?;
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        engine.precompile_compatibility_hash().hash(&mut hasher);
+
+        Ok(ModuleStore {
+            registry: Arc::new(RwLock::new(ModuleRegistry::load(&registry_path)?)),
+            compiling: Default::default(),
+            dirs,
+            registry_path,
+            engine_tag: format!("{:016x}", hasher.finish()),
+        })
+    }
+
+    pub fn snapshot(&self) -> ModuleRegistry {
+        self.registry
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    pub fn module_path(&self, module: &DeployedModule) -> PathBuf {
+        self.dirs
+            .serialized_dir
+            .join(&module.name)
+            .join(format!("{}.{}.cwasm", module.version, self.engine_tag))
+    }
+
+    fn archive_path(&self, name: &str, version: &str) -> PathBuf {
+        self.dirs
+            .archive_dir
+            .join(name)
+            .join(format!("{}.wasm", version))
+    }
+
+    fn manifest_path(&self, name: &str) -> PathBuf {
+        self.dirs.archive_dir.join(name).join(MANIFEST_FILE)
+    }
+
+    /// Resolves a function reference to the precompiled module to run, compiling it again from
+    /// the archive if it was precompiled for another engine configuration.
+    pub fn resolve(&self, reference: &str) -> Result<(DeployedModule, PathBuf), DeployError> {
+        let module = self
+            .registry
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .resolve(reference)?
+            .clone();
+        let path = self.module_path(&module);
+
+        if !path.is_file() {
+            self.recompile(&module, &path)?;
+        }
+
+        Ok((module, path))
+    }
+
+    /// Compiles `module` from the archive to `path`, unless a concurrent call just did.
+    fn recompile(&self, module: &DeployedModule, path: &Path) -> Result<(), DeployError> {
+        let lock = self
+            .compiling
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(path.to_path_buf())
+            .or_default()
+            .clone();
+        let _compiling = lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let archive_path = self.archive_path(&module.name, &module.version);
+        if path.is_file() || !archive_path.is_file() {
+            return Ok(());
+        }
+
+        let bytes = fs::read(&archive_path).map_err(|err| DeployError::Storage(err.into()))?;
+        write_atomic(path, &precompile(&bytes)?).map_err(DeployError::Storage)
+    }
+
+    /// The manifest of the function `name`, `None` if it has none. Functions of every runtime
+    /// share their manifest.
+    pub fn manifest(&self, name: &str) -> Result<Option<FunctionManifest>, DeployError> {
+        validate_name("function name", name)?;
+
+        FunctionManifest::load(&self.manifest_path(name)).map_err(DeployError::InvalidManifest)
+    }
+
+    /// Validates and stores the manifest of the function `name`, replacing its current one.
+    pub fn set_manifest(
+        &self,
+        name: &str,
+        contents: &str,
+    ) -> Result<FunctionManifest, DeployError> {
+        validate_name("function name", name)?;
+        let manifest = FunctionManifest::parse(contents).map_err(DeployError::InvalidManifest)?;
+
+        write_atomic(&self.manifest_path(name), contents.as_bytes())
+            .map_err(DeployError::Storage)?;
+
+        Ok(manifest)
+    }
+
+    /// Stores `bytes` as a version of `name` and points `latest`, and `alias` if given, at it.
+    /// Deploying a module that is already stored only moves the aliases.
+    pub fn deploy(
+        &self,
+        name: &str,
+        alias: Option<&str>,
+        bytes: &[u8],
+    ) -> Result<DeployedModule, DeployError> {
+        validate_name("function name", name)?;
+        if let Some(alias) = alias {
+            validate_name("alias", alias)?;
+        }
+
+        let module = self.store(name, bytes)?;
+
+        let mut registry = self.write();
+        let deployed = self.register(&mut registry, module, bytes)?;
+
+        registry.set_alias(name, LATEST, &deployed.version)?;
+        if let Some(alias) = alias {
+            registry.set_alias(name, alias, &deployed.version)?;
+        }
+
+        self.persist(&registry)?;
+
+        Ok(deployed)
+    }
+
+    /// Stores every module in `module_dir` that isn't stored yet, named by its file stem, along
+    /// with the `{name}.nebula.toml` manifests next to them. Only new versions move `latest`, so a
+    /// rollback survives a restart.
+    pub fn import_dir(
+        &self,
+        module_dir: &Path,
+    ) -> Result<Vec<Result<DeployedModule, DeployError>>> {
+        let files = list_files(
+            module_dir
+                .to_str()
+                .context("Module dir is not valid UTF-8")?,
+        )
+        .with_context(|| format!("Failed to list wasm modules in {:?}", module_dir))?;
+
+        let mut imported = Vec::new();
+
+        for file in files {
+            let Some(file_name) = file.file_name().and_then(|file_name| file_name.to_str()) else {
+                continue;
+            };
+
+            if let Some(name) = file_name.strip_suffix(MANIFEST_SUFFIX) {
+                let result = fs::read_to_string(&file)
+                    .map_err(|err| DeployError::Storage(err.into()))
+                    .and_then(|contents| self.set_manifest(name, &contents));
+                if let Err(err) = result {
+                    imported.push(Err(err));
+                }
+                continue;
+            }
+
+            let Some(name) = file_name.strip_suffix(".wasm") else {
+                continue;
+            };
+
+            let result = fs::read(&file)
+                .map_err(|err| DeployError::Storage(err.into()))
+                .and_then(|bytes| {
+                    let module = self.store(name, &bytes)?;
+                    let mut registry = self.write();
+                    let known = registry
+                        .get(name)
+                        .is_ok_and(|function| function.find(name, &module.version).is_ok());
+
+                    let deployed = self.register(&mut registry, module, &bytes)?;
+                    if !known {
+                        registry.set_alias(name, LATEST, &deployed.version)?;
+                    }
+                    Ok((deployed, known))
+                });
+
+            match result {
+                Ok((_, true)) => {}
+                Ok((deployed, false)) => imported.push(Ok(deployed)),
+                Err(err) => imported.push(Err(err)),
+            }
+        }
+
+        self.persist(&self.write())?;
+
+        Ok(imported)
+    }
+
+    pub fn set_alias(
+        &self,
+        name: &str,
+        alias: &str,
+        reference: &str,
+    ) -> Result<FunctionVersions, DeployError> {
+        let mut registry = self.write();
+        let function = registry.set_alias(name, alias, reference)?.clone();
+        self.persist(&registry)?;
+
+        Ok(function)
+    }
+
+    pub fn rollback(
+        &self,
+        name: &str,
+        reference: Option<&str>,
+    ) -> Result<FunctionVersions, DeployError> {
+        let mut registry = self.write();
+        let function = registry.rollback(name, reference)?.clone();
+        self.persist(&registry)?;
+
+        Ok(function)
+    }
+
+    /// Removes a version of `name` and its files, see [`ModuleRegistry::remove_version`].
+    pub fn remove_version(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Result<(DeployedModule, Option<FunctionVersions>), DeployError> {
+        let mut registry = self.write();
+        let (removed, function) = registry.remove_version(name, reference)?;
+        self.persist(&registry)?;
+        drop(registry);
+
+        self.remove_files(&removed)?;
+
+        Ok((removed, function))
+    }
+
+    /// Removes the manifest of `name`, returns whether it had one.
+    pub fn remove_manifest(&self, name: &str) -> Result<bool, DeployError> {
+        validate_name("function name", name)?;
+
+        match fs::remove_file(self.manifest_path(name)) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(DeployError::Storage(err.into())),
+        }
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, ModuleRegistry> {
+        self.registry
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn persist(&self, registry: &ModuleRegistry) -> Result<(), DeployError> {
+        registry
+            .save(&self.registry_path)
+            .map_err(DeployError::Storage)
+    }
+
+    /// Removes the archived module and its precompiled modules for every engine configuration.
+    fn remove_files(&self, module: &DeployedModule) -> Result<(), DeployError> {
+        let storage = |err: std::io::Error| DeployError::Storage(err.into());
+        let prefix = format!("{}.", module.version);
+
+        let serialized_dir = self.dirs.serialized_dir.join(&module.name);
+        let precompiled = match fs::read_dir(&serialized_dir) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(storage)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(storage(err)),
+        };
+
+        let files = precompiled
+            .into_iter()
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|file_name| file_name.to_str())
+                    .is_some_and(|file_name| file_name.starts_with(&prefix))
+            })
+            .chain(std::iter::once(
+                self.archive_path(&module.name, &module.version),
+            ));
+
+        for file in files {
+            match fs::remove_file(&file) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(storage(err)),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Precompiles the module and writes it to disk if it's new, without registering it. Runs
+    /// outside the registry lock, so invocations don't wait for the compilation.
+    fn store(&self, name: &str, bytes: &[u8]) -> Result<DeployedModule, DeployError> {
+        let module = DeployedModule {
+            name: name.to_string(),
+            version: content_version(bytes),
+            size: bytes.len(),
+            deployed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_secs()),
+        };
+        let module_path = self.module_path(&module);
+
+        if !module_path.is_file() {
+            let serialized = precompile(bytes)?;
+            let archive_path = self.archive_path(name, &module.version);
+
+            write_atomic(&archive_path, bytes)
+                .and_then(|_| write_atomic(&module_path, &serialized))
+                .map_err(DeployError::Storage)?;
+        }
+
+        Ok(module)
+    }
+
+    /// Registers a [`store`](ModuleStore::store)d module, without touching any alias. Writes the
+    /// archive again if a concurrent removal of the same version deleted it in the meantime, the
+    /// precompiled module is then compiled again when it's resolved.
+    fn register(
+        &self,
+        registry: &mut ModuleRegistry,
+        module: DeployedModule,
+        bytes: &[u8],
+    ) -> Result<DeployedModule, DeployError> {
+        let archive_path = self.archive_path(&module.name, &module.version);
+        if !archive_path.is_file() {
+            write_atomic(&archive_path, bytes).map_err(DeployError::Storage)?;
+        }
+
+        Ok(registry.register(module))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deploy::tests::COMMAND;
+
+    use super::*;
+
+    const OTHER_COMMAND: &str = r#"
+        (module
+          (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+          (memory (export "memory") 1)
+          (func (export "_start") i32.const 0 call $exit))
+    "#;
+
+    fn open_store(test: &str) -> (ModuleStore, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("nebula-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let dirs = DeployDirs {
+            serialized_dir: dir.join("serialized"),
+            archive_dir: dir.join("archive"),
+        };
+
+        (
+            ModuleStore::open(dirs, dir.join("registry.json")).unwrap(),
+            dir,
+        )
+    }
+
+    #[test]
+    fn parses_references() {
+        assert_eq!(parse_reference("factorial"), ("factorial", None));
+        assert_eq!(
+            parse_reference("factorial@stable"),
+            ("factorial", Some("stable"))
+        );
+    }
+
+    #[test]
+    fn versions_are_content_addressed() {
+        let (store, dir) = open_store("content-addressed");
+
+        let first = store.deploy("noop", None, COMMAND.as_bytes()).unwrap();
+        let again = store.deploy("noop", None, COMMAND.as_bytes()).unwrap();
+        let second = store
+            .deploy("noop", Some("stable"), OTHER_COMMAND.as_bytes())
+            .unwrap();
+
+        assert_eq!(first.version, content_version(COMMAND.as_bytes()));
+        assert_eq!(first, again);
+        assert_ne!(first.version, second.version);
+        assert_eq!(store.snapshot().get("noop").unwrap().versions.len(), 2);
+        assert!(store.module_path(&first).is_file());
+
+        assert_eq!(store.resolve("noop").unwrap().0, second);
+        assert_eq!(store.resolve("noop@stable").unwrap().0, second);
+        assert_eq!(
+            store
+                .resolve(&format!("noop@{}", &first.version[..8]))
+                .unwrap()
+                .0,
+            first
+        );
+        assert!(matches!(
+            store.resolve("noop@abc"),
+            Err(DeployError::UnknownVersion(_, _))
+        ));
+        assert!(matches!(
+            store.resolve("add"),
+            Err(DeployError::UnknownFunction(_))
+        ));
+
+        // Reopening reads the persisted registry.
+        let reopened = ModuleStore::open(store.dirs.clone(), store.registry_path.clone()).unwrap();
+        assert_eq!(reopened.snapshot(), store.snapshot());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rolls_back_latest() {
+        let (store, dir) = open_store("rollback");
+
+        let first = store.deploy("noop", None, COMMAND.as_bytes()).unwrap();
+        let second = store
+            .deploy("noop", None, OTHER_COMMAND.as_bytes())
+            .unwrap();
+
+        let function = store.rollback("noop", None).unwrap();
+        assert_eq!(function.latest(), Some(&first));
+        assert!(store.rollback("noop", None).is_err());
+
+        let function = store.rollback("noop", Some(&second.version)).unwrap();
+        assert_eq!(function.latest(), Some(&second));
+
+        let function = store.set_alias("noop", "stable", &first.version).unwrap();
+        assert_eq!(function.aliases.get("stable"), Some(&first.version));
+        assert!(store.set_alias("noop", "not valid", "latest").is_err());
+
+        // Removing `latest` moves it to the newest version left, and removes the files.
+        let (removed, function) = store.remove_version("noop", "latest").unwrap();
+        assert_eq!(removed, second);
+        assert_eq!(function.unwrap().latest(), Some(&first));
+        assert!(!store.module_path(&second).exists());
+
+        let (_, function) = store.remove_version("noop", "stable").unwrap();
+        assert_eq!(function, None);
+        assert!(matches!(
+            store.resolve("noop"),
+            Err(DeployError::UnknownFunction(_))
+        ));
+
+        let _ = fs::remove_dir_all(dir);
+    }
+    #[test]
+    fn imports_modules_with_manifests() {
+        let (store, dir) = open_store("import");
+        let module_dir = dir.join("modules");
+        fs::create_dir_all(&module_dir).unwrap();
+        fs::write(module_dir.join("noop.wasm"), COMMAND).unwrap();
+        fs::write(
+            module_dir.join("noop.nebula.toml"),
+            "description = \"Does nothing\"\n[input]\ntype = \"json\"\n",
+        )
+        .unwrap();
+
+        let imported = store.import_dir(&module_dir).unwrap();
+        assert_eq!(imported.len(), 1);
+
+        let manifest = store.manifest("noop").unwrap().unwrap();
+        assert_eq!(manifest.description.as_deref(), Some("Does nothing"));
+        assert_eq!(store.manifest("add").unwrap(), None);
+
+        // Modules precompiled for another engine are compiled again from the archive.
+        let (module, path) = store.resolve("noop").unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            store.resolve("noop").unwrap(),
+            (module.clone(), path.clone())
+        );
+        assert!(path.is_file());
+
+        // Concurrent invocations compile it once, and never see it half written.
+        fs::remove_file(&path).unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| assert_eq!(store.resolve("noop").unwrap().0, module));
+            }
+        });
+        assert!(path.is_file());
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
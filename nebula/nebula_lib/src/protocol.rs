@@ -0,0 +1,187 @@
+//! Output protocol between function modules and the runners.
+//!
+//! Functions built on the `shared` crate finish by printing a single JSON line, the output frame,
+//! carrying the result, the timestamps taken inside the function and any error:
+//!
+//! ```text
+//! {"nebula":1,"result":"120","started_at":1712000000000000,"finished_at":1712000000000150,"error":null,"exit_code":0}
+//! ```
+//!
+//! Docker functions also print a start frame as their very first line, so the host can timestamp
+//! the container becoming ready on its own clock:
+//!
+//! ```text
+//! {"nebula":1,"event":"start","started_at":1712000000000000}
+//! ```
+//!
+//! Anything else printed before the output frame is ignored. Modules built before the protocol
+//! existed print `result|timestamp` (Docker) or just `result` (Wasm), which is still accepted.
+
+use serde::{Deserialize, Serialize};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputFrame {
+    /// Protocol version the function was built against.
+    pub nebula: u32,
+    pub result: String,
+    /// Microseconds since epoch when the function started, as seen from inside the function.
+    #[serde(default)]
+    pub started_at: Option<u64>,
+    /// Microseconds since epoch when the function produced its result.
+    #[serde(default)]
+    pub finished_at: Option<u64>,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub exit_code: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StartFrame {
+    pub nebula: u32,
+    /// Always `start`.
+    pub event: String,
+    #[serde(default)]
+    pub started_at: Option<u64>,
+}
+
+pub fn is_start_frame(line: &str) -> bool {
+    serde_json::from_str::<StartFrame>(line.trim())
+        .is_ok_and(|frame| frame.nebula > 0 && frame.event == "start")
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ParsedOutput {
+    pub result: String,
+    pub started_at: Option<u128>,
+    pub finished_at: Option<u128>,
+    pub error: Option<String>,
+    pub exit_code: Option<i32>,
+    /// Protocol version of the frame, `None` if the output used the legacy format.
+    pub protocol: Option<u32>,
+}
+
+pub fn parse_output(stdout: &str) -> ParsedOutput {
+    let stdout = match stdout.lines().next() {
+        Some(first_line) if is_start_frame(first_line) => {
+            stdout.split_once('\n').map_or("", |(_, rest)| rest)
+        }
+        _ => stdout,
+    };
+
+    parse_frame(stdout).unwrap_or_else(|| parse_legacy(stdout))
+}
+
+fn parse_frame(stdout: &str) -> Option<ParsedOutput> {
+    let last_line = stdout.lines().rev().find(|line| !line.trim().is_empty())?;
+    let frame: OutputFrame = serde_json::from_str(last_line.trim()).ok()?;
+
+    if frame.nebula == 0 {
+        return None;
+    }
+
+    Some(ParsedOutput {
+        result: frame.result,
+        started_at: frame.started_at.map(u128::from),
+        finished_at: frame.finished_at.map(u128::from),
+        error: frame.error,
+        exit_code: Some(frame.exit_code),
+        protocol: Some(frame.nebula),
+    })
+}
+
+/// `result|timestamp`, split on the last pipe so results containing pipes survive.
+fn parse_legacy(stdout: &str) -> ParsedOutput {
+    let output = stdout.trim();
+
+    match output.rsplit_once('|') {
+        Some((result, timestamp)) if timestamp.trim().parse::<u128>().is_ok() => ParsedOutput {
+            result: result.to_string(),
+            started_at: timestamp.trim().parse::<u128>().ok(),
+            ..Default::default()
+        },
+        _ => ParsedOutput {
+            result: output.to_string(),
+            ..Default::default()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_output_frame() {
+        let stdout = "some log line\n\
+            {\"nebula\":1,\"result\":\"top | bottom\",\"started_at\":10,\"finished_at\":20,\"error\":null,\"exit_code\":0}\n";
+
+        let parsed = parse_output(stdout);
+
+        assert_eq!(parsed.result, "top | bottom");
+        assert_eq!(parsed.started_at, Some(10));
+        assert_eq!(parsed.finished_at, Some(20));
+        assert_eq!(parsed.error, None);
+        assert_eq!(parsed.exit_code, Some(0));
+        assert_eq!(parsed.protocol, Some(1));
+    }
+
+    #[test]
+    fn parses_error_frame() {
+        let stdout = "{\"nebula\":1,\"result\":\"\",\"error\":\"Failed to parse input as u128\",\"exit_code\":1}";
+
+        let parsed = parse_output(stdout);
+
+        assert_eq!(
+            parsed.error.as_deref(),
+            Some("Failed to parse input as u128")
+        );
+        assert_eq!(parsed.exit_code, Some(1));
+        assert_eq!(parsed.started_at, None);
+    }
+
+    #[test]
+    fn skips_start_frame() {
+        let start = "{\"nebula\":1,\"event\":\"start\",\"started_at\":5}";
+        assert!(is_start_frame(start));
+
+        let parsed = parse_output(&format!(
+            "{}\n{{\"nebula\":1,\"result\":\"8\",\"started_at\":5}}\n",
+            start
+        ));
+        assert_eq!(parsed.result, "8");
+
+        // The function died after starting, there is no result to report.
+        let parsed = parse_output(&format!("{}\n", start));
+        assert_eq!(parsed.result, "");
+    }
+
+    #[test]
+    fn accepts_legacy_docker_output() {
+        let parsed = parse_output("120|1712000000000000\n");
+
+        assert_eq!(parsed.result, "120");
+        assert_eq!(parsed.started_at, Some(1712000000000000));
+        assert_eq!(parsed.protocol, None);
+    }
+
+    #[test]
+    fn legacy_output_keeps_pipes_in_result() {
+        let parsed = parse_output("top|bottom|1712000000000000");
+        assert_eq!(parsed.result, "top|bottom");
+
+        let parsed = parse_output("top|bottom");
+        assert_eq!(parsed.result, "top|bottom");
+        assert_eq!(parsed.started_at, None);
+    }
+
+    #[test]
+    fn json_results_are_not_frames() {
+        let parsed = parse_output("{\"result\":\"not a frame\"}");
+
+        assert_eq!(parsed.result, "{\"result\":\"not a frame\"}");
+        assert_eq!(parsed.protocol, None);
+    }
+}
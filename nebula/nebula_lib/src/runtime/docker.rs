@@ -0,0 +1,65 @@
+use anyhow::{bail, Result};
+
+use crate::{
+    docker_images::resolve_image,
+    docker_runner::run_docker_image,
+    models::{FunctionResult, ModuleType},
+    module_store::parse_reference,
+};
+
+use super::{Capabilities, FunctionRuntime, Invocation, PreparedFunction};
+
+/// Runs `nebula-function-{name}-{base}` images through the `docker` CLI.
+pub struct DockerRuntime;
+
+impl FunctionRuntime for DockerRuntime {
+    fn module_type(&self) -> ModuleType {
+        ModuleType::Docker
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            base_images: true,
+            precompiled: false,
+            process_isolation: true,
+        }
+    }
+
+    /// Resolves `name` or `name@version`, where the version is a prefix of the image id.
+    fn prepare(&self, invocation: &Invocation) -> Result<PreparedFunction> {
+        let (func_name, version) = parse_reference(&invocation.func_name);
+        let image = resolve_image(func_name, &invocation.base_image)?;
+
+        if let Some(version) = version {
+            let digest = image.digest.as_deref().unwrap_or_default();
+            let digest = digest.strip_prefix("sha256:").unwrap_or(digest);
+
+            if !digest.starts_with(version.strip_prefix("sha256:").unwrap_or(version)) {
+                bail!(
+                    "{} is at version {}, not {}",
+                    image.image_name,
+                    digest,
+                    version
+                );
+            }
+        }
+
+        Ok(PreparedFunction {
+            func_name: image.func_name,
+            base_image: image.base_image,
+            target: image.image_name,
+            version: image.digest,
+            limits: invocation.limits.clone(),
+        })
+    }
+
+    fn invoke(&self, function: &PreparedFunction, input: &str) -> Result<FunctionResult> {
+        Ok(run_docker_image(
+            &function.target,
+            input,
+            function.func_name.clone(),
+            function.base_image.clone(),
+            &function.limits,
+        )?)
+    }
+}
@@ -0,0 +1,376 @@
+//! Runtimes executing functions, one per [`ModuleType`].
+//!
+//! A runtime resolves a function to something it can run in [`FunctionRuntime::prepare`], runs it
+//! any number of times with [`FunctionRuntime::invoke`] and releases it again with
+//! [`FunctionRuntime::teardown`]. The [`RuntimeRegistry`] picks the runtime for a request, so
+//! callers never deal with image names or module paths themselves.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{Debug, Display, Formatter},
+    sync::Arc,
+};
+
+use serde::Serialize;
+use tracing::{field::Empty, info_span, Span};
+
+use crate::{
+    manifest::ExecutionLimits,
+    models::{FunctionResult, ModuleType},
+    module_store::ModuleStore,
+};
+
+pub mod docker;
+pub mod wasm;
+
+pub use docker::DockerRuntime;
+pub use wasm::WasmRuntime;
+
+/// What a runtime supports, so callers can adapt without knowing the concrete runtime.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Capabilities {
+    /// Functions are built for, and run on, a selectable base image.
+    pub base_images: bool,
+    /// Modules are compiled ahead of time, so preparing a function doesn't compile it.
+    pub precompiled: bool,
+    /// Each invocation runs in its own process, rather than inside the server.
+    pub process_isolation: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invocation {
+    pub func_name: String,
+    /// Base image to run on, ignored by runtimes without base image support.
+    pub base_image: String,
+    /// Limits from the function's manifest.
+    pub limits: ExecutionLimits,
+}
+
+/// A function resolved by [`FunctionRuntime::prepare`], ready to be invoked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreparedFunction {
+    pub func_name: String,
+    pub base_image: String,
+    /// What the runtime executes, e.g. an image name or a precompiled module path.
+    pub target: String,
+    /// Version of the function `target` runs, recorded in every result.
+    pub version: Option<String>,
+    pub limits: ExecutionLimits,
+}
+
+pub trait FunctionRuntime: Send + Sync {
+    fn module_type(&self) -> ModuleType;
+
+    fn capabilities(&self) -> Capabilities;
+
+    /// Resolves the function, failing if it isn't available to this runtime.
+    fn prepare(&self, invocation: &Invocation) -> anyhow::Result<PreparedFunction>;
+
+    fn invoke(&self, function: &PreparedFunction, input: &str) -> anyhow::Result<FunctionResult>;
+
+    /// Releases whatever `prepare` acquired.
+    fn teardown(&self, _function: PreparedFunction) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// What a runtime fails [`FunctionRuntime::prepare`] with when whatever it runs functions on can't
+/// be reached, e.g. the container engine is down, as opposed to the function not existing.
+#[derive(Debug)]
+pub struct RuntimeUnavailable(pub String);
+
+impl Display for RuntimeUnavailable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for RuntimeUnavailable {}
+
+#[derive(Debug)]
+pub enum InvocationError {
+    /// No runtime is registered for the module type.
+    UnsupportedRuntime(ModuleType),
+    /// The runtime can't be reached, see [`RuntimeUnavailable`].
+    Unavailable(anyhow::Error),
+    /// The function could not be resolved.
+    Prepare(anyhow::Error),
+    /// The runtime failed to run the function.
+    Invoke(anyhow::Error),
+}
+
+impl Display for InvocationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvocationError::UnsupportedRuntime(module_type) => {
+                write!(f, "No runtime available for {} functions", module_type)
+            }
+            InvocationError::Unavailable(err) | InvocationError::Prepare(err) => {
+                write!(f, "{:#}", err)
+            }
+            InvocationError::Invoke(err) => write!(f, "Failed to run function: {:#}", err),
+        }
+    }
+}
+
+impl Error for InvocationError {}
+
+#[derive(Clone, Default)]
+pub struct RuntimeRegistry {
+    runtimes: HashMap<ModuleType, Arc<dyn FunctionRuntime>>,
+}
+
+impl Debug for RuntimeRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.runtimes.keys()).finish()
+    }
+}
+
+impl RuntimeRegistry {
+    pub fn new() -> Self {
+        RuntimeRegistry::default()
+    }
+
+    /// Docker and Wasm runtimes, with Wasm functions resolved through `modules`.
+    pub fn with_defaults(modules: ModuleStore) -> Self {
+        let mut registry = RuntimeRegistry::new();
+        registry.register(Arc::new(DockerRuntime));
+        registry.register(Arc::new(WasmRuntime::new(modules)));
+        registry
+    }
+
+    /// Registers `runtime` for its module type, replacing any runtime already registered for it.
+    pub fn register(&mut self, runtime: Arc<dyn FunctionRuntime>) {
+        self.runtimes.insert(runtime.module_type(), runtime);
+    }
+
+    pub fn get(&self, module_type: ModuleType) -> Option<Arc<dyn FunctionRuntime>> {
+        self.runtimes.get(&module_type).cloned()
+    }
+
+    pub fn capabilities(&self) -> HashMap<ModuleType, Capabilities> {
+        self.runtimes
+            .iter()
+            .map(|(module_type, runtime)| (*module_type, runtime.capabilities()))
+            .collect()
+    }
+
+    /// Prepares the function and tears it down again, to check that it's available without
+    /// running it.
+    pub fn resolve(
+        &self,
+        module_type: ModuleType,
+        invocation: &Invocation,
+    ) -> Result<(), InvocationError> {
+        let runtime = self
+            .get(module_type)
+            .ok_or(InvocationError::UnsupportedRuntime(module_type))?;
+
+        let function = runtime.prepare(invocation).map_err(prepare_error)?;
+        runtime.teardown(function).map_err(InvocationError::Prepare)
+    }
+
+    /// Prepares the function once, invokes it `times` times with `input` and tears it down.
+    pub fn invoke(
+        &self,
+        module_type: ModuleType,
+        invocation: &Invocation,
+        input: &str,
+        times: usize,
+    ) -> Result<Vec<FunctionResult>, InvocationError> {
+        let runtime = self
+            .get(module_type)
+            .ok_or(InvocationError::UnsupportedRuntime(module_type))?;
+
+        let function = info_span!("prepare", func_name = %invocation.func_name)
+            .in_scope(|| runtime.prepare(invocation))
+            .map_err(prepare_error)?;
+
+        let results = (0..times)
+            .map(|_| {
+                let span = info_span!(
+                    "invocation",
+                    func_name = %function.func_name,
+                    func_type = %module_type,
+                    input,
+                    base_image = %function.base_image,
+                    version = function.version.as_deref(),
+                    result = Empty,
+                    error = Empty,
+                    exit_code = Empty,
+                    startup_time = Empty,
+                    total_runtime = Empty,
+                );
+
+                span.in_scope(|| runtime.invoke(&function, input))
+                    .map(|mut result| {
+                        result.version = function.version.clone();
+                        record_result(&span, &result);
+                        result
+                    })
+            })
+            .collect::<anyhow::Result<Vec<_>>>();
+
+        let teardown = runtime.teardown(function);
+
+        let results = results.map_err(InvocationError::Invoke)?;
+        teardown.map_err(InvocationError::Invoke)?;
+
+        Ok(results)
+    }
+}
+
+fn prepare_error(err: anyhow::Error) -> InvocationError {
+    if err.is::<RuntimeUnavailable>() {
+        InvocationError::Unavailable(err)
+    } else {
+        InvocationError::Prepare(err)
+    }
+}
+
+/// Records the outcome of an invocation on its span, times in microseconds.
+fn record_result(span: &Span, result: &FunctionResult) {
+    span.record("result", result.result.as_str());
+    if let Some(error) = &result.error {
+        span.record("error", error.as_str());
+    }
+    if let Some(exit_code) = result.exit_code {
+        span.record("exit_code", exit_code);
+    }
+    if let Some(metrics) = &result.metrics {
+        span.record("startup_time", metrics.startup_time as i64);
+        span.record("total_runtime", metrics.total_runtime as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use anyhow::bail;
+
+    use super::*;
+
+    /// Runtime echoing its input back, counting how often each step ran.
+    #[derive(Default)]
+    struct FakeRuntime {
+        prepared: AtomicUsize,
+        invoked: AtomicUsize,
+        torn_down: AtomicUsize,
+    }
+
+    impl FunctionRuntime for FakeRuntime {
+        fn module_type(&self) -> ModuleType {
+            ModuleType::Wasm
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                base_images: false,
+                precompiled: false,
+                process_isolation: false,
+            }
+        }
+
+        fn prepare(&self, invocation: &Invocation) -> anyhow::Result<PreparedFunction> {
+            if invocation.func_name == "missing" {
+                bail!("Unknown function missing");
+            }
+            self.prepared.fetch_add(1, Ordering::SeqCst);
+            Ok(PreparedFunction {
+                func_name: invocation.func_name.clone(),
+                base_image: invocation.base_image.clone(),
+                target: format!("fake://{}", invocation.func_name),
+                version: Some("v1".to_string()),
+                limits: invocation.limits.clone(),
+            })
+        }
+
+        fn invoke(
+            &self,
+            function: &PreparedFunction,
+            input: &str,
+        ) -> anyhow::Result<FunctionResult> {
+            self.invoked.fetch_add(1, Ordering::SeqCst);
+            Ok(FunctionResult {
+                metrics: None,
+                result: input.to_string(),
+                func_type: self.module_type(),
+                func_name: function.func_name.clone(),
+                input: input.to_string(),
+                base_image: function.base_image.clone(),
+                error: None,
+                exit_code: Some(0),
+                version: None,
+                trigger: Default::default(),
+            })
+        }
+
+        fn teardown(&self, _function: PreparedFunction) -> anyhow::Result<()> {
+            self.torn_down.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn invocation(func_name: &str) -> Invocation {
+        Invocation {
+            func_name: func_name.to_string(),
+            base_image: "debian".to_string(),
+            limits: ExecutionLimits::default(),
+        }
+    }
+
+    #[test]
+    fn dispatches_to_registered_runtime() {
+        let runtime = Arc::new(FakeRuntime::default());
+        let mut registry = RuntimeRegistry::new();
+        registry.register(runtime.clone());
+
+        let results = registry
+            .invoke(ModuleType::Wasm, &invocation("echo"), "5", 3)
+            .unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].result, "5");
+        assert_eq!(results[0].version.as_deref(), Some("v1"));
+        assert_eq!(runtime.prepared.load(Ordering::SeqCst), 1);
+        assert_eq!(runtime.invoked.load(Ordering::SeqCst), 3);
+        assert_eq!(runtime.torn_down.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn rejects_unregistered_module_types() {
+        let mut registry = RuntimeRegistry::new();
+        registry.register(Arc::new(FakeRuntime::default()));
+
+        let err = registry
+            .invoke(ModuleType::Docker, &invocation("echo"), "5", 1)
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            InvocationError::UnsupportedRuntime(ModuleType::Docker)
+        ));
+    }
+
+    #[test]
+    fn reports_prepare_failures() {
+        let mut registry = RuntimeRegistry::new();
+        registry.register(Arc::new(FakeRuntime::default()));
+
+        let err = registry
+            .invoke(ModuleType::Wasm, &invocation("missing"), "5", 1)
+            .unwrap_err();
+
+        assert!(matches!(err, InvocationError::Prepare(_)));
+        assert_eq!(err.to_string(), "Unknown function missing");
+        assert!(matches!(
+            registry.resolve(ModuleType::Wasm, &invocation("missing")),
+            Err(InvocationError::Prepare(_))
+        ));
+        assert!(registry
+            .resolve(ModuleType::Wasm, &invocation("factorial"))
+            .is_ok());
+    }
+}
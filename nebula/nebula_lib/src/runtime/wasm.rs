@@ -0,0 +1,97 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+
+use crate::{
+    models::{FunctionResult, ModuleType},
+    module_store::ModuleStore,
+    wasm_runner::run_wasi_module,
+};
+
+use super::{Capabilities, FunctionRuntime, Invocation, PreparedFunction};
+
+/// Runs precompiled WASI modules in-process with wasmtime.
+pub struct WasmRuntime {
+    modules: ModuleStore,
+}
+
+impl WasmRuntime {
+    pub fn new(modules: ModuleStore) -> Self {
+        WasmRuntime { modules }
+    }
+}
+
+impl FunctionRuntime for WasmRuntime {
+    fn module_type(&self) -> ModuleType {
+        ModuleType::Wasm
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            base_images: false,
+            precompiled: true,
+            process_isolation: false,
+        }
+    }
+
+    /// Resolves `name`, `name@alias` or `name@version` through the module store.
+    fn prepare(&self, invocation: &Invocation) -> Result<PreparedFunction> {
+        let (module, module_path) = self.modules.resolve(&invocation.func_name)?;
+
+        if !module_path.is_file() {
+            bail!(
+                "Module for {}@{} is missing from {:?}",
+                module.name,
+                module.short_version(),
+                module_path
+            );
+        }
+
+        Ok(PreparedFunction {
+            func_name: module.name,
+            base_image: "N/A".to_string(),
+            target: module_path.to_string_lossy().to_string(),
+            version: Some(module.version),
+            limits: invocation.limits.clone(),
+        })
+    }
+
+    fn invoke(&self, function: &PreparedFunction, input: &str) -> Result<FunctionResult> {
+        run_wasi_module(
+            input,
+            PathBuf::from(&function.target),
+            &function.func_name,
+            &function.limits,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deploy::DeployDirs;
+
+    use super::*;
+
+    #[test]
+    fn rejects_unknown_modules() {
+        let dir = PathBuf::from("./does-not-exist");
+        let modules = ModuleStore::open(
+            DeployDirs {
+                serialized_dir: dir.join("serialized"),
+                archive_dir: dir.join("archive"),
+            },
+            dir.join("registry.json"),
+        )
+        .unwrap();
+        let runtime = WasmRuntime::new(modules);
+        let invocation = Invocation {
+            func_name: "factorial".to_string(),
+            base_image: "debian".to_string(),
+            limits: Default::default(),
+        };
+
+        let err = runtime.prepare(&invocation).unwrap_err();
+
+        assert_eq!(err.to_string(), "Unknown wasm function factorial");
+    }
+}
@@ -2,23 +2,43 @@
 
 use std::{
     fs::File,
-    io::{Read, Write},
+    io::Read,
     path::PathBuf,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
     time::Instant,
 };
 
 use anyhow::Result;
-use wasi_common::pipe::{ReadPipe, WritePipe};
+use tracing::{debug, info_span};
+use wasi_common::{
+    pipe::{ReadPipe, WritePipe},
+    WasiCtx,
+};
 
 use wasmtime::*;
-use wasmtime_wasi::sync::WasiCtxBuilder;
+use wasmtime_wasi::{sync::WasiCtxBuilder, I32Exit};
 
 use crate::{
     docker_runner::current_micros,
-    list_files::list_files,
+    manifest::ExecutionLimits,
     models::{FunctionResult, Metrics, ModuleType},
+    protocol::parse_output,
 };
 
+struct WasmState {
+    wasi: WasiCtx,
+    limits: StoreLimits,
+}
+
+/// Configuration of every engine, modules only deserialize into an engine configured like the one
+/// that compiled them. Epoch interruption is what stops modules at their timeout.
+pub fn engine_config() -> Config {
+    let mut config = Config::new();
+    config.epoch_interruption(true);
+    config
+}
+
 fn load_module(engine: &Engine, wasi_module_path: PathBuf) -> Result<Module, anyhow::Error> {
     let mut module_file = File::open(wasi_module_path)?;
     let mut module_bytes = Vec::<u8>::new();
@@ -32,14 +52,15 @@ pub fn run_wasi_module(
     input: &str,
     wasi_module_path: PathBuf,
     func_name: &str,
+    limits: &ExecutionLimits,
 ) -> Result<FunctionResult, anyhow::Error> {
     let start_since_epoch = current_micros()?;
     let start = Instant::now();
     // Define the WASI functions globally on the `Config`.
-    let engine = Engine::default();
+    let engine = Engine::new(&engine_config())?;
     let mut linker = Linker::new(&engine);
 
-    wasmtime_wasi::add_to_linker(&mut linker, |s| s)?;
+    wasmtime_wasi::add_to_linker(&mut linker, |state: &mut WasmState| &mut state.wasi)?;
 
     let stdin = ReadPipe::from(input);
     let stdout = WritePipe::new_in_memory();
@@ -47,30 +68,78 @@ pub fn run_wasi_module(
     // Create a WASI context and put it in a Store; all instances in the store
     // share this context. `WasiCtxBuilder` provides a number of ways to
     // configure what the target program will have access to.
+    let env: Vec<(String, String)> = limits.env.clone().into_iter().collect();
     let wasi = WasiCtxBuilder::new()
         .stdin(Box::new(stdin.clone()))
         .stdout(Box::new(stdout.clone()))
+        .envs(&env)?
         .build();
 
-    let mut store = Store::new(&engine, wasi);
+    let mut store_limits = StoreLimitsBuilder::new();
+    if let Some(memory_limit) = limits.memory_limit {
+        store_limits = store_limits.memory_size(memory_limit as usize);
+    }
+
+    let mut store = Store::new(
+        &engine,
+        WasmState {
+            wasi,
+            limits: store_limits.build(),
+        },
+    );
+    store.limiter(|state| &mut state.limits);
+    // The epoch only moves once the timeout is up.
+    store.set_epoch_deadline(1);
 
     // Instantiate our module with the imports we've created, and run it.
 
-    let module = load_module(&engine, wasi_module_path)?;
+    let module = info_span!("module_load").in_scope(|| load_module(&engine, wasi_module_path))?;
 
     let startup_time = start.clone().elapsed().as_micros();
 
-    linker
-        .module(&mut store, "", &module)
-        .expect("the function to be linked");
+    info_span!("instantiate").in_scope(|| {
+        linker
+            .module(&mut store, "", &module)
+            .expect("the function to be linked");
+    });
+
+    let (done, watchdog) = mpsc::channel::<()>();
+    if let Some(timeout) = limits.timeout {
+        let engine = engine.clone();
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = watchdog.recv_timeout(timeout) {
+                engine.increment_epoch();
+            }
+        });
+    }
 
-    linker
-        .get_default(&mut store, "")
-        .expect("Should get the wasi runtime")
-        .typed::<(), ()>(&store)
-        .expect("should type the function")
-        .call(&mut store, ())
-        .expect("should call the function");
+    let call_result = info_span!("execute").in_scope(|| {
+        linker
+            .get_default(&mut store, "")
+            .expect("Should get the wasi runtime")
+            .typed::<(), ()>(&store)
+            .expect("should type the function")
+            .call(&mut store, ())
+    });
+
+    drop(done);
+
+    // A WASI command exiting through `proc_exit` surfaces as an `I32Exit` error, anything else is
+    // a trap.
+    let (exit_code, trap) = match call_result {
+        Ok(()) => (0, None),
+        Err(err) => match (err.downcast_ref::<I32Exit>(), err.downcast_ref::<Trap>()) {
+            (Some(exit), _) => (exit.0, None),
+            (None, Some(Trap::Interrupt)) => (
+                1,
+                Some(format!(
+                    "Timed out after {}ms",
+                    limits.timeout.unwrap_or_default().as_millis()
+                )),
+            ),
+            _ => (1, Some(format!("{:#}", err))),
+        },
+    };
 
     drop(store);
 
@@ -79,66 +148,67 @@ pub fn run_wasi_module(
         .map_err(|_err| anyhow::Error::msg("sole remaining reference"))?
         .into_inner();
 
-    let result = String::from_utf8(contents)?.trim().to_string();
+    let parsed =
+        info_span!("output_parse").in_scope(|| parse_output(&String::from_utf8_lossy(&contents)));
+
+    let error = trap
+        .or(parsed.error)
+        .or_else(|| (exit_code != 0).then(|| format!("Module exited with status {}", exit_code)));
 
     let total_runtime = start.elapsed().as_micros();
 
-    println!(
-        "Done! Elapsed time: {:.2}ms, used {:.2}ms to start up.",
+    debug!(
+        "done in {:.2}ms, used {:.2}ms to start up",
         total_runtime as f64 / 1000.0,
         startup_time as f64 / 1000.0
     );
 
     Ok(FunctionResult {
-        result,
+        result: parsed.result,
         metrics: Some(Metrics {
             startup_time,
             start_since_epoch,
             total_runtime,
             end_since_epoch: start_since_epoch + total_runtime,
             startup_percentage: ((startup_time as f64 / total_runtime as f64) * 100.0).round(),
+            container_startup_time: None,
+            startup_anomaly: None,
+            queue_time: None,
         }),
         func_type: ModuleType::Wasm,
         func_name: func_name.to_string(),
         input: input.to_string(),
         base_image: "N/A".to_string(),
+        error,
+        exit_code: parsed.exit_code.or(Some(exit_code)),
+        version: None,
+        trigger: Default::default(),
     })
 }
 
-pub fn serialize_wasm_modules(module_dir: PathBuf, serialized_dir: PathBuf) -> anyhow::Result<()> {
-    let modules = list_files(module_dir.to_str().unwrap())?;
-    let engine = Engine::default();
-
-    for module in modules {
-        let module_name = module.file_name().unwrap();
-        let module = Module::from_file(&engine, &module)?;
-        let module_bytes = module.serialize()?;
-
-        let target_module = serialized_dir.join(module_name);
-
-        let mut file = File::create(target_module)?;
-
-        file.write_all(&module_bytes)?;
-    }
-
-    println!("{:?}", serialized_dir);
-
-    Ok(())
-}
-
 #[cfg(test)]
 mod tests {
-    use crate::list_files::list_files;
-
     use super::*;
 
     #[test]
-    fn it_works() {
-        let files = list_files("../faas_user/modules").unwrap();
-        println!("{:?}", files);
-        match run_wasi_module("2", PathBuf::from(""), "") {
-            Ok(_list) => assert_eq!(2, 3),
-            Err(_err) => assert_eq!(1, 2),
-        }
+    fn stops_modules_at_their_timeout() {
+        let path = std::env::temp_dir().join(format!("nebula-loop-{}.cwasm", std::process::id()));
+        let module =
+            r#"(module (memory (export "memory") 1) (func (export "_start") (loop br 0)))"#;
+        std::fs::write(&path, crate::deploy::precompile(module.as_bytes()).unwrap()).unwrap();
+
+        let limits = ExecutionLimits {
+            timeout: Some(std::time::Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let result = run_wasi_module("", path.clone(), "loop", &limits).unwrap();
+
+        assert_eq!(result.error.as_deref(), Some("Timed out after 50ms"));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn it_fails_on_missing_module() {
+        assert!(run_wasi_module("2", PathBuf::from(""), "", &ExecutionLimits::default()).is_err());
     }
 }
@@ -1,189 +1,210 @@
 use std::sync::Arc;
 
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Form};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
 use nebula_lib::{
-    docker_runner::run_docker_image,
-    models::{FunctionResult, ModuleType},
-    wasm_runner::run_wasi_module,
+    manifest::{ExecutionLimits, UNDECLARED_INPUT},
+    models::FunctionResult,
+    module_store::parse_reference,
+    runtime::Invocation,
 };
-use serde::Serialize;
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use tracing::{info, info_span, warn, Instrument, Span};
 
 use crate::{
-    models::{AppState, FCList, FunctionRequest},
+    api::{
+        error::ApiError,
+        results::{get_fc_list, ResultsQuery},
+    },
+    api_keys::{self, Caller},
+    concurrency::InvocationPermit,
+    models::{AppState, FunctionRequest},
     utilities::{
-        get_file_path::get_file_path,
         html_template::HtmlTemplate,
-        persist::save_results,
-        sanitize_input::{get_limits, sanitize_input},
+        negotiate::{Payload, ResponseFormat},
     },
 };
 
+/// Body of a successful invocation when JSON is negotiated. Each call of the function gets an
+/// entry in `results`; a function that ran but failed is still a `200 OK`, with its
+/// `FunctionResult::error` set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InvocationResponse {
+    pub results: Vec<FunctionResult>,
+}
+
+/// Invokes a function from a JSON or form encoded [`FunctionRequest`].
+///
+/// Responds with the htmx results fragment for htmx and browser requests, and with an
+/// [`InvocationResponse`] otherwise. Failures are reported as
+/// [`ErrorResponse`](crate::api::error::ErrorResponse)s:
+/// - `400 invalid_request` if the body can't be parsed
+/// - `400 unsupported_runtime` if no runtime is registered for the module type
+/// - `404 function_not_found` if the function isn't deployed for the runtime
+/// - `422 invalid_input` if the input doesn't match the function's manifest
+/// - `429 quota_exceeded` if the calls exceed the quota of the API key
+/// - `429 too_many_requests` if too many invocations are waiting for a slot
+/// - `500 invocation_failed` if the runtime failed to run the function
 pub async fn call_function(
     State(state): State<Arc<AppState>>,
-    Form(request): Form<FunctionRequest>,
-) -> impl IntoResponse {
-    info!(
-        "calling function: {:?}, type: {:?}, {} times",
-        request.function_name, request.module_type, request.num_calls
-    );
-
-    let limits = get_limits();
+    format: ResponseFormat,
+    caller: Option<Extension<Caller>>,
+    Payload(request): Payload<FunctionRequest>,
+) -> Result<Response, ApiError> {
+    let caller = caller.map(|Extension(caller)| caller);
+    let results = run_request(&state, caller.as_ref(), request)
+        .await
+        .map_err(|err| err.with_format(format))?;
+
+    let response = match format {
+        ResponseFormat::Html => {
+            let template = get_fc_list(&state, ResultsQuery::default())
+                .await
+                .map_err(|err| err.with_format(format))?;
+            HtmlTemplate(template).into_response()
+        }
+        ResponseFormat::Json => Json(InvocationResponse { results }).into_response(),
+    };
 
-    let mut results = Vec::new();
+    Ok(response)
+}
 
-    for _ in 0..request.num_calls {
-        let req = request.clone();
-        let input = &req.input;
-        let input = &sanitize_input(&req.function_name, input, &limits);
-        let result: FunctionResult = match request.module_type {
-            ModuleType::Docker => {
-                let docker_module =
-                    format!("nebula-function-{}-{}", req.function_name, req.base_image);
-                run_docker_image(&docker_module, input, req.function_name, req.base_image)
-                    .expect("It to work")
-            }
-            ModuleType::Wasm => {
-                let function_path = get_file_path(&req.function_name);
-                run_wasi_module(input, function_path, &req.function_name).expect("to work")
-            }
-        };
+/// Runs `request` within the quota of `caller` and the concurrency limits, and records the
+/// results.
+pub async fn run_request(
+    state: &AppState,
+    caller: Option<&Caller>,
+    request: FunctionRequest,
+) -> Result<Vec<FunctionResult>, ApiError> {
+    let limits = validate_request(state, &request)?;
+    api_keys::reserve(state, caller, request.num_calls as u32)?;
+
+    run_reserved(state, caller, request, limits).await
+}
 
-        results.push(result);
+/// Runs `request`, which passed [`validate_request`] with `limits` and is counted against the
+/// quota of `caller` already, within the concurrency limits, and records the results.
+pub async fn run_reserved(
+    state: &AppState,
+    caller: Option<&Caller>,
+    request: FunctionRequest,
+    limits: ExecutionLimits,
+) -> Result<Vec<FunctionResult>, ApiError> {
+    let (func_name, _) = parse_reference(&request.function_name);
+    let permit = state
+        .limits
+        .acquire(request.module_type, func_name)
+        .instrument(info_span!("queue"))
+        .await?;
+
+    let results = invoke_request(state, request, limits, permit).await?;
+
+    if let Some(caller) = caller {
+        state.api_keys.record_compute(&caller.name, &results);
     }
+    record_results(state, &results).await;
 
-    let mut lock = state.function_calls.lock().await;
-
-    for result in results {
-        lock.push(result);
-    }
+    Ok(results)
+}
 
-    let function_results: Vec<FunctionResult> = lock.clone().into_iter().rev().collect();
-    let _ = save_results(function_results.clone());
+/// Adds `results` to the invocation history and the Prometheus metrics and streams them to the
+/// subscribers, logging rather than failing the invocation if they can't be stored. Returns the
+/// ids of their history rows, `None` if they couldn't be stored.
+pub async fn record_results(state: &AppState, results: &[FunctionResult]) -> Option<Vec<i64>> {
+    state.prometheus.record(results);
+
+    let history = state.history.clone();
+    let batch = results.to_vec();
+    let span = info_span!("persist", results = results.len());
+
+    let ids =
+        match tokio::task::spawn_blocking(move || span.in_scope(|| history.insert(&batch))).await {
+            Ok(Ok(ids)) => Some(ids),
+            Ok(Err(err)) => {
+                warn!("failed to record results: {:#}", err);
+                None
+            }
+            Err(err) => {
+                warn!("failed to record results: {}", err);
+                None
+            }
+        };
 
-    let template = get_fc_list(function_results);
+    // After inserting, so the averages sent along include them.
+    state.live.publish(results);
 
-    HtmlTemplate(template)
+    ids
 }
 
-#[derive(Serialize)]
-struct HeadlessResponse {
-    results: Vec<FunctionResult>,
+/// Checks the input against the function's manifest, or against [`UNDECLARED_INPUT`] if it has
+/// none, returning the limits it runs under.
+pub fn validate_request(
+    state: &AppState,
+    request: &FunctionRequest,
+) -> Result<ExecutionLimits, ApiError> {
+    let (func_name, _) = parse_reference(&request.function_name);
+
+    match state.modules.manifest(func_name)? {
+        Some(manifest) => {
+            manifest.input.validate(&request.input)?;
+            Ok(manifest.limits())
+        }
+        None => {
+            UNDECLARED_INPUT.validate(&request.input)?;
+            Ok(ExecutionLimits::default())
+        }
+    }
 }
 
-pub async fn call_function_headless(
-    // State(_state): State<Arc<AppState>>,
-    Form(request): Form<FunctionRequest>,
-) -> impl IntoResponse {
+/// Runs the request on the runtime registered for its module type, holding `permit` until it's
+/// done.
+pub async fn invoke_request(
+    state: &AppState,
+    request: FunctionRequest,
+    limits: ExecutionLimits,
+    permit: InvocationPermit,
+) -> Result<Vec<FunctionResult>, ApiError> {
     info!(
         "calling function: {:?}, type: {:?}, {} times",
         request.function_name, request.module_type, request.num_calls
     );
 
-    let limits = get_limits();
-
-    let mut results = Vec::new();
-
-    for _ in 0..request.num_calls {
-        let req = request.clone();
-        let input = &req.input;
-        let input = &sanitize_input(&req.function_name, input, &limits);
-        let result: FunctionResult = match request.module_type {
-            ModuleType::Docker => {
-                let docker_module =
-                    format!("nebula-function-{}-{}", req.function_name, req.base_image);
-                run_docker_image(&docker_module, input, req.function_name, req.base_image)
-                    .expect("It to work")
-            }
-            ModuleType::Wasm => {
-                let function_path = get_file_path(&req.function_name);
-                run_wasi_module(input, function_path, &req.function_name).expect("to work")
-            }
-        };
-
-        results.push(result);
-    }
-
-    let body = HeadlessResponse { results };
-    let body = serde_json::to_string(&body).expect("failed to serialize results");
-
-    (StatusCode::OK, body).into_response()
-}
-
-pub fn get_fc_list(function_results: Vec<FunctionResult>) -> FCList {
-    let total_invocations = function_results.len();
-    let results = if total_invocations == 0 {
-        FCList {
-            function_results,
-            total_wasm_invocations: 0,
-            total_docker_invocations: 0,
-            avg_wasm_startup: 0,
-            avg_docker_startup: 0,
-            avg_wasm_runtime: 0,
-            avg_docker_runtime: 0,
-            avg_wasm_total_time: 0,
-            avg_docker_total_time: 0,
-        }
-    } else {
-        let wasm_results: Vec<FunctionResult> = function_results
-            .clone()
-            .into_iter()
-            .filter(|result| matches!(result.func_type, ModuleType::Wasm))
-            .collect();
-
-        let total_wasm_invocations = wasm_results.len();
-        let wasm_startup_times: u128 = wasm_results
-            .iter()
-            .map(|result| result.metrics.as_ref().unwrap().startup_time)
-            .sum();
-        let wasm_runtime_sum: u128 = wasm_results
-            .iter()
-            .map(|result| {
-                let metrics = result.metrics.as_ref().unwrap();
-                metrics.total_runtime - metrics.startup_time
-            })
-            .sum();
-        let wasm_total_time_sum: u128 = wasm_results
-            .iter()
-            .map(|result| result.metrics.as_ref().unwrap().total_runtime)
-            .sum();
-
-        let docker_results: Vec<FunctionResult> = function_results
-            .clone()
-            .into_iter()
-            .filter(|result| matches!(result.func_type, ModuleType::Docker))
-            .collect();
-
-        let total_docker_invocations = docker_results.len();
-        let docker_startup_times: u128 = docker_results
-            .iter()
-            .map(|result| result.metrics.as_ref().unwrap().startup_time)
-            .sum();
-        let docker_runtime_sum: u128 = docker_results
-            .iter()
-            .map(|result| {
-                let metrics = result.metrics.as_ref().unwrap();
-                metrics.total_runtime - metrics.startup_time
-            })
-            .sum();
-        let docker_total_time_sum: u128 = docker_results
-            .iter()
-            .map(|result| result.metrics.as_ref().unwrap().total_runtime)
-            .sum();
-
-        FCList {
-            function_results,
-            total_wasm_invocations,
-            total_docker_invocations,
-            avg_wasm_startup: wasm_startup_times / (total_wasm_invocations as u128).max(1),
-            avg_wasm_runtime: wasm_runtime_sum / (total_wasm_invocations as u128).max(1),
-            avg_wasm_total_time: wasm_total_time_sum / (total_wasm_invocations as u128).max(1),
-            avg_docker_startup: docker_startup_times / (total_docker_invocations as u128).max(1),
-            avg_docker_runtime: docker_runtime_sum / (total_docker_invocations as u128).max(1),
-            avg_docker_total_time: docker_total_time_sum
-                / (total_docker_invocations as u128).max(1),
-        }
+    let input = request.input;
+    let trigger = request.trigger;
+    let invocation = Invocation {
+        func_name: request.function_name,
+        base_image: request.base_image,
+        limits,
     };
+    let runtimes = state.runtimes.clone();
+    // Blocking tasks don't inherit the current span.
+    let span = Span::current();
+
+    let mut results = tokio::task::spawn_blocking(move || {
+        span.in_scope(|| {
+            runtimes.invoke(
+                request.module_type,
+                &invocation,
+                &input,
+                request.num_calls as usize,
+            )
+        })
+    })
+    .await
+    .map_err(|err| ApiError::internal(err.to_string()))??;
+
+    let permit_queue_time = permit.queue_time.as_micros();
+    drop(permit);
+
+    for result in &mut results {
+        result.trigger = trigger;
+        if let Some(metrics) = result.metrics.as_mut() {
+            metrics.queue_time = Some(permit_queue_time);
+        }
+    }
 
-    results
+    Ok(results)
 }
@@ -0,0 +1,316 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use nebula_lib::models::{FunctionResult, ModuleType};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    api::{
+        call_function::run_request,
+        error::{ApiError, ErrorBody},
+    },
+    api_keys::Caller,
+    models::{default_image, AppState, FunctionRequest},
+    utilities::{
+        format::format_micro_to_milli,
+        html_template::HtmlTemplate,
+        negotiate::{Payload, ResponseFormat},
+    },
+};
+
+/// Most rounds a single comparison may run.
+pub const MAX_ROUNDS: u8 = 20;
+
+/// Body of `POST /api/compare`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComparisonRequest {
+    pub function_name: String,
+    pub input: String,
+    /// Docker base images to compare with Wasm, a list or a comma separated string.
+    #[serde(default = "default_base_images", deserialize_with = "comma_separated")]
+    pub base_images: Vec<String>,
+    /// Invocations on every runtime.
+    #[serde(default = "default_rounds")]
+    pub rounds: u8,
+}
+
+fn default_base_images() -> Vec<String> {
+    vec![default_image()]
+}
+
+fn default_rounds() -> u8 {
+    1
+}
+
+/// Forms can't send lists, so they send the base images as `debian, alpine`.
+fn comma_separated<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum List {
+        Joined(String),
+        Items(Vec<String>),
+    }
+
+    Ok(match List::deserialize(deserializer)? {
+        List::Joined(joined) => joined
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect(),
+        List::Items(items) => items,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Comparison {
+    pub function_name: String,
+    pub input: String,
+    pub rounds: u8,
+    /// Wasm first, then Docker on each base image in the requested order.
+    pub runtimes: Vec<RuntimeComparison>,
+    /// Whether every invocation succeeded with the same output.
+    pub identical_outputs: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuntimeComparison {
+    pub module_type: ModuleType,
+    /// `None` for Wasm.
+    pub base_image: Option<String>,
+    /// One result per round.
+    pub results: Vec<FunctionResult>,
+    /// Why the function couldn't be invoked on this runtime.
+    pub error: Option<ErrorBody>,
+    /// Means over the rounds, in microseconds.
+    pub startup_time: u128,
+    pub total_runtime: u128,
+    /// Difference to the means of Wasm in microseconds, positive when slower than Wasm. `None`
+    /// for Wasm itself, or if either has no results.
+    pub startup_delta: Option<i128>,
+    pub total_delta: Option<i128>,
+}
+
+impl RuntimeComparison {
+    fn new(module_type: ModuleType, base_image: Option<String>) -> Self {
+        RuntimeComparison {
+            module_type,
+            base_image,
+            results: Vec::new(),
+            error: None,
+            startup_time: 0,
+            total_runtime: 0,
+            startup_delta: None,
+            total_delta: None,
+        }
+    }
+
+    fn request(&self, function_name: &str, input: &str) -> FunctionRequest {
+        FunctionRequest {
+            function_name: function_name.to_string(),
+            input: input.to_string(),
+            module_type: self.module_type,
+            num_calls: 1,
+            base_image: self.base_image.clone().unwrap_or_else(default_image),
+            trigger: Default::default(),
+        }
+    }
+
+    /// Sets the means of the results and their difference to `wasm`'s.
+    fn summarize(&mut self, wasm: Option<(u128, u128)>) {
+        let metrics: Vec<_> = self
+            .results
+            .iter()
+            .filter_map(|result| result.metrics.as_ref())
+            .collect();
+        if metrics.is_empty() {
+            return;
+        }
+
+        let count = metrics.len() as u128;
+        self.startup_time = metrics.iter().map(|m| m.startup_time).sum::<u128>() / count;
+        self.total_runtime = metrics.iter().map(|m| m.total_runtime).sum::<u128>() / count;
+
+        if let Some((startup_time, total_runtime)) = wasm {
+            self.startup_delta = Some(self.startup_time as i128 - startup_time as i128);
+            self.total_delta = Some(self.total_runtime as i128 - total_runtime as i128);
+        }
+    }
+}
+
+impl Comparison {
+    fn summarize(&mut self) {
+        let mut wasm = None;
+        for runtime in &mut self.runtimes {
+            runtime.summarize(wasm);
+            if runtime.module_type == ModuleType::Wasm && !runtime.results.is_empty() {
+                wasm = Some((runtime.startup_time, runtime.total_runtime));
+            }
+        }
+
+        let results: Vec<&FunctionResult> = self
+            .runtimes
+            .iter()
+            .flat_map(|runtime| &runtime.results)
+            .collect();
+        self.identical_outputs = self.runtimes.iter().all(|runtime| runtime.error.is_none())
+            && results.iter().all(|result| result.error.is_none())
+            && results
+                .windows(2)
+                .all(|pair| pair[0].result.trim() == pair[1].result.trim());
+    }
+}
+
+#[derive(Template)]
+#[template(path = "components/comparison.rs.html")]
+struct ComparisonTemplate {
+    comparison: Comparison,
+}
+
+impl ComparisonTemplate {
+    fn format_time(&self, time: &u128) -> String {
+        format_micro_to_milli(*time)
+    }
+
+    fn format_delta(&self, delta: &Option<i128>) -> String {
+        match delta {
+            Some(delta) if *delta < 0 => {
+                format!("-{}", format_micro_to_milli(delta.unsigned_abs()))
+            }
+            Some(delta) => format!("+{}", format_micro_to_milli(*delta as u128)),
+            None => String::new(),
+        }
+    }
+
+    /// Output of the first round, or its error.
+    fn output(&self, runtime: &RuntimeComparison) -> String {
+        match runtime.results.first() {
+            Some(FunctionResult {
+                error: Some(error), ..
+            }) => format!("Error: {}", error),
+            Some(result) => result.result.clone(),
+            None => String::new(),
+        }
+    }
+}
+
+/// Runs the function with the same input on Wasm and on Docker with each base image, one round
+/// at a time so the runtimes take turns, and responds with them side by side. A runtime the
+/// function can't be invoked on has its `error` set, the comparison only fails if none of them
+/// ran.
+pub async fn compare(
+    State(state): State<Arc<AppState>>,
+    format: ResponseFormat,
+    caller: Option<Extension<Caller>>,
+    Payload(request): Payload<ComparisonRequest>,
+) -> Result<Response, ApiError> {
+    let caller = caller.map(|Extension(caller)| caller);
+
+    if request.base_images.is_empty() || !(1..=MAX_ROUNDS).contains(&request.rounds) {
+        return Err(ApiError::invalid_request(format!(
+            "Expected at least one base image and 1 to {} rounds",
+            MAX_ROUNDS
+        ))
+        .with_format(format));
+    }
+
+    let mut comparison = Comparison {
+        function_name: request.function_name,
+        input: request.input,
+        rounds: request.rounds,
+        runtimes: std::iter::once(RuntimeComparison::new(ModuleType::Wasm, None))
+            .chain(
+                request
+                    .base_images
+                    .into_iter()
+                    .map(|base_image| RuntimeComparison::new(ModuleType::Docker, Some(base_image))),
+            )
+            .collect(),
+        identical_outputs: false,
+    };
+
+    let mut first_error = None;
+    for _ in 0..comparison.rounds {
+        for runtime in &mut comparison.runtimes {
+            if runtime.error.is_some() {
+                continue;
+            }
+
+            let request = runtime.request(&comparison.function_name, &comparison.input);
+            match run_request(&state, caller.as_ref(), request).await {
+                Ok(results) => runtime.results.extend(results),
+                Err(err) => {
+                    runtime.error = Some(ErrorBody {
+                        code: err.code.to_string(),
+                        message: err.message.clone(),
+                    });
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+    }
+
+    if let Some(err) = first_error.filter(|_| {
+        comparison
+            .runtimes
+            .iter()
+            .all(|runtime| runtime.results.is_empty())
+    }) {
+        return Err(err.with_format(format));
+    }
+    comparison.summarize();
+
+    Ok(match format {
+        ResponseFormat::Html => HtmlTemplate(ComparisonTemplate { comparison }).into_response(),
+        ResponseFormat::Json => Json(comparison).into_response(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(output: &str, startup_time: u128, total_runtime: u128) -> FunctionResult {
+        FunctionResult {
+            result: output.to_string(),
+            ..FunctionResult::test_fixture(startup_time, total_runtime)
+        }
+    }
+
+    #[test]
+    fn pairs_runtimes_with_deltas() {
+        let request: ComparisonRequest = serde_urlencoded::from_str(
+            "function_name=factorial&input=5&base_images=debian,%20alpine&rounds=2",
+        )
+        .unwrap();
+        assert_eq!(request.base_images, ["debian", "alpine"]);
+
+        let mut wasm = RuntimeComparison::new(ModuleType::Wasm, None);
+        wasm.results = vec![result("120", 100, 1_000), result("120", 300, 3_000)];
+        let mut debian = RuntimeComparison::new(ModuleType::Docker, Some("debian".to_string()));
+        debian.results = vec![result("120\n", 50_000, 60_000)];
+        let mut comparison = Comparison {
+            function_name: request.function_name,
+            input: request.input,
+            rounds: request.rounds,
+            runtimes: vec![wasm, debian],
+            identical_outputs: false,
+        };
+
+        comparison.summarize();
+        assert!(comparison.identical_outputs);
+        assert_eq!(comparison.runtimes[0].total_runtime, 2_000);
+        assert_eq!(comparison.runtimes[0].total_delta, None);
+        assert_eq!(comparison.runtimes[1].startup_delta, Some(49_800));
+        assert_eq!(comparison.runtimes[1].total_delta, Some(58_000));
+
+        comparison.runtimes[1].results[0].result = "121".to_string();
+        comparison.summarize();
+        assert!(!comparison.identical_outputs);
+    }
+}
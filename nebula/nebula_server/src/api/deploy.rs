@@ -0,0 +1,252 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    extract::{
+        multipart::{Field, MultipartRejection},
+        Multipart, State,
+    },
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use nebula_lib::{deploy::DeployError, manifest::FunctionManifest, module_store::DeployedModule};
+use tracing::info;
+
+use crate::{
+    api::error::ApiError,
+    models::AppState,
+    utilities::{html_template::HtmlTemplate, negotiate::ResponseFormat},
+};
+
+/// Largest module accepted by the deploy endpoint.
+pub const MAX_MODULE_SIZE: usize = 32 * 1024 * 1024;
+
+/// Longest `token` field read before the token is checked.
+const MAX_TOKEN_SIZE: usize = 1024;
+
+#[derive(Template)]
+#[template(path = "components/deployed_module.rs.html")]
+struct DeployedModuleTemplate {
+    module: DeployedModule,
+}
+
+#[derive(Default)]
+struct DeployForm {
+    name: Option<String>,
+    alias: Option<String>,
+    module: Option<Vec<u8>>,
+    manifest: Option<String>,
+}
+
+/// Deploys a Wasm function from a multipart upload with the fields:
+/// - `name`, the function name
+/// - `module`, the compiled WASI command
+/// - `alias`, optional, an alias such as `stable` to point at the module besides `latest`
+/// - `manifest`, optional, the function's `nebula.toml`, replacing its current manifest
+/// - `token`, the deploy token, if it isn't sent as an `Authorization: Bearer` header, in which
+///   case it has to be the first field
+///
+/// Responds with `201 Created` and the [`DeployedModule`], after which the function can be
+/// invoked right away. Deploying a module that is already stored only moves the aliases. Fails
+/// with `422 invalid_manifest` before anything is stored if the manifest is invalid.
+pub async fn deploy_function(
+    State(state): State<Arc<AppState>>,
+    format: ResponseFormat,
+    headers: HeaderMap,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Response, ApiError> {
+    deploy(state, headers, multipart)
+        .await
+        .map(|module| match format {
+            ResponseFormat::Html => (
+                StatusCode::CREATED,
+                HtmlTemplate(DeployedModuleTemplate { module }),
+            )
+                .into_response(),
+            ResponseFormat::Json => (StatusCode::CREATED, Json(module)).into_response(),
+        })
+        .map_err(|err| err.with_format(format))
+}
+
+async fn deploy(
+    state: Arc<AppState>,
+    headers: HeaderMap,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<DeployedModule, ApiError> {
+    // The token is checked before the module is read, so uploads without a valid one aren't
+    // buffered.
+    let has_bearer = bearer_token(&headers).is_some();
+    if has_bearer {
+        authorize_deploy(&state, &headers, None)?;
+    } else {
+        deploy_token(&state)?;
+    }
+
+    let multipart = multipart.map_err(|err| ApiError::invalid_request(err.body_text()))?;
+    let form = read_form(&state, &headers, multipart, !has_bearer).await?;
+
+    let name = form
+        .name
+        .ok_or_else(|| ApiError::invalid_request("Missing field name"))?;
+    let module = form
+        .module
+        .ok_or_else(|| ApiError::invalid_request("Missing field module"))?;
+    let alias = form
+        .alias
+        .filter(|alias| !alias.trim().is_empty())
+        .map(|alias| alias.trim().to_string());
+    let manifest = form.manifest.filter(|manifest| !manifest.trim().is_empty());
+
+    let modules = state.modules.clone();
+    let deployed = tokio::task::spawn_blocking(move || {
+        let name = name.trim();
+
+        if let Some(manifest) = &manifest {
+            FunctionManifest::parse(manifest).map_err(DeployError::InvalidManifest)?;
+        }
+        let deployed = modules.deploy(name, alias.as_deref(), &module)?;
+        if let Some(manifest) = &manifest {
+            modules.set_manifest(name, manifest)?;
+        }
+
+        Ok::<_, DeployError>(deployed)
+    })
+    .await
+    .map_err(|err| ApiError::internal(err.to_string()))??;
+
+    info!("deployed {}@{}", deployed.name, deployed.short_version());
+
+    Ok(deployed)
+}
+
+/// Checks the deploy token, sent as an `Authorization: Bearer` header or as `form_token`.
+pub fn authorize_deploy(
+    state: &AppState,
+    headers: &HeaderMap,
+    form_token: Option<&str>,
+) -> Result<(), ApiError> {
+    let deploy_token = deploy_token(state)?;
+
+    if !bearer_token(headers)
+        .or(form_token)
+        .is_some_and(|token| tokens_match(token.trim(), deploy_token))
+    {
+        return Err(invalid_token());
+    }
+
+    Ok(())
+}
+
+fn deploy_token(state: &AppState) -> Result<&str, ApiError> {
+    state.deploy_token.as_deref().ok_or_else(|| {
+        ApiError::new(
+            StatusCode::FORBIDDEN,
+            "deploy_disabled",
+            "Deploying is disabled, start the server with a deploy token to enable it",
+        )
+    })
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn invalid_token() -> ApiError {
+    ApiError::new(
+        StatusCode::UNAUTHORIZED,
+        "unauthorized",
+        "Missing or invalid deploy token",
+    )
+}
+
+/// Reads the form, first checking the `token` field if `token_field` is set. That has to be the
+/// first field then, and isn't read beyond [`MAX_TOKEN_SIZE`].
+async fn read_form(
+    state: &AppState,
+    headers: &HeaderMap,
+    mut multipart: Multipart,
+    token_field: bool,
+) -> Result<DeployForm, ApiError> {
+    if token_field {
+        let field = multipart
+            .next_field()
+            .await
+            .map_err(|err| ApiError::invalid_request(err.body_text()))?
+            .filter(|field| field.name() == Some("token"))
+            .ok_or_else(|| {
+                ApiError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "unauthorized",
+                    "Send the deploy token as an Authorization: Bearer header or as the first \
+                     field of the form",
+                )
+            })?;
+        let token = read_token(field).await?;
+        authorize_deploy(state, headers, Some(&token))?;
+    }
+
+    let mut form = DeployForm::default();
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| ApiError::invalid_request(err.body_text()))?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+
+        match name.as_str() {
+            "module" => {
+                let bytes = field
+                    .bytes()
+                    .await
+                    .map_err(|err| ApiError::invalid_request(err.body_text()))?;
+                form.module = Some(bytes.to_vec());
+            }
+            "name" | "alias" | "manifest" => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|err| ApiError::invalid_request(err.body_text()))?;
+                match name.as_str() {
+                    "name" => form.name = Some(text),
+                    "alias" => form.alias = Some(text),
+                    _ => form.manifest = Some(text),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(form)
+}
+
+async fn read_token(mut field: Field<'_>) -> Result<String, ApiError> {
+    let mut token = Vec::new();
+
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|err| ApiError::invalid_request(err.body_text()))?
+    {
+        if token.len() + chunk.len() > MAX_TOKEN_SIZE {
+            return Err(invalid_token());
+        }
+        token.extend_from_slice(&chunk);
+    }
+
+    String::from_utf8(token).map_err(|_| invalid_token())
+}
+
+/// Compares in constant time, so the token can't be guessed byte by byte from response times.
+pub(crate) fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
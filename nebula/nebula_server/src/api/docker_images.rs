@@ -0,0 +1,63 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use axum::{extract::State, http::StatusCode, Json};
+use nebula_lib::docker_images::{
+    group_by_base_image, list_function_images, DockerImage, ProvisionReport,
+};
+use serde::Serialize;
+
+use crate::{
+    api::error::ApiError, models::AppState, utilities::provision_images::provision_images,
+};
+
+#[derive(Serialize)]
+pub struct DockerImagesResponse {
+    pub functions: BTreeSet<String>,
+    pub base_images: BTreeSet<String>,
+    /// Function names grouped by the base image they are built on.
+    pub by_base_image: BTreeMap<String, Vec<String>>,
+    pub images: Vec<DockerImage>,
+}
+
+impl From<Vec<DockerImage>> for DockerImagesResponse {
+    fn from(images: Vec<DockerImage>) -> Self {
+        DockerImagesResponse {
+            functions: images.iter().map(|image| image.func_name.clone()).collect(),
+            base_images: images
+                .iter()
+                .map(|image| image.base_image.clone())
+                .collect(),
+            by_base_image: group_by_base_image(&images),
+            images,
+        }
+    }
+}
+
+/// Lists the function images the container engine currently has.
+pub async fn get_docker_images() -> Result<Json<DockerImagesResponse>, ApiError> {
+    let images = tokio::task::spawn_blocking(list_function_images)
+        .await
+        .map_err(|err| ApiError::internal(err.to_string()))?
+        .map_err(|err| {
+            ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "container_engine_unavailable",
+                format!("{:#}", err),
+            )
+        })?;
+
+    Ok(Json(images.into()))
+}
+
+/// Rescans the docker module directory and loads any images that have appeared since startup.
+pub async fn provision_docker_images(State(state): State<Arc<AppState>>) -> Json<ProvisionReport> {
+    let docker_module_dir = state.dirs.docker_module_dir.clone();
+    let report = tokio::task::spawn_blocking(move || provision_images(&docker_module_dir))
+        .await
+        .unwrap_or_default();
+
+    Json(report)
+}
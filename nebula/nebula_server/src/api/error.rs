@@ -0,0 +1,145 @@
+use askama::Template;
+use axum::{
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use nebula_lib::{deploy::DeployError, manifest::InputError, runtime::InvocationError};
+use serde::{Deserialize, Serialize};
+
+use crate::utilities::{html_template::HtmlTemplate, negotiate::ResponseFormat};
+
+/// Body of every failed API request:
+///
+/// ```json
+/// { "error": { "code": "function_not_found", "message": "Unknown wasm function add" } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+    /// Stable, machine readable error code.
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Template)]
+#[template(path = "components/api_error.rs.html")]
+struct ApiErrorTemplate<'a> {
+    message: &'a str,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+    pub format: ResponseFormat,
+    /// Seconds after which the request may succeed, sent as `Retry-After`.
+    pub retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            format: ResponseFormat::Json,
+            retry_after: None,
+        }
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_request", message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", message)
+    }
+
+    pub fn with_format(mut self, format: ResponseFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+}
+
+impl From<InvocationError> for ApiError {
+    fn from(err: InvocationError) -> Self {
+        let (status, code) = match err {
+            InvocationError::UnsupportedRuntime(_) => {
+                (StatusCode::BAD_REQUEST, "unsupported_runtime")
+            }
+            InvocationError::Unavailable(_) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "runtime_unavailable")
+            }
+            InvocationError::Prepare(_) => (StatusCode::NOT_FOUND, "function_not_found"),
+            InvocationError::Invoke(_) => (StatusCode::INTERNAL_SERVER_ERROR, "invocation_failed"),
+        };
+        ApiError::new(status, code, err.to_string())
+    }
+}
+
+impl From<InputError> for ApiError {
+    fn from(err: InputError) -> Self {
+        ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_input", err.0)
+    }
+}
+
+impl From<DeployError> for ApiError {
+    fn from(err: DeployError) -> Self {
+        let (status, code) = match err {
+            DeployError::InvalidName(_) | DeployError::AmbiguousVersion(_, _) => {
+                (StatusCode::BAD_REQUEST, "invalid_request")
+            }
+            DeployError::InvalidModule(_) => (StatusCode::UNPROCESSABLE_ENTITY, "invalid_module"),
+            DeployError::InvalidManifest(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "invalid_manifest")
+            }
+            DeployError::UnknownFunction(_) => (StatusCode::NOT_FOUND, "function_not_found"),
+            DeployError::UnknownVersion(_, _) => (StatusCode::NOT_FOUND, "version_not_found"),
+            DeployError::Storage(_) => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+        };
+        ApiError::new(status, code, err.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let retry_after = self.retry_after;
+
+        let mut response = match self.format {
+            ResponseFormat::Html => {
+                let template = ApiErrorTemplate {
+                    message: &self.message,
+                };
+                (self.status, HtmlTemplate(template)).into_response()
+            }
+            ResponseFormat::Json => {
+                let body = ErrorResponse {
+                    error: ErrorBody {
+                        code: self.code.to_string(),
+                        message: self.message,
+                    },
+                };
+                (self.status, Json(body)).into_response()
+            }
+        };
+
+        if let Some(seconds) = retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+
+        response
+    }
+}
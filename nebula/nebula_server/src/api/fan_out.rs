@@ -0,0 +1,279 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{extract::State, Extension, Json};
+use futures::{stream, StreamExt};
+use nebula_lib::{
+    models::{FunctionResult, ModuleType},
+    module_store::parse_reference,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, info_span, Instrument};
+
+use crate::{
+    api::{
+        call_function::{invoke_request, record_results, validate_request},
+        error::{ApiError, ErrorBody},
+    },
+    api_keys::{self, Caller},
+    models::{default_image, AppState, FunctionRequest},
+    utilities::negotiate::Payload,
+};
+
+/// Most invocations a single fan-out may run.
+pub const MAX_FAN_OUT: usize = 1000;
+
+/// Body of `POST /api/wasm/all`, every function is invoked once with every input.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FanOutRequest {
+    /// Function names, optionally with a version or alias after an `@`.
+    pub functions: Vec<String>,
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    /// Integer inputs added after `inputs`.
+    #[serde(default)]
+    pub range: Option<InputRange>,
+    #[serde(default = "default_module_type")]
+    pub module_type: ModuleType,
+    #[serde(default = "default_image")]
+    pub base_image: String,
+}
+
+/// Integers from `start` to `end`, both included.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputRange {
+    pub start: i64,
+    pub end: i64,
+    #[serde(default = "default_step")]
+    pub step: u64,
+}
+
+fn default_module_type() -> ModuleType {
+    ModuleType::Wasm
+}
+
+fn default_step() -> u64 {
+    1
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FanOutResponse {
+    /// One run per function and input, in the order of the functions and then of the inputs.
+    pub runs: Vec<FanOutRun>,
+    /// Microseconds from the start of the first invocation to the end of the last one.
+    pub wall_time: u128,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FanOutRun {
+    pub function_name: String,
+    pub input: String,
+    /// `None` if the function couldn't be invoked.
+    pub result: Option<FunctionResult>,
+    /// Why the function couldn't be invoked or failed.
+    pub error: Option<ErrorBody>,
+}
+
+impl FanOutRequest {
+    /// The inputs, with the range expanded. Fails with `400 invalid_request` if there are none,
+    /// or too many to run with every function.
+    fn inputs(&self) -> Result<Vec<String>, ApiError> {
+        let mut inputs = self.inputs.clone();
+
+        if let Some(range) = &self.range {
+            if range.step == 0 || range.end < range.start {
+                return Err(ApiError::invalid_request(
+                    "The range needs a start before its end and a positive step",
+                ));
+            }
+            // In `u128`, as the whole `i64` range holds one more integer than `u64::MAX`.
+            let count = u128::from(range.end.abs_diff(range.start) / range.step) + 1;
+            if count > MAX_FAN_OUT as u128 {
+                return Err(too_many(count));
+            }
+            inputs.extend(
+                (range.start..=range.end)
+                    .step_by(range.step as usize)
+                    .map(|input| input.to_string()),
+            );
+        }
+
+        if self.functions.is_empty() || inputs.is_empty() {
+            return Err(ApiError::invalid_request(
+                "Expected at least one function and one input",
+            ));
+        }
+        let count = self.functions.len() * inputs.len();
+        if count > MAX_FAN_OUT {
+            return Err(too_many(count as u128));
+        }
+
+        Ok(inputs)
+    }
+}
+
+/// Invokes every function with every input, up to `--max-concurrency` at once, and responds with
+/// all the runs once they're done. The fan-out takes one place in the wait queue, failing with
+/// `429 too_many_requests` if it's full, and the quota of an API key has to cover every invocation
+/// up front.
+/// An invocation that fails, e.g. because its input doesn't match the manifest, has its `error`
+/// set without failing the others.
+pub async fn fan_out(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+    Payload(request): Payload<FanOutRequest>,
+) -> Result<Json<FanOutResponse>, ApiError> {
+    let caller = caller.map(|Extension(caller)| caller);
+    let inputs = request.inputs()?;
+    let count = request.functions.len() * inputs.len();
+    // Held until every run is done, so fan-outs waiting for slots are bounded like invocations.
+    let _queued = state.limits.enter_queue()?;
+    api_keys::reserve(&state, caller.as_ref(), count as u32)?;
+
+    info!(
+        "fanning out {} functions over {} inputs",
+        request.functions.len(),
+        inputs.len()
+    );
+
+    let mut requests = Vec::with_capacity(count);
+    for function_name in &request.functions {
+        for input in &inputs {
+            requests.push(FunctionRequest {
+                function_name: function_name.clone(),
+                input: input.clone(),
+                module_type: request.module_type,
+                num_calls: 1,
+                base_image: request.base_image.clone(),
+                trigger: Default::default(),
+            });
+        }
+    }
+
+    let started = Instant::now();
+    let runs: Vec<FanOutRun> = stream::iter(requests)
+        .map(|request| fan_out_run(&state, caller.as_ref(), request))
+        .buffered(state.limits.max_concurrency())
+        .collect()
+        .await;
+    let wall_time = started.elapsed().as_micros();
+
+    let succeeded = runs.iter().filter(|run| run.error.is_none()).count();
+    Ok(Json(FanOutResponse {
+        failed: runs.len() - succeeded,
+        runs,
+        wall_time,
+        succeeded,
+    }))
+}
+
+/// Runs one invocation of a fan-out, which holds a place in the wait queue for all of them.
+async fn fan_out_run(
+    state: &AppState,
+    caller: Option<&Caller>,
+    request: FunctionRequest,
+) -> FanOutRun {
+    let function_name = request.function_name.clone();
+    let input = request.input.clone();
+    let (func_name, _) = parse_reference(&function_name);
+
+    let outcome = match validate_request(state, &request) {
+        Ok(limits) => match state
+            .limits
+            .acquire_queued(request.module_type, func_name)
+            .instrument(info_span!("queue"))
+            .await
+        {
+            Ok(permit) => invoke_request(state, request, limits, permit).await,
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err),
+    };
+
+    if let Ok(ref results) = outcome {
+        if let Some(caller) = caller {
+            state.api_keys.record_compute(&caller.name, results);
+        }
+        record_results(state, results).await;
+    }
+
+    let (result, error) = match outcome {
+        Ok(mut results) => {
+            let result = results.pop();
+            let error = result.as_ref().and_then(|result| result.error.clone());
+            (
+                result,
+                error.map(|message| ErrorBody {
+                    code: "function_failed".to_string(),
+                    message,
+                }),
+            )
+        }
+        Err(err) => (
+            None,
+            Some(ErrorBody {
+                code: err.code.to_string(),
+                message: err.message,
+            }),
+        ),
+    };
+
+    FanOutRun {
+        function_name,
+        input,
+        result,
+        error,
+    }
+}
+
+fn too_many(count: u128) -> ApiError {
+    ApiError::invalid_request(format!(
+        "A fan-out runs at most {} invocations, got {}",
+        MAX_FAN_OUT, count
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_input_ranges() {
+        let request: FanOutRequest = serde_json::from_value(serde_json::json!({
+            "functions": ["factorial", "fibonacci"],
+            "inputs": ["0"],
+            "range": { "start": 5, "end": 20, "step": 5 },
+        }))
+        .unwrap();
+        assert_eq!(request.module_type, ModuleType::Wasm);
+        assert_eq!(request.inputs().unwrap(), ["0", "5", "10", "15", "20"]);
+
+        let too_many = FanOutRequest {
+            range: Some(InputRange {
+                start: 1,
+                end: MAX_FAN_OUT as i64,
+                step: 1,
+            }),
+            ..request.clone()
+        };
+        assert_eq!(too_many.inputs().unwrap_err().code, "invalid_request");
+
+        let whole_range = FanOutRequest {
+            range: Some(InputRange {
+                start: i64::MIN,
+                end: i64::MAX,
+                step: 1,
+            }),
+            ..request.clone()
+        };
+        assert_eq!(whole_range.inputs().unwrap_err().code, "invalid_request");
+
+        let empty = FanOutRequest {
+            inputs: Vec::new(),
+            range: None,
+            ..request
+        };
+        assert!(empty.inputs().is_err());
+    }
+}
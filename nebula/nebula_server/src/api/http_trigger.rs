@@ -0,0 +1,199 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, State},
+    http::{
+        header::{
+            AUTHORIZATION, CONNECTION, CONTENT_LENGTH, COOKIE, PROXY_AUTHORIZATION,
+            TRANSFER_ENCODING,
+        },
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri,
+    },
+    response::Response,
+    Extension,
+};
+use nebula_lib::{
+    http::{HttpRequestEnvelope, HttpResponseEnvelope},
+    models::{ModuleType, Trigger},
+};
+use serde::Deserialize;
+
+use crate::{
+    api::{call_function::run_request, error::ApiError},
+    api_keys::{key_in_authorization, Caller, API_KEY_HEADER},
+    models::{AppState, FunctionRequest},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct TriggerPath {
+    /// Function name, optionally with a version or alias after an `@`.
+    name: String,
+    #[serde(default)]
+    path: String,
+}
+
+/// Invokes the Wasm function `name` with the request as a
+/// [`HttpRequestEnvelope`](nebula_lib::http::HttpRequestEnvelope) and responds with the
+/// [`HttpResponseEnvelope`](nebula_lib::http::HttpResponseEnvelope) it returns.
+///
+/// Failures before the function runs are reported like `/api/wasm` does, and:
+/// - `502 function_failed` if the function failed
+/// - `502 invalid_response` if its response has an invalid status, header or body
+pub async fn invoke_http(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+    Path(TriggerPath { name, path }): Path<TriggerPath>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let caller = caller.map(|Extension(caller)| caller);
+    let envelope = HttpRequestEnvelope {
+        method: method.to_string(),
+        path: format!("/{}", path),
+        query: uri.query().map(str::to_string),
+        headers: envelope_headers(&headers, caller.is_some()),
+        body: String::new(),
+        body_encoding: Default::default(),
+    }
+    .with_body(body.to_vec());
+    let input =
+        serde_json::to_string(&envelope).map_err(|err| ApiError::internal(err.to_string()))?;
+
+    let request = FunctionRequest {
+        function_name: name,
+        input,
+        module_type: ModuleType::Wasm,
+        num_calls: 1,
+        base_image: String::new(),
+        trigger: Trigger::Http,
+    };
+    let result = run_request(&state, caller.as_ref(), request)
+        .await?
+        .pop()
+        .ok_or_else(|| ApiError::internal("The function returned no result"))?;
+
+    if let Some(error) = result.error {
+        return Err(ApiError::new(
+            StatusCode::BAD_GATEWAY,
+            "function_failed",
+            error,
+        ));
+    }
+
+    into_response(HttpResponseEnvelope::from_result(&result.result))
+}
+
+/// The request headers, without credentials. The envelope ends up in the history as the input,
+/// so the API key, `Authorization` if it carried the key of an `authenticated` request, cookies
+/// and proxy credentials are left out.
+fn envelope_headers(headers: &HeaderMap, authenticated: bool) -> BTreeMap<String, String> {
+    let mut envelope = BTreeMap::<String, String>::new();
+    let strip_authorization = authenticated && key_in_authorization(headers);
+
+    for (name, value) in headers {
+        if [
+            API_KEY_HEADER,
+            COOKIE.as_str(),
+            PROXY_AUTHORIZATION.as_str(),
+        ]
+        .contains(&name.as_str())
+            || (strip_authorization && name == AUTHORIZATION)
+        {
+            continue;
+        }
+        let value = String::from_utf8_lossy(value.as_bytes());
+        envelope
+            .entry(name.to_string())
+            .and_modify(|joined| {
+                joined.push_str(", ");
+                joined.push_str(&value);
+            })
+            .or_insert_with(|| value.into_owned());
+    }
+
+    envelope
+}
+
+fn into_response(envelope: HttpResponseEnvelope) -> Result<Response, ApiError> {
+    let status = StatusCode::from_u16(envelope.status)
+        .map_err(|_| invalid_response(format!("Invalid status {}", envelope.status)))?;
+    let body = envelope
+        .body_bytes()
+        .map_err(|err| invalid_response(format!("Invalid base64 body: {}", err)))?;
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+
+    for (name, value) in &envelope.headers {
+        let name = HeaderName::try_from(name.as_str())
+            .map_err(|_| invalid_response(format!("Invalid header name {:?}", name)))?;
+        // Framing is up to the server.
+        if [CONTENT_LENGTH, TRANSFER_ENCODING, CONNECTION].contains(&name) {
+            continue;
+        }
+        let value = HeaderValue::try_from(value.as_str())
+            .map_err(|_| invalid_response(format!("Invalid value for header {}", name)))?;
+        response.headers_mut().append(name, value);
+    }
+
+    Ok(response)
+}
+
+fn invalid_response(message: String) -> ApiError {
+    ApiError::new(StatusCode::BAD_GATEWAY, "invalid_response", message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_envelopes_onto_http() {
+        let mut headers = HeaderMap::new();
+        headers.append("accept", HeaderValue::from_static("text/html"));
+        headers.append("accept", HeaderValue::from_static("*/*"));
+        headers.append(API_KEY_HEADER, HeaderValue::from_static("secret"));
+        headers.append(COOKIE, HeaderValue::from_static("session=secret"));
+
+        let envelope = envelope_headers(&headers, true);
+        assert_eq!(envelope["accept"], "text/html, */*");
+        assert!(!envelope.contains_key(API_KEY_HEADER));
+        assert!(!envelope.contains_key("cookie"));
+
+        let response = into_response(HttpResponseEnvelope::from_result(
+            r#"{"status":201,"headers":{"location":"/users/42","content-length":"1"},"body":"{}"}"#,
+        ))
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["location"], "/users/42");
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+
+        let invalid = into_response(HttpResponseEnvelope::from_result(r#"{"status":42}"#));
+        assert_eq!(invalid.unwrap_err().code, "invalid_response");
+    }
+
+    #[test]
+    fn strips_keys_sent_as_basic_auth() {
+        // `admin:secret`
+        let basic = HeaderValue::from_static("Basic YWRtaW46c2VjcmV0");
+        let mut headers = HeaderMap::new();
+        headers.append(AUTHORIZATION, basic.clone());
+        headers.append(PROXY_AUTHORIZATION, basic);
+
+        let envelope = envelope_headers(&headers, true);
+        assert!(envelope.is_empty());
+
+        // Without keys the function gets its own credentials.
+        let envelope = envelope_headers(&headers, false);
+        assert_eq!(envelope["authorization"], "Basic YWRtaW46c2VjcmV0");
+        assert!(!envelope.contains_key("proxy-authorization"));
+
+        // With the key in `X-API-Key`, `Authorization` is meant for the function.
+        headers.append(API_KEY_HEADER, HeaderValue::from_static("secret"));
+        let envelope = envelope_headers(&headers, true);
+        assert!(envelope.contains_key("authorization"));
+    }
+}
@@ -0,0 +1,140 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{header::LOCATION, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+
+use crate::{
+    api::{
+        call_function::{validate_request, InvocationResponse},
+        error::ApiError,
+    },
+    api_keys::{self, Caller},
+    jobs::{Job, JobStatus},
+    models::{AppState, FunctionRequest},
+    utilities::negotiate::Payload,
+};
+
+/// Queues a [`FunctionRequest`] and responds with `202 Accepted` and the queued [`Job`], its
+/// status is at the `Location` header. Fails with `422 invalid_input` if the input doesn't match
+/// the function's manifest, with `503 queue_full` if too many jobs are queued, and with
+/// `429 quota_exceeded` if the calls exceed the quota of the API key.
+pub async fn submit_job(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+    Payload(request): Payload<FunctionRequest>,
+) -> Result<Response, ApiError> {
+    let caller = caller.map(|Extension(caller)| caller);
+    validate_request(&state, &request)?;
+    let invocations = request.num_calls as u32;
+    api_keys::reserve(&state, caller.as_ref(), invocations)?;
+
+    let api_key = caller.as_ref().map(|caller| caller.name.clone());
+    let job = match state.jobs.submit(request, api_key).await {
+        Ok(job) => job,
+        Err(err) => {
+            api_keys::refund(&state, caller.as_ref(), invocations);
+            return Err(err);
+        }
+    };
+
+    Ok((
+        StatusCode::ACCEPTED,
+        [(LOCATION, format!("/api/jobs/{}", job.id))],
+        Json(job),
+    )
+        .into_response())
+}
+
+/// The caller's jobs newest first, without results. Admin keys see every job.
+pub async fn list_jobs(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+) -> Json<Vec<Job>> {
+    let caller = caller.map(|Extension(caller)| caller);
+    let mut jobs = state.jobs.list().await;
+    jobs.retain(|job| api_keys::may_access(caller.as_ref(), job.api_key.as_deref()));
+
+    Json(jobs)
+}
+
+pub async fn get_job(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+    Path(id): Path<String>,
+) -> Result<Json<Job>, ApiError> {
+    let job = find_job(&state, caller, &id).await?;
+
+    with_results(&state, job).await.map(Json)
+}
+
+/// The results of a finished job, as returned by a synchronous invocation. Fails with
+/// `409 job_not_finished` while the job is queued or running, and with `409 job_failed` if it
+/// couldn't be run.
+pub async fn get_job_results(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+    Path(id): Path<String>,
+) -> Result<Json<InvocationResponse>, ApiError> {
+    let job = with_results(&state, find_job(&state, caller, &id).await?).await?;
+
+    match job.status {
+        JobStatus::Succeeded => Ok(Json(InvocationResponse {
+            results: job.results,
+        })),
+        JobStatus::Failed => Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "job_failed",
+            job.error.map_or_else(
+                || "The job failed".to_string(),
+                |error| format!("{}: {}", error.code, error.message),
+            ),
+        )),
+        JobStatus::Queued | JobStatus::Running => Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "job_not_finished",
+            format!("Job {} hasn't finished yet", job.id),
+        )),
+    }
+}
+
+/// Jobs of other keys are not found, unless the caller's key is an admin key.
+async fn find_job(
+    state: &AppState,
+    caller: Option<Extension<Caller>>,
+    id: &str,
+) -> Result<Job, ApiError> {
+    let caller = caller.map(|Extension(caller)| caller);
+    let job = state
+        .jobs
+        .get(id)
+        .await
+        .filter(|job| api_keys::may_access(caller.as_ref(), job.api_key.as_deref()));
+
+    job.ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "job_not_found",
+            format!("Unknown job {}", id),
+        )
+    })
+}
+
+/// Reads the results of `job` from the history rows it points at.
+async fn with_results(state: &AppState, mut job: Job) -> Result<Job, ApiError> {
+    if job.result_ids.is_empty() {
+        return Ok(job);
+    }
+
+    let history = state.history.clone();
+    let ids = job.result_ids.clone();
+    job.results = tokio::task::spawn_blocking(move || history.get(&ids))
+        .await
+        .map_err(|err| ApiError::internal(err.to_string()))?
+        .map_err(|err| ApiError::internal(format!("{:#}", err)))?;
+
+    Ok(job)
+}
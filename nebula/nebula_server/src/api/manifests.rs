@@ -0,0 +1,25 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use nebula_lib::manifest::FunctionManifest;
+
+use crate::{api::error::ApiError, models::AppState};
+
+/// The manifest of a function, shared by its Wasm and Docker versions. Fails with
+/// `404 manifest_not_found` if the function has none.
+pub async fn get_manifest(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<FunctionManifest>, ApiError> {
+    state.modules.manifest(&name)?.map(Json).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "manifest_not_found",
+            format!("{} has no manifest", name),
+        )
+    })
+}
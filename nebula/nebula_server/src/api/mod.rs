@@ -1 +1,14 @@
 pub mod call_function;
+pub mod compare;
+pub mod deploy;
+pub mod docker_images;
+pub mod error;
+pub mod fan_out;
+pub mod http_trigger;
+pub mod jobs;
+pub mod manifests;
+pub mod pipelines;
+pub mod results;
+pub mod schedules;
+pub mod usage;
+pub mod versions;
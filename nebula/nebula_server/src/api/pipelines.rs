@@ -0,0 +1,156 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use nebula_lib::{
+    deploy::validate_name, manifest::InputSchema, module_store::parse_reference,
+    runtime::Invocation,
+};
+
+use crate::{
+    api::error::ApiError,
+    api_keys::Caller,
+    models::AppState,
+    pipelines::{
+        run_pipeline, NewPipeline, Pipeline, PipelineInput, PipelineRun, PipelineStage, MAX_STAGES,
+    },
+    utilities::negotiate::Payload,
+};
+
+/// Every pipeline, by name.
+pub async fn list_pipelines(State(state): State<Arc<AppState>>) -> Json<Vec<Pipeline>> {
+    Json(state.pipelines.list().await)
+}
+
+pub async fn get_pipeline(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<Pipeline>, ApiError> {
+    state
+        .pipelines
+        .get(&name)
+        .await
+        .map(Json)
+        .ok_or_else(|| pipeline_not_found(&name))
+}
+
+/// Adds the pipeline `name` or replaces its stages. Fails with `400 invalid_request` for an
+/// invalid name or number of stages, with `403 forbidden` if another API key created the
+/// pipeline, with `404 function_not_found` if a stage's function isn't deployed and with
+/// `422 incompatible_stages` if a stage's manifest declares an output the next stage's manifest
+/// doesn't take as input.
+pub async fn put_pipeline(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+    Path(name): Path<String>,
+    Payload(new): Payload<NewPipeline>,
+) -> Result<Json<Pipeline>, ApiError> {
+    let caller = caller.map(|Extension(caller)| caller);
+    validate_name("pipeline name", &name)?;
+    validate_stages(&state, &new.stages).await?;
+
+    state
+        .pipelines
+        .put(&name, new.stages, caller.as_ref())
+        .await
+        .map(Json)
+}
+
+/// Removes the pipeline `name`. Fails with `403 forbidden` if another API key created it.
+pub async fn delete_pipeline(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let caller = caller.map(|Extension(caller)| caller);
+
+    state
+        .pipelines
+        .remove(&name, caller.as_ref())
+        .await?
+        .map(|_| StatusCode::NO_CONTENT)
+        .ok_or_else(|| pipeline_not_found(&name))
+}
+
+/// Runs the pipeline `name` and responds with the [`PipelineRun`]. Fails like `/api/wasm` if the
+/// first stage can't be started, a later stage failing is still a `200 OK` with `failed_stage`
+/// and `error` set.
+pub async fn invoke_pipeline(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+    Path(name): Path<String>,
+    Payload(PipelineInput { input }): Payload<PipelineInput>,
+) -> Result<Json<PipelineRun>, ApiError> {
+    let caller = caller.map(|Extension(caller)| caller);
+    let pipeline = state
+        .pipelines
+        .get(&name)
+        .await
+        .ok_or_else(|| pipeline_not_found(&name))?;
+
+    run_pipeline(&state, caller.as_ref(), &pipeline, input)
+        .await
+        .map(Json)
+}
+
+/// Checks that every stage's function is deployed, and that the output declared in each stage's
+/// manifest fits the input of the next one.
+async fn validate_stages(state: &AppState, stages: &[PipelineStage]) -> Result<(), ApiError> {
+    if stages.is_empty() || stages.len() > MAX_STAGES {
+        return Err(ApiError::invalid_request(format!(
+            "A pipeline has 1 to {} stages, got {}",
+            MAX_STAGES,
+            stages.len()
+        )));
+    }
+
+    let mut previous: Option<(&str, InputSchema)> = None;
+    for stage in stages {
+        let (func_name, _) = parse_reference(&stage.function_name);
+        let manifest = state.modules.manifest(func_name)?;
+
+        let invocation = Invocation {
+            func_name: stage.function_name.clone(),
+            base_image: stage.base_image.clone(),
+            limits: manifest
+                .as_ref()
+                .map(|manifest| manifest.limits())
+                .unwrap_or_default(),
+        };
+        let module_type = stage.module_type;
+        let runtimes = state.runtimes.clone();
+        tokio::task::spawn_blocking(move || runtimes.resolve(module_type, &invocation))
+            .await
+            .map_err(|err| ApiError::internal(err.to_string()))??;
+
+        if let (Some((previous_name, output)), Some(manifest)) = (&previous, &manifest) {
+            if !manifest.input.accepts(output) {
+                return Err(ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "incompatible_stages",
+                    format!(
+                        "The output of {} doesn't fit the input of {}",
+                        previous_name, stage.function_name
+                    ),
+                ));
+            }
+        }
+
+        previous = manifest
+            .and_then(|manifest| manifest.output)
+            .map(|output| (stage.function_name.as_str(), output));
+    }
+
+    Ok(())
+}
+
+fn pipeline_not_found(name: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "pipeline_not_found",
+        format!("Unknown pipeline {}", name),
+    )
+}
@@ -0,0 +1,264 @@
+use std::{fmt::Display, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use nebula_lib::models::{FunctionResult, ModuleType, Trigger};
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::{
+    api::error::ApiError,
+    history::{Page, ResultFilter, RuntimeSummary, MAX_TIMESTAMP},
+    live::StreamQuery,
+    models::{AppState, FCList},
+    utilities::{html_template::HtmlTemplate, negotiate::ResponseFormat},
+};
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 500;
+
+/// Query parameters of `GET /api/results`. Empty parameters are ignored, so the filter form in
+/// the UI can always send every field.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResultsQuery {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_name: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module_type: Option<ModuleType>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_image: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+    /// Milliseconds since epoch, inclusive.
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    /// Milliseconds since epoch, exclusive.
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<u64>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub success: Option<bool>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trigger: Option<Trigger>,
+    /// `next_cursor` of the previous page.
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<i64>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+impl ResultsQuery {
+    /// Fails with `400 invalid_request` if `since` or `until` is past [`MAX_TIMESTAMP`].
+    pub fn filter(&self) -> Result<ResultFilter, ApiError> {
+        if [self.since, self.until]
+            .iter()
+            .flatten()
+            .any(|millis| *millis > MAX_TIMESTAMP)
+        {
+            return Err(ApiError::invalid_request(format!(
+                "`since` and `until` are milliseconds since epoch, at most {}",
+                MAX_TIMESTAMP
+            )));
+        }
+
+        Ok(ResultFilter {
+            func_name: self.function_name.clone(),
+            module_type: self.module_type,
+            base_image: self.base_image.clone(),
+            input: self.input.clone(),
+            since: self.since,
+            until: self.until,
+            success: self.success,
+            trigger: self.trigger,
+        })
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// URL of the page after this one, with the same filters.
+    fn next_url(&self, cursor: i64) -> String {
+        let next = ResultsQuery {
+            cursor: Some(cursor),
+            ..self.clone()
+        };
+
+        format!(
+            "/api/results?{}",
+            serde_urlencoded::to_string(next).unwrap_or_default()
+        )
+    }
+
+    /// URL of the stream of the results and averages matching the same filters, as fragments.
+    fn stream_url(&self) -> String {
+        StreamQuery {
+            html: Some(true),
+            filters: ResultsQuery {
+                cursor: None,
+                limit: None,
+                ..self.clone()
+            },
+        }
+        .url()
+    }
+}
+
+pub(crate) fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    match Option::<String>::deserialize(deserializer)?
+        .as_deref()
+        .map(str::trim)
+    {
+        None | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(de::Error::custom),
+    }
+}
+
+/// Body of `GET /api/results` when JSON is negotiated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResultsPage {
+    /// Newest first.
+    pub results: Vec<FunctionResult>,
+    /// Pass as `cursor` to get the next page, `null` on the last page.
+    pub next_cursor: Option<i64>,
+}
+
+/// Lists the invocation history newest first, narrowed down by the query parameters.
+///
+/// htmx and browser requests get the results fragment, with the averages of the matching
+/// invocations on the first page and an element loading the next page once it's scrolled into
+/// view. Everything else gets a [`ResultsPage`]. Fails with `400 invalid_request` if a parameter
+/// can't be parsed.
+pub async fn get_results(
+    State(state): State<Arc<AppState>>,
+    format: ResponseFormat,
+    query: Result<Query<ResultsQuery>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let Query(query) = query.map_err(|rejection| {
+        ApiError::invalid_request(rejection.body_text()).with_format(format)
+    })?;
+
+    let response = match format {
+        ResponseFormat::Html => HtmlTemplate(get_fc_list(&state, query).await?).into_response(),
+        ResponseFormat::Json => {
+            let (page, _) = load_page(&state, &query, false).await?;
+            Json(ResultsPage {
+                results: page.results,
+                next_cursor: page.next_cursor,
+            })
+            .into_response()
+        }
+    };
+
+    Ok(response)
+}
+
+/// The results fragment for a page of the history, including the averages of each runtime on
+/// the first page.
+pub async fn get_fc_list(state: &AppState, query: ResultsQuery) -> Result<FCList, ApiError> {
+    let first_page = query.cursor.is_none();
+    let (page, summaries) = load_page(state, &query, first_page)
+        .await
+        .map_err(|err| err.with_format(ResponseFormat::Html))?;
+    let (wasm, docker) = summaries.unwrap_or_default();
+
+    Ok(FCList {
+        function_results: page.results,
+        next_url: page.next_cursor.map(|cursor| query.next_url(cursor)),
+        stream_url: first_page.then(|| query.stream_url()),
+        total_wasm_invocations: wasm.invocations,
+        total_docker_invocations: docker.invocations,
+        avg_wasm_startup: wasm.avg_startup,
+        avg_wasm_runtime: wasm.avg_runtime,
+        avg_wasm_total_time: wasm.avg_total_time,
+        avg_docker_startup: docker.avg_startup,
+        avg_docker_runtime: docker.avg_runtime,
+        avg_docker_total_time: docker.avg_total_time,
+    })
+}
+
+/// A page of the history, with the Wasm and Docker averages of the whole selection if
+/// `with_summaries` is set.
+async fn load_page(
+    state: &AppState,
+    query: &ResultsQuery,
+    with_summaries: bool,
+) -> Result<(Page, Option<(RuntimeSummary, RuntimeSummary)>), ApiError> {
+    let history = state.history.clone();
+    let filter = query.filter()?;
+    let cursor = query.cursor;
+    let limit = query.limit();
+
+    tokio::task::spawn_blocking(move || {
+        let page = history.page(&filter, cursor, limit)?;
+        let summaries = if with_summaries {
+            Some((
+                history.summary(ModuleType::Wasm, &filter)?,
+                history.summary(ModuleType::Docker, &filter)?,
+            ))
+        } else {
+            None
+        };
+
+        Ok::<_, anyhow::Error>((page, summaries))
+    })
+    .await
+    .map_err(|err| ApiError::internal(err.to_string()))?
+    .map_err(|err| ApiError::internal(format!("{:#}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_filters_and_keeps_them_in_the_next_url() {
+        let Query(query) = Query::<ResultsQuery>::try_from_uri(
+            &"/api/results?function_name=factorial&module_type=Wasm&input=&success=false&limit=10"
+                .parse()
+                .unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(query.module_type, Some(ModuleType::Wasm));
+        assert_eq!(query.input, None);
+        assert_eq!(query.filter().unwrap().success, Some(false));
+        assert_eq!(
+            query.next_url(42),
+            "/api/results?function_name=factorial&module_type=Wasm&success=false&cursor=42&limit=10"
+        );
+        assert_eq!(
+            query.stream_url(),
+            "/api/results/stream?html=true&function_name=factorial&module_type=Wasm&success=false"
+        );
+
+        assert!(Query::<ResultsQuery>::try_from_uri(
+            &"/api/results?module_type=lambda".parse().unwrap()
+        )
+        .is_err());
+
+        let far_future = ResultsQuery {
+            until: Some(u64::MAX),
+            ..query
+        };
+        assert_eq!(far_future.filter().unwrap_err().code, "invalid_request");
+    }
+}
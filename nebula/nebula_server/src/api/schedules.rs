@@ -0,0 +1,157 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{header::LOCATION, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use nebula_lib::runtime::Invocation;
+
+use crate::{
+    api::{call_function::validate_request, error::ApiError},
+    api_keys::{self, Caller},
+    cron::CronExpr,
+    jobs::unix_millis,
+    models::AppState,
+    schedules::{NewSchedule, Schedule},
+    utilities::negotiate::Payload,
+};
+
+/// Adds a [`NewSchedule`] and responds with `201 Created` and the [`Schedule`], which is at the
+/// `Location` header. Fails with `400 invalid_request` if the cron expression is invalid or never
+/// matches, such as `0 0 31 2 *`, with `404 function_not_found` if the function isn't deployed and with `422 invalid_input` if the
+/// input doesn't match the function's manifest.
+pub async fn create_schedule(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+    Payload(new): Payload<NewSchedule>,
+) -> Result<Response, ApiError> {
+    let cron = new
+        .cron
+        .parse::<CronExpr>()
+        .map_err(|err| ApiError::invalid_request(err.0))?;
+    if cron.next_after(unix_millis() / 1000).is_none() {
+        return Err(ApiError::invalid_request(format!(
+            "The cron expression {:?} never matches",
+            new.cron
+        )));
+    }
+    let limits = validate_request(&state, &new.request)?;
+
+    let invocation = Invocation {
+        func_name: new.request.function_name.clone(),
+        base_image: new.request.base_image.clone(),
+        limits,
+    };
+    let module_type = new.request.module_type;
+    let runtimes = state.runtimes.clone();
+    tokio::task::spawn_blocking(move || runtimes.resolve(module_type, &invocation))
+        .await
+        .map_err(|err| ApiError::internal(err.to_string()))??;
+
+    let schedule = state
+        .schedules
+        .add(new, caller.map(|Extension(caller)| caller.name))
+        .await;
+
+    Ok((
+        StatusCode::CREATED,
+        [(LOCATION, format!("/api/schedules/{}", schedule.id))],
+        Json(schedule),
+    )
+        .into_response())
+}
+
+/// The caller's schedules oldest first. Admin keys see every schedule.
+pub async fn list_schedules(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+) -> Json<Vec<Schedule>> {
+    let caller = caller.map(|Extension(caller)| caller);
+    let mut schedules = state.schedules.list().await;
+    schedules.retain(|schedule| api_keys::may_access(caller.as_ref(), schedule.api_key.as_deref()));
+
+    Json(schedules)
+}
+
+pub async fn get_schedule(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+    Path(id): Path<String>,
+) -> Result<Json<Schedule>, ApiError> {
+    find_schedule(&state, caller, &id).await.map(Json)
+}
+
+pub async fn delete_schedule(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    find_schedule(&state, caller, &id).await?;
+
+    state
+        .schedules
+        .remove(&id)
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+        .ok_or_else(|| schedule_not_found(&id))
+}
+
+/// Stops running the schedule until it's resumed.
+pub async fn pause_schedule(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+    Path(id): Path<String>,
+) -> Result<Json<Schedule>, ApiError> {
+    set_paused(&state, caller, &id, true).await
+}
+
+/// Runs a paused schedule again, from its next matching time on.
+pub async fn resume_schedule(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+    Path(id): Path<String>,
+) -> Result<Json<Schedule>, ApiError> {
+    set_paused(&state, caller, &id, false).await
+}
+
+async fn set_paused(
+    state: &AppState,
+    caller: Option<Extension<Caller>>,
+    id: &str,
+    paused: bool,
+) -> Result<Json<Schedule>, ApiError> {
+    find_schedule(state, caller, id).await?;
+
+    state
+        .schedules
+        .set_paused(id, paused)
+        .await
+        .map(Json)
+        .ok_or_else(|| schedule_not_found(id))
+}
+
+/// Schedules of other keys are not found, unless the caller's key is an admin key.
+async fn find_schedule(
+    state: &AppState,
+    caller: Option<Extension<Caller>>,
+    id: &str,
+) -> Result<Schedule, ApiError> {
+    let caller = caller.map(|Extension(caller)| caller);
+
+    state
+        .schedules
+        .get(id)
+        .await
+        .filter(|schedule| api_keys::may_access(caller.as_ref(), schedule.api_key.as_deref()))
+        .ok_or_else(|| schedule_not_found(id))
+}
+
+fn schedule_not_found(id: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "schedule_not_found",
+        format!("Unknown schedule {}", id),
+    )
+}
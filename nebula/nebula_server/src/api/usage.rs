@@ -0,0 +1,26 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, Extension, Json};
+
+use crate::{
+    api::error::ApiError,
+    api_keys::{Caller, KeyReport},
+    models::AppState,
+};
+
+/// The quota and usage of every API key. Needs an admin key once keys are configured, and fails
+/// with `403 forbidden` for other keys.
+pub async fn get_usage(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Extension<Caller>>,
+) -> Result<Json<Vec<KeyReport>>, ApiError> {
+    if caller.is_some_and(|Extension(caller)| !caller.admin) {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "Usage is only visible to admin keys",
+        ));
+    }
+
+    Ok(Json(state.api_keys.report()))
+}
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    Json,
+};
+use nebula_lib::module_store::FunctionVersions;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    api::{deploy::authorize_deploy, error::ApiError},
+    models::AppState,
+    utilities::negotiate::Payload,
+};
+
+#[derive(Deserialize)]
+pub struct AliasRequest {
+    /// Version, version prefix or alias to point at.
+    pub version: String,
+}
+
+#[derive(Deserialize)]
+pub struct RollbackRequest {
+    /// Version to roll back to, the version deployed before `latest` if left out.
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+/// Every version of a Wasm function, with its aliases.
+pub async fn get_versions(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<FunctionVersions>, ApiError> {
+    let registry = state.modules.snapshot();
+
+    Ok(Json(registry.get(&name)?.clone()))
+}
+
+/// Points an alias at a version of a Wasm function.
+pub async fn set_alias(
+    State(state): State<Arc<AppState>>,
+    Path((name, alias)): Path<(String, String)>,
+    headers: HeaderMap,
+    Payload(request): Payload<AliasRequest>,
+) -> Result<Json<FunctionVersions>, ApiError> {
+    authorize_deploy(&state, &headers, None)?;
+
+    let modules = state.modules.clone();
+    let function = {
+        let (name, alias) = (name.clone(), alias.clone());
+        tokio::task::spawn_blocking(move || modules.set_alias(&name, &alias, &request.version))
+            .await
+            .map_err(|err| ApiError::internal(err.to_string()))??
+    };
+
+    info!(
+        "pointed {}@{} at {:?}",
+        name,
+        alias,
+        function.aliases.get(&alias)
+    );
+
+    Ok(Json(function))
+}
+
+/// Points `latest` back at an earlier version of a Wasm function.
+pub async fn rollback(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Payload(request): Payload<RollbackRequest>,
+) -> Result<Json<FunctionVersions>, ApiError> {
+    authorize_deploy(&state, &headers, None)?;
+
+    let modules = state.modules.clone();
+    let function = {
+        let name = name.clone();
+        tokio::task::spawn_blocking(move || modules.rollback(&name, request.version.as_deref()))
+            .await
+            .map_err(|err| ApiError::internal(err.to_string()))??
+    };
+
+    info!(
+        "rolled {} back to {:?}",
+        name,
+        function.latest().map(|module| module.short_version())
+    );
+
+    Ok(Json(function))
+}
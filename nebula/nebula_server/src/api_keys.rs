@@ -0,0 +1,759 @@
+//! API keys and per key quotas.
+//!
+//! Keys live in `keys.json` in the data directory, written by `nebula_server keys` or by hand,
+//! and are reloaded whenever the file changes. As long as the file holds no keys the API stays
+//! open. Once it does, every request to `/api/*` needs a key in the `X-API-Key` header, unless the
+//! server was started with an anonymous quota, which requests without a key then share. Requests
+//! without a valid key are challenged for basic auth, so browsers ask for the key as the password
+//! and send it along with the requests of the web UI.
+//!
+//! Each key may limit the invocations it starts per minute and the compute time its invocations
+//! use per day. Usage is tracked per key and written to `usage.json` every few seconds, and when
+//! the server stops.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use nebula_lib::{deploy::write_atomic, models::FunctionResult};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    api::{deploy::tokens_match, error::ApiError},
+    models::AppState,
+    utilities::negotiate::ResponseFormat,
+};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Basic auth challenge sent along with `401 unauthorized`.
+const API_REALM: &str = "Basic realm=\"Nebula API\"";
+
+/// Name usage of requests without a key is tracked under.
+pub const ANONYMOUS: &str = "anonymous";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// How often changed usage is written to `usage.json`.
+const USAGE_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    /// Invocations started per minute, counting every call of a request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invocations_per_minute: Option<u32>,
+    /// Seconds of function runtime per day, reset at midnight UTC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compute_seconds_per_day: Option<u64>,
+}
+
+impl Quota {
+    pub fn is_unlimited(&self) -> bool {
+        self.invocations_per_minute.is_none() && self.compute_seconds_per_day.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub name: String,
+    /// Hex encoded sha256 of the key, as written by `nebula_server keys add`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_hash: Option<String>,
+    /// The key itself, for keys added to the file by hand.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Admin keys can see the usage of every key.
+    #[serde(default)]
+    pub admin: bool,
+    #[serde(flatten)]
+    pub quota: Quota,
+}
+
+impl ApiKey {
+    fn matches(&self, presented: &str, presented_hash: &str) -> bool {
+        let by_hash = self
+            .key_hash
+            .as_deref()
+            .is_some_and(|hash| tokens_match(presented_hash, &hash.to_ascii_lowercase()));
+        let by_key = self
+            .key
+            .as_deref()
+            .is_some_and(|key| tokens_match(presented, key));
+
+        by_hash || by_key
+    }
+}
+
+/// The contents of the keys file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeysFile {
+    #[serde(default)]
+    pub keys: Vec<ApiKey>,
+}
+
+impl KeysFile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(KeysFile::default());
+        }
+
+        let contents = fs::read_to_string(path)?;
+        serde_json::from_str(&contents).with_context(|| format!("invalid keys file {:?}", path))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        write_atomic(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    /// Adds a key named `name`, returning the generated key. Only its hash is stored.
+    pub fn add(&mut self, name: &str, quota: Quota, admin: bool) -> anyhow::Result<String> {
+        if name == ANONYMOUS {
+            bail!("{:?} is reserved for requests without a key", ANONYMOUS);
+        }
+        if self.keys.iter().any(|key| key.name == name) {
+            bail!("a key named {:?} exists already", name);
+        }
+
+        let key = format!("neb_{}", Uuid::new_v4().simple());
+        self.keys.push(ApiKey {
+            name: name.to_string(),
+            key_hash: Some(hash_key(&key)),
+            key: None,
+            admin,
+            quota,
+        });
+
+        Ok(key)
+    }
+
+    /// Removes the key named `name`, returning whether there was one.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.keys.len();
+        self.keys.retain(|key| key.name != name);
+
+        self.keys.len() != before
+    }
+}
+
+/// Who made a request, added to the request extensions by [`require_api_key`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    pub name: String,
+    pub quota: Quota,
+    pub admin: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeyUsage {
+    pub total_invocations: u64,
+    /// Microseconds.
+    pub total_compute_time: u128,
+    /// Days since epoch the daily counters are for.
+    pub day: u64,
+    pub invocations_today: u64,
+    /// Microseconds.
+    pub compute_time_today: u128,
+    /// Milliseconds since epoch.
+    pub last_used: Option<u64>,
+    /// Invocations started within the last minute, oldest first.
+    #[serde(skip)]
+    recent: VecDeque<(Instant, u32)>,
+}
+
+impl KeyUsage {
+    fn roll_over(&mut self, today: u64) {
+        if self.day != today {
+            self.day = today;
+            self.invocations_today = 0;
+            self.compute_time_today = 0;
+        }
+    }
+
+    fn invocations_last_minute(&mut self, now: Instant) -> u32 {
+        while self
+            .recent
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) >= Duration::from_secs(60))
+        {
+            self.recent.pop_front();
+        }
+
+        self.recent.iter().map(|(_, count)| count).sum()
+    }
+}
+
+/// A key and its usage, as shown on the admin page.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyReport {
+    pub name: String,
+    pub admin: bool,
+    pub quota: Quota,
+    pub invocations_last_minute: u32,
+    pub usage: KeyUsage,
+}
+
+#[derive(Debug)]
+struct LoadedKeys {
+    modified: Option<SystemTime>,
+    keys: Vec<ApiKey>,
+}
+
+#[derive(Debug)]
+pub struct ApiKeys {
+    path: PathBuf,
+    usage_path: PathBuf,
+    /// Quota shared by requests without a key, which are rejected if it's `None`.
+    anonymous: Option<Quota>,
+    keys: Mutex<LoadedKeys>,
+    usage: Mutex<HashMap<String, KeyUsage>>,
+    /// Whether `usage` changed since it was last written.
+    usage_changed: AtomicBool,
+    /// Held while writing `usage.json`, so writes don't overtake each other.
+    saving_usage: Mutex<()>,
+}
+
+impl ApiKeys {
+    pub fn new(path: PathBuf, usage_path: PathBuf, anonymous: Option<Quota>) -> Self {
+        let usage = fs::read_to_string(&usage_path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+
+        // The keys are loaded on first use.
+        ApiKeys {
+            path,
+            usage_path,
+            anonymous,
+            keys: Mutex::new(LoadedKeys {
+                modified: None,
+                keys: Vec::new(),
+            }),
+            usage: Mutex::new(usage),
+            usage_changed: AtomicBool::new(false),
+            saving_usage: Mutex::new(()),
+        }
+    }
+
+    /// Whether requests need a key, i.e. any keys are configured.
+    pub fn enabled(&self) -> bool {
+        !self.reload().keys.is_empty()
+    }
+
+    /// Identifies the caller of a request from its `X-API-Key` header, or the password of its
+    /// basic auth credentials. `None` if no keys are configured.
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Option<Caller>, ApiError> {
+        let loaded = self.reload();
+        if loaded.keys.is_empty() {
+            return Ok(None);
+        }
+
+        let Some(presented) = presented_key(headers) else {
+            return self.anonymous_caller().map(Some);
+        };
+
+        let presented_hash = hash_key(&presented);
+        loaded
+            .keys
+            .iter()
+            .find(|key| key.matches(&presented, &presented_hash))
+            .map(|key| {
+                Some(Caller {
+                    name: key.name.clone(),
+                    quota: key.quota,
+                    admin: key.admin,
+                })
+            })
+            .ok_or_else(|| {
+                ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", "Invalid API key")
+            })
+    }
+
+    /// The caller for work started on behalf of the key named `name`, such as scheduled runs, or
+    /// of requests without a key if `name` is `None`. `None` if no keys are configured, and fails
+    /// with `401 unauthorized` if the key has been removed since.
+    pub fn caller_named(&self, name: Option<&str>) -> Result<Option<Caller>, ApiError> {
+        let loaded = self.reload();
+        if loaded.keys.is_empty() {
+            return Ok(None);
+        }
+
+        match name {
+            Some(ANONYMOUS) | None => self.anonymous_caller().map(Some),
+            Some(name) => loaded
+                .keys
+                .iter()
+                .find(|key| key.name == name)
+                .map(|key| {
+                    Some(Caller {
+                        name: key.name.clone(),
+                        quota: key.quota,
+                        admin: key.admin,
+                    })
+                })
+                .ok_or_else(|| {
+                    ApiError::new(
+                        StatusCode::UNAUTHORIZED,
+                        "unauthorized",
+                        format!("The API key {:?} has been removed", name),
+                    )
+                }),
+        }
+    }
+
+    fn anonymous_caller(&self) -> Result<Caller, ApiError> {
+        match self.anonymous {
+            Some(quota) => Ok(Caller {
+                name: ANONYMOUS.to_string(),
+                quota,
+                admin: false,
+            }),
+            None => Err(ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "Missing API key, send it as an X-API-Key header",
+            )),
+        }
+    }
+
+    /// Counts `invocations` against the caller's quota, failing with `429 quota_exceeded` if
+    /// they'd exceed it. The compute time of the invocations isn't known yet, so only a daily
+    /// quota that is already used up rejects them, and the last request of the day may overshoot
+    /// it by up to its own compute time.
+    pub fn reserve(&self, caller: &Caller, invocations: u32) -> Result<(), ApiError> {
+        let now = Instant::now();
+        let (today, seconds_left_today) = today();
+
+        let mut usage = self.lock_usage();
+        let key_usage = usage.entry(caller.name.clone()).or_default();
+        key_usage.roll_over(today);
+
+        if let Some(limit) = caller.quota.compute_seconds_per_day {
+            if key_usage.compute_time_today >= limit as u128 * 1_000_000 {
+                return Err(quota_exceeded(format!(
+                    "The daily quota of {} compute seconds is used up",
+                    limit
+                ))
+                .with_retry_after(seconds_left_today));
+            }
+        }
+
+        let recent = key_usage.invocations_last_minute(now);
+        if let Some(limit) = caller.quota.invocations_per_minute {
+            if recent + invocations > limit {
+                let err = quota_exceeded(format!(
+                    "The quota of {} invocations per minute is used up",
+                    limit
+                ));
+
+                // Waits for enough of the recent invocations to leave the window.
+                let mut freed = 0;
+                let retry_after = key_usage.recent.iter().find_map(|(at, count)| {
+                    freed += count;
+                    (recent - freed + invocations <= limit)
+                        .then(|| 60 - now.duration_since(*at).as_secs())
+                });
+
+                return Err(match retry_after {
+                    Some(seconds) => err.with_retry_after(seconds.max(1)),
+                    None => err,
+                });
+            }
+        }
+
+        key_usage.recent.push_back((now, invocations));
+        key_usage.total_invocations += invocations as u64;
+        key_usage.invocations_today += invocations as u64;
+        key_usage.last_used = Some(unix_millis());
+        self.usage_changed.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Gives back `invocations` that were [`reserve`](ApiKeys::reserve)d but never started, e.g.
+    /// because the job queue was full.
+    pub fn refund(&self, caller: &Caller, invocations: u32) {
+        if invocations == 0 {
+            return;
+        }
+        let mut usage = self.lock_usage();
+        let Some(key_usage) = usage.get_mut(&caller.name) else {
+            return;
+        };
+
+        let mut left = invocations;
+        while left > 0 {
+            let Some((_, count)) = key_usage.recent.back_mut() else {
+                break;
+            };
+            if *count > left {
+                *count -= left;
+                break;
+            }
+            left -= *count;
+            key_usage.recent.pop_back();
+        }
+        key_usage.total_invocations = key_usage
+            .total_invocations
+            .saturating_sub(invocations as u64);
+        key_usage.invocations_today = key_usage
+            .invocations_today
+            .saturating_sub(invocations as u64);
+        self.usage_changed.store(true, Ordering::SeqCst);
+    }
+
+    /// Adds the runtime of `results` to the compute time used by the key named `name`.
+    pub fn record_compute(&self, name: &str, results: &[FunctionResult]) {
+        let compute_time: u128 = results
+            .iter()
+            .filter_map(|result| result.metrics.as_ref())
+            .map(|metrics| metrics.total_runtime)
+            .sum();
+
+        let mut usage = self.lock_usage();
+        let key_usage = usage.entry(name.to_string()).or_default();
+        key_usage.roll_over(today().0);
+        key_usage.total_compute_time += compute_time;
+        key_usage.compute_time_today += compute_time;
+        self.usage_changed.store(true, Ordering::SeqCst);
+    }
+
+    /// Writes the usage to `usage.json` if it changed since it was last written.
+    pub fn save_usage(&self) {
+        let _saving = self
+            .saving_usage
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !self.usage_changed.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let serialized = serde_json::to_string(&*self.lock_usage());
+        if let Err(err) = serialized
+            .map_err(anyhow::Error::from)
+            .and_then(|serialized| write_atomic(&self.usage_path, serialized.as_bytes()))
+        {
+            warn!(
+                "failed to save key usage to {:?}: {:#}",
+                self.usage_path, err
+            );
+            self.usage_changed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Every configured key, and the anonymous quota if there is one, with its usage.
+    pub fn report(&self) -> Vec<KeyReport> {
+        let keys = self.reload().keys.clone();
+        let now = Instant::now();
+        let today = today().0;
+        let mut usage = self.lock_usage();
+
+        let anonymous = self
+            .anonymous
+            .map(|quota| (ANONYMOUS.to_string(), false, quota));
+        keys.into_iter()
+            .map(|key| (key.name, key.admin, key.quota))
+            .chain(anonymous)
+            .map(|(name, admin, quota)| {
+                let key_usage = usage.entry(name.clone()).or_default();
+                key_usage.roll_over(today);
+
+                KeyReport {
+                    invocations_last_minute: key_usage.invocations_last_minute(now),
+                    usage: key_usage.clone(),
+                    name,
+                    admin,
+                    quota,
+                }
+            })
+            .collect()
+    }
+
+    /// Reloads the keys if the file changed since they were last read.
+    fn reload(&self) -> MutexGuard<'_, LoadedKeys> {
+        let mut loaded = self
+            .keys
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified != loaded.modified {
+            match KeysFile::load(&self.path) {
+                Ok(file) => loaded.keys = file.keys,
+                // Keeps the keys that were loaded, rather than opening up the API.
+                Err(err) => warn!("failed to load API keys: {:#}", err),
+            }
+            loaded.modified = modified;
+        }
+
+        loaded
+    }
+
+    fn lock_usage(&self) -> MutexGuard<'_, HashMap<String, KeyUsage>> {
+        self.usage
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Rejects requests without a valid key once keys are configured, and adds the [`Caller`] to the
+/// request extensions.
+pub async fn require_api_key(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let caller = match state.api_keys.authenticate(request.headers()) {
+        Ok(caller) => caller,
+        Err(err) => {
+            let format = ResponseFormat::from_headers(request.headers());
+            let mut response = err.with_format(format).into_response();
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static(API_REALM));
+            return response;
+        }
+    };
+
+    if let Some(caller) = caller {
+        request.extensions_mut().insert(caller);
+    }
+
+    next.run(request).await
+}
+
+/// Starts the task writing the usage of the keys every [`USAGE_SAVE_INTERVAL`].
+pub fn spawn_usage_writer(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(USAGE_SAVE_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let state = state.clone();
+            if let Err(err) = tokio::task::spawn_blocking(move || state.api_keys.save_usage()).await
+            {
+                warn!("failed to save key usage: {}", err);
+            }
+        }
+    });
+}
+
+/// Counts `invocations` against the quota of `caller`, if the API requires keys.
+pub fn reserve(
+    state: &AppState,
+    caller: Option<&Caller>,
+    invocations: u32,
+) -> Result<(), ApiError> {
+    match caller {
+        Some(caller) => state.api_keys.reserve(caller, invocations),
+        None => Ok(()),
+    }
+}
+
+/// Gives back invocations [`reserve`]d for `caller` that won't run.
+pub fn refund(state: &AppState, caller: Option<&Caller>, invocations: u32) {
+    if let Some(caller) = caller {
+        state.api_keys.refund(caller, invocations);
+    }
+}
+
+/// Whether `caller` may see and change what was created with the key named `owner`: admin keys
+/// and the key itself may, and anyone while the API doesn't require keys.
+pub fn may_access(caller: Option<&Caller>, owner: Option<&str>) -> bool {
+    match caller {
+        Some(caller) => caller.admin || owner == Some(caller.name.as_str()),
+        None => true,
+    }
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.trim().as_bytes()))
+}
+
+fn presented_key(headers: &HeaderMap) -> Option<String> {
+    let header = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string());
+
+    header.or_else(|| basic_password(headers))
+}
+
+/// Whether the key was presented as the password of `Authorization: Basic`, rather than in
+/// `X-API-Key`.
+pub fn key_in_authorization(headers: &HeaderMap) -> bool {
+    !headers.contains_key(API_KEY_HEADER) && basic_password(headers).is_some()
+}
+
+fn basic_password(headers: &HeaderMap) -> Option<String> {
+    let credentials = headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+    let (_, password) = decoded.split_once(':')?;
+
+    Some(password.to_string())
+}
+
+fn quota_exceeded(message: String) -> ApiError {
+    ApiError::new(StatusCode::TOO_MANY_REQUESTS, "quota_exceeded", message)
+}
+
+/// Days since epoch and the seconds left until the next one.
+fn today() -> (u64, u64) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs());
+
+    (
+        now / SECONDS_PER_DAY,
+        SECONDS_PER_DAY - now % SECONDS_PER_DAY,
+    )
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn api_keys(name: &str, anonymous: Option<Quota>) -> (ApiKeys, String) {
+        let dir = std::env::temp_dir().join(format!("nebula-keys-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut file = KeysFile::default();
+        let key = file
+            .add(
+                "benchmarks",
+                Quota {
+                    invocations_per_minute: Some(3),
+                    compute_seconds_per_day: Some(1),
+                },
+                false,
+            )
+            .unwrap();
+        file.save(&dir.join("keys.json")).unwrap();
+
+        (
+            ApiKeys::new(dir.join("keys.json"), dir.join("usage.json"), anonymous),
+            key,
+        )
+    }
+
+    fn with_key(key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(API_KEY_HEADER, HeaderValue::from_str(key).unwrap());
+        headers
+    }
+
+    #[test]
+    fn authenticates_with_keys_from_the_file() {
+        let (keys, key) = api_keys("authenticate", None);
+
+        let caller = keys.authenticate(&with_key(&key)).unwrap().unwrap();
+        assert_eq!(caller.name, "benchmarks");
+
+        let basic = STANDARD.encode(format!("admin:{}", key));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Basic {}", basic)).unwrap(),
+        );
+        assert!(keys.authenticate(&headers).unwrap().is_some());
+
+        assert_eq!(
+            keys.authenticate(&with_key("neb_wrong"))
+                .unwrap_err()
+                .status,
+            StatusCode::UNAUTHORIZED
+        );
+
+        assert_eq!(keys.caller_named(Some("benchmarks")).unwrap(), Some(caller));
+        assert!(keys.caller_named(Some("removed")).is_err());
+        assert!(keys.caller_named(None).is_err());
+        assert!(keys.authenticate(&HeaderMap::new()).is_err());
+    }
+
+    #[test]
+    fn requests_without_a_key_share_the_anonymous_quota() {
+        let anonymous = Quota {
+            invocations_per_minute: Some(10),
+            compute_seconds_per_day: None,
+        };
+        let (keys, _) = api_keys("anonymous", Some(anonymous));
+
+        let caller = keys.authenticate(&HeaderMap::new()).unwrap().unwrap();
+        assert_eq!(caller.name, ANONYMOUS);
+        assert_eq!(caller.quota, anonymous);
+    }
+
+    #[test]
+    fn enforces_quotas() {
+        let (keys, key) = api_keys("quotas", None);
+        let caller = keys.authenticate(&with_key(&key)).unwrap().unwrap();
+
+        keys.reserve(&caller, 2).unwrap();
+        let err = keys.reserve(&caller, 2).unwrap_err();
+        assert_eq!(err.code, "quota_exceeded");
+        assert_eq!(err.retry_after, Some(60));
+        keys.reserve(&caller, 1).unwrap();
+        keys.refund(&caller, 1);
+        keys.reserve(&caller, 1).unwrap();
+
+        let caller = Caller {
+            quota: Quota {
+                invocations_per_minute: None,
+                ..caller.quota
+            },
+            ..caller
+        };
+        keys.reserve(&caller, 1).unwrap();
+        keys.record_compute(
+            &caller.name,
+            &[
+                FunctionResult::test_fixture(0, 600_000),
+                FunctionResult::test_fixture(0, 600_000),
+            ],
+        );
+        assert!(keys.reserve(&caller, 1).is_err());
+
+        let report = keys.report();
+        assert_eq!(report[0].invocations_last_minute, 4);
+        assert_eq!(report[0].usage.total_invocations, 4);
+        assert_eq!(report[0].usage.compute_time_today, 1_200_000);
+
+        keys.save_usage();
+        let reloaded = ApiKeys::new(keys.path.clone(), keys.usage_path.clone(), None);
+        assert_eq!(reloaded.report()[0].usage.total_invocations, 4);
+    }
+}
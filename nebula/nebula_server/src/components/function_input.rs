@@ -0,0 +1,52 @@
+use nebula_lib::manifest::{FunctionManifest, InputSchema};
+
+/// The input field of a function on the Wasm and Docker pages, derived from its manifest.
+#[derive(Debug, Clone, Default)]
+pub struct InputWidget {
+    pub name: String,
+    /// `type` of the input element, a textarea is used for JSON.
+    pub input_type: &'static str,
+    pub multiline: bool,
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    pub max_length: Option<usize>,
+    pub placeholder: String,
+    pub description: String,
+    pub examples: Vec<String>,
+}
+
+impl InputWidget {
+    pub fn new(name: &str, manifest: Option<&FunctionManifest>) -> Self {
+        let manifest = manifest.cloned().unwrap_or_default();
+        let mut widget = InputWidget {
+            name: name.to_string(),
+            input_type: "text",
+            placeholder: name.to_string(),
+            description: manifest.description.unwrap_or_default(),
+            examples: manifest.examples,
+            ..Default::default()
+        };
+
+        match manifest.input {
+            InputSchema::Integer { min, max } => {
+                widget.input_type = "number";
+                widget.min = min;
+                widget.max = max;
+                widget.placeholder = match (min, max) {
+                    (Some(min), Some(max)) => format!("{} ({} to {})", name, min, max),
+                    (None, Some(max)) => format!("{} (up to {})", name, max),
+                    (Some(min), None) => format!("{} (from {})", name, min),
+                    (None, None) => name.to_string(),
+                };
+            }
+            InputSchema::String { max_length } => widget.max_length = max_length,
+            InputSchema::Json => {
+                widget.multiline = true;
+                widget.placeholder = format!("{} (JSON)", name);
+            }
+            InputSchema::Bytes { .. } => widget.placeholder = format!("{} (base64)", name),
+        }
+
+        widget
+    }
+}
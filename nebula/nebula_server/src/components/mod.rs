@@ -1 +1 @@
-pub mod function_results;
+pub mod function_input;
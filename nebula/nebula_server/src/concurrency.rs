@@ -0,0 +1,303 @@
+//! Concurrency limits for invocations.
+//!
+//! Every invocation needs a slot for its function and a global slot before it runs. Invocations
+//! that can't get both right away wait in a bounded queue, and once that queue is full they are
+//! rejected with `429 Too Many Requests` and a `Retry-After` estimate instead of piling up.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::http::StatusCode;
+use nebula_lib::models::ModuleType;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::api::error::ApiError;
+
+#[derive(Debug, Clone)]
+pub struct LimitsConfig {
+    /// Invocations running at once across all functions.
+    pub max_concurrency: usize,
+    /// Invocations of one function running at once, unlimited if `None`.
+    pub max_concurrency_per_function: Option<usize>,
+    /// Per function limits overriding `max_concurrency_per_function`.
+    pub function_limits: HashMap<String, usize>,
+    /// Invocations allowed to wait for a slot before new ones are rejected.
+    pub max_queued: usize,
+}
+
+#[derive(Debug)]
+pub struct ConcurrencyLimits {
+    config: LimitsConfig,
+    global: Arc<Semaphore>,
+    functions: std::sync::Mutex<HashMap<(ModuleType, String), Arc<Semaphore>>>,
+    queued: AtomicUsize,
+}
+
+/// Slots held by a running invocation, released on drop.
+#[derive(Debug)]
+pub struct InvocationPermit {
+    _function: Option<OwnedSemaphorePermit>,
+    _global: OwnedSemaphorePermit,
+    /// How long the invocation waited for its slots.
+    pub queue_time: Duration,
+}
+
+impl ConcurrencyLimits {
+    pub fn new(config: LimitsConfig) -> Self {
+        ConcurrencyLimits {
+            global: Arc::new(Semaphore::new(config.max_concurrency.max(1))),
+            functions: Default::default(),
+            queued: AtomicUsize::new(0),
+            config,
+        }
+    }
+
+    /// Waits for a slot to run `func_name`, or fails with `429` if too many invocations are
+    /// waiting already.
+    pub async fn acquire(
+        &self,
+        module_type: ModuleType,
+        func_name: &str,
+    ) -> Result<InvocationPermit, ApiError> {
+        self.acquire_slot(module_type, func_name, true).await
+    }
+
+    /// Waits for a slot to run `func_name` without a place in the wait queue, for invocations
+    /// that were queued elsewhere already, like jobs, or hold a place from [`Self::enter_queue`].
+    pub async fn acquire_queued(
+        &self,
+        module_type: ModuleType,
+        func_name: &str,
+    ) -> Result<InvocationPermit, ApiError> {
+        self.acquire_slot(module_type, func_name, false).await
+    }
+
+    async fn acquire_slot(
+        &self,
+        module_type: ModuleType,
+        func_name: &str,
+        bounded: bool,
+    ) -> Result<InvocationPermit, ApiError> {
+        let start = Instant::now();
+        let function = self.function_semaphore(module_type, func_name);
+
+        // Invocations that don't have to wait don't count against the queue.
+        if let Some(function_permit) = try_acquire(&function) {
+            if let Ok(global_permit) = self.global.clone().try_acquire_owned() {
+                return Ok(InvocationPermit {
+                    _function: function_permit,
+                    _global: global_permit,
+                    queue_time: start.elapsed(),
+                });
+            }
+        }
+
+        let _queued = match bounded {
+            true => Some(self.enter_queue()?),
+            false => None,
+        };
+
+        let function_permit = match function {
+            Some(function) => Some(
+                function
+                    .acquire_owned()
+                    .await
+                    .map_err(|err| ApiError::internal(err.to_string()))?,
+            ),
+            None => None,
+        };
+        let global_permit = self
+            .global
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|err| ApiError::internal(err.to_string()))?;
+
+        Ok(InvocationPermit {
+            _function: function_permit,
+            _global: global_permit,
+            queue_time: start.elapsed(),
+        })
+    }
+
+    /// Takes a place in the wait queue, for a batch of invocations such as a fan-out that then
+    /// wait with [`Self::acquire_queued`] while it's held. Fails with `429` if the queue is full.
+    pub fn enter_queue(&self) -> Result<QueueSlot<'_>, ApiError> {
+        QueueSlot::take(&self.queued, self.config.max_queued).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "too_many_requests",
+                "Too many invocations are waiting, try again later",
+            )
+            .with_retry_after(self.retry_after())
+        })
+    }
+
+    fn function_semaphore(
+        &self,
+        module_type: ModuleType,
+        func_name: &str,
+    ) -> Option<Arc<Semaphore>> {
+        let limit = self
+            .config
+            .function_limits
+            .get(func_name)
+            .copied()
+            .or(self.config.max_concurrency_per_function)?;
+
+        let mut functions = self
+            .functions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        Some(
+            functions
+                .entry((module_type, func_name.to_string()))
+                .or_insert_with(|| Arc::new(Semaphore::new(limit.max(1))))
+                .clone(),
+        )
+    }
+
+    /// Invocations allowed to run at once across all functions.
+    pub fn max_concurrency(&self) -> usize {
+        self.config.max_concurrency.max(1)
+    }
+
+    /// Invocations holding a global slot.
+    pub fn in_flight(&self) -> usize {
+        self.config.max_concurrency.max(1) - self.global.available_permits()
+    }
+
+    /// Invocations waiting in the bounded queue.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Seconds until a retry is likely to get in, assuming invocations take about a second.
+    fn retry_after(&self) -> u64 {
+        let queued = self.queued.load(Ordering::Relaxed);
+
+        (queued / self.config.max_concurrency.max(1)) as u64 + 1
+    }
+}
+
+fn try_acquire(semaphore: &Option<Arc<Semaphore>>) -> Option<Option<OwnedSemaphorePermit>> {
+    match semaphore {
+        Some(semaphore) => semaphore.clone().try_acquire_owned().ok().map(Some),
+        None => Some(None),
+    }
+}
+
+/// A place in the wait queue, given back on drop so cancelled requests don't leak it.
+#[derive(Debug)]
+pub struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl<'a> QueueSlot<'a> {
+    fn take(queued: &'a AtomicUsize, max_queued: usize) -> Option<Self> {
+        queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                (queued < max_queued).then_some(queued + 1)
+            })
+            .ok()
+            .map(|_| QueueSlot(queued))
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Parses a `name=limit` per function limit.
+pub fn parse_function_limit(value: &str) -> Result<(String, usize), String> {
+    let (name, limit) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected name=limit, got {:?}", value))?;
+    let limit = limit
+        .parse::<usize>()
+        .map_err(|err| format!("invalid limit for {}: {}", name, err))?;
+
+    Ok((name.to_string(), limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_queued: usize) -> ConcurrencyLimits {
+        ConcurrencyLimits::new(LimitsConfig {
+            max_concurrency: 2,
+            max_concurrency_per_function: None,
+            function_limits: HashMap::from([("fibonacci-recursive".to_string(), 1)]),
+            max_queued,
+        })
+    }
+
+    #[tokio::test]
+    async fn rejects_when_the_queue_is_full() {
+        let limits = limits(0);
+
+        let _first = limits
+            .acquire(ModuleType::Wasm, "fibonacci-recursive")
+            .await
+            .unwrap();
+        let err = limits
+            .acquire(ModuleType::Wasm, "fibonacci-recursive")
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(err.retry_after, Some(1));
+        assert!(limits.enter_queue().is_err());
+
+        // Other functions, and the same function on another runtime, have their own slots.
+        let _second = limits
+            .acquire(ModuleType::Docker, "fibonacci-recursive")
+            .await
+            .unwrap();
+        assert!(limits.acquire(ModuleType::Wasm, "factorial").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn queued_invocations_wait_for_a_slot() {
+        let limits = Arc::new(limits(1));
+
+        let first = limits
+            .acquire(ModuleType::Wasm, "fibonacci-recursive")
+            .await
+            .unwrap();
+
+        let waiting = {
+            let limits = limits.clone();
+            tokio::spawn(async move {
+                limits
+                    .acquire(ModuleType::Wasm, "fibonacci-recursive")
+                    .await
+                    .map(|permit| permit.queue_time)
+            })
+        };
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(first);
+
+        let queue_time = waiting.await.unwrap().unwrap();
+        assert!(queue_time >= Duration::from_millis(20));
+        assert_eq!(limits.queued.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn parses_function_limits() {
+        assert_eq!(
+            parse_function_limit("factorial=3"),
+            Ok(("factorial".to_string(), 3))
+        );
+        assert!(parse_function_limit("factorial").is_err());
+        assert!(parse_function_limit("factorial=many").is_err());
+    }
+}
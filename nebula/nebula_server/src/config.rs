@@ -0,0 +1,185 @@
+//! Where the server keeps its files.
+//!
+//! Each directory is set by a flag, its environment variable or the TOML file passed with
+//! `--config`, in that order, and defaults to a directory in the home directory:
+//!
+//! - `data_dir`, `~/.nebula`: deployed functions, their registry, the API keys and, per server
+//!   version, the history, jobs, schedules, pipelines and usage.
+//! - `wasm_module_dir`, `~/modules/wasm`: Wasm modules imported on startup.
+//! - `docker_module_dir`, `~/modules/docker`: Docker image archives loaded on startup.
+//!
+//! Servers with different data directories don't share any state, so several can run side by
+//! side on one host.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Directories as set by flags, environment variables or a config file, `None` where unset.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirsConfig {
+    pub data_dir: Option<PathBuf>,
+    pub wasm_module_dir: Option<PathBuf>,
+    pub docker_module_dir: Option<PathBuf>,
+}
+
+impl DirsConfig {
+    /// Reads a config file. Relative paths in it are relative to the file, and a leading `~/`
+    /// is the home directory.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let config: DirsConfig =
+            toml::from_str(&contents).with_context(|| format!("Failed to parse {:?}", path))?;
+
+        let base = path.parent().unwrap_or(Path::new("."));
+        let resolve = |dir: Option<PathBuf>| dir.map(|dir| resolve_path(base, &dir));
+
+        Ok(DirsConfig {
+            data_dir: resolve(config.data_dir),
+            wasm_module_dir: resolve(config.wasm_module_dir),
+            docker_module_dir: resolve(config.docker_module_dir),
+        })
+    }
+
+    /// Takes the directories not set in `self` from `fallback`.
+    pub fn or(self, fallback: DirsConfig) -> DirsConfig {
+        DirsConfig {
+            data_dir: self.data_dir.or(fallback.data_dir),
+            wasm_module_dir: self.wasm_module_dir.or(fallback.wasm_module_dir),
+            docker_module_dir: self.docker_module_dir.or(fallback.docker_module_dir),
+        }
+    }
+
+    /// Fills in the defaults for the directories that aren't set.
+    pub fn resolve(self) -> Result<Dirs> {
+        let or_home = |dir: Option<PathBuf>, default: &str| match dir {
+            Some(dir) => Ok(dir),
+            None => dirs::home_dir()
+                .map(|home| home.join(default))
+                .context("Home directory not found"),
+        };
+
+        Ok(Dirs {
+            data_dir: or_home(self.data_dir, ".nebula")?,
+            wasm_module_dir: or_home(self.wasm_module_dir, "modules/wasm")?,
+            docker_module_dir: or_home(self.docker_module_dir, "modules/docker")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dirs {
+    pub data_dir: PathBuf,
+    pub wasm_module_dir: PathBuf,
+    pub docker_module_dir: PathBuf,
+}
+
+impl Dirs {
+    /// Creates the data directory and the one for this server version.
+    pub fn create(&self) -> Result<()> {
+        fs::create_dir_all(self.version_dir())
+            .with_context(|| format!("Failed to create {:?}", self.version_dir()))
+    }
+
+    /// Precompiled modules of the deployed functions.
+    pub fn serialized_dir(&self) -> PathBuf {
+        self.data_dir.join("serialized")
+    }
+
+    /// Every uploaded version of every deployed function.
+    pub fn module_archive_dir(&self) -> PathBuf {
+        self.data_dir.join("modules")
+    }
+
+    pub fn module_registry_path(&self) -> PathBuf {
+        self.data_dir.join("registry.json")
+    }
+
+    /// API keys and their quotas, see [`crate::api_keys`].
+    pub fn api_keys_path(&self) -> PathBuf {
+        self.data_dir.join("keys.json")
+    }
+
+    /// Where earlier versions kept the invocation history, imported into the database on startup.
+    pub fn data_path(&self) -> PathBuf {
+        self.version_dir().join("data.json")
+    }
+
+    pub fn history_path(&self) -> PathBuf {
+        self.version_dir().join("history.db")
+    }
+
+    pub fn jobs_path(&self) -> PathBuf {
+        self.version_dir().join("jobs.json")
+    }
+
+    pub fn pipelines_path(&self) -> PathBuf {
+        self.version_dir().join("pipelines.json")
+    }
+
+    pub fn schedules_path(&self) -> PathBuf {
+        self.version_dir().join("schedules.json")
+    }
+
+    pub fn usage_path(&self) -> PathBuf {
+        self.version_dir().join("usage.json")
+    }
+
+    fn version_dir(&self) -> PathBuf {
+        self.data_dir.join(VERSION)
+    }
+}
+
+fn resolve_path(base: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => dirs::home_dir().map_or_else(|| path.to_path_buf(), |home| home.join(rest)),
+        Err(_) => base.join(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let dir = TempDir::new("config");
+        let path = dir.join("nebula.toml");
+        fs::write(
+            &path,
+            "data_dir = \"test\"\nwasm_module_dir = \"/srv/wasm\"\ndocker_module_dir = \"~/docker\"\n",
+        )
+        .unwrap();
+
+        let file = DirsConfig::load(&path).unwrap();
+        assert_eq!(file.data_dir, Some(dir.join("test")));
+        assert_eq!(file.wasm_module_dir, Some(PathBuf::from("/srv/wasm")));
+        assert_eq!(
+            file.docker_module_dir,
+            dirs::home_dir().map(|home| home.join("docker"))
+        );
+
+        let flags = DirsConfig {
+            wasm_module_dir: Some(PathBuf::from("/opt/wasm")),
+            ..Default::default()
+        };
+        let resolved = flags.or(file).resolve().unwrap();
+        assert_eq!(resolved.wasm_module_dir, PathBuf::from("/opt/wasm"));
+        assert_eq!(
+            resolved.history_path(),
+            dir.join("test").join(VERSION).join("history.db")
+        );
+
+        fs::write(&path, "data-dir = \"test\"\n").unwrap();
+        assert!(DirsConfig::load(&path).is_err());
+    }
+}
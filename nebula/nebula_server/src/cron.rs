@@ -0,0 +1,237 @@
+//! Cron expressions, evaluated in UTC.
+//!
+//! The five classic fields, `minute hour day-of-month month day-of-week`. Each field is a list of
+//! `*`, a value or a range `a-b`, optionally stepped like `*/15` or `0-30/10`. Days of the week
+//! run from 0 (Sunday) to 7 (Sunday again). Like in Vixie cron, a day matches either day field
+//! when both are restricted. `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly` are
+//! accepted as well.
+
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+/// Days searched for the next match, enough for a 29th of February.
+const SEARCH_DAYS: u64 = 366 * 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CronExpr {
+    /// One bit per allowed value.
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    /// Whether a day field starts with `*`, the other one alone decides then.
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+/// Why a cron expression can't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronError(pub String);
+
+impl Display for CronError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for CronError {}
+
+impl FromStr for CronExpr {
+    type Err = CronError;
+
+    fn from_str(expr: &str) -> Result<Self, Self::Err> {
+        let expr = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expr => expr,
+        };
+
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day_of_month, month, day_of_week] = fields[..] else {
+            return Err(CronError(format!(
+                "Expected 5 fields, minute hour day-of-month month day-of-week, got {}",
+                fields.len()
+            )));
+        };
+
+        let mut days_of_week = parse_field(day_of_week, 0, 7, "day of week")?;
+        // 7 is Sunday as well.
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1;
+        }
+
+        Ok(CronExpr {
+            minutes: parse_field(minute, 0, 59, "minute")?,
+            hours: parse_field(hour, 0, 23, "hour")?,
+            days_of_month: parse_field(day_of_month, 1, 31, "day of month")?,
+            months: parse_field(month, 1, 12, "month")?,
+            days_of_week,
+            any_day_of_month: day_of_month.starts_with('*'),
+            any_day_of_week: day_of_week.starts_with('*'),
+        })
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32, name: &str) -> Result<u64, CronError> {
+    let invalid = |reason: &str| CronError(format!("Invalid {} {:?}: {}", name, field, reason));
+    let value = |value: &str| {
+        value
+            .parse::<u32>()
+            .ok()
+            .filter(|value| (min..=max).contains(value))
+            .ok_or_else(|| invalid(&format!("values go from {} to {}", min, max)))
+    };
+
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(invalid("the step must be a positive number")),
+            },
+            None => (part, 1),
+        };
+
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // `5/10` runs from 5 to the end.
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if start > end {
+            return Err(invalid("ranges can't wrap around"));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+impl CronExpr {
+    /// The first matching minute after `after`, in seconds since epoch.
+    pub fn next_after(&self, after: u64) -> Option<u64> {
+        let start = after / 60 + 1;
+        let mut minute_of_day = start % 1440;
+
+        for day in (start / 1440)..(start / 1440 + SEARCH_DAYS) {
+            if self.matches_day(day) {
+                for minute in minute_of_day..1440 {
+                    if self.hours & (1 << (minute / 60)) != 0
+                        && self.minutes & (1 << (minute % 60)) != 0
+                    {
+                        return Some((day * 1440 + minute) * 60);
+                    }
+                }
+            }
+            minute_of_day = 0;
+        }
+
+        None
+    }
+
+    fn matches_day(&self, days_since_epoch: u64) -> bool {
+        let (month, day_of_month) = month_and_day(days_since_epoch);
+        // 1970-01-01 was a Thursday.
+        let day_of_week = (days_since_epoch + 4) % 7;
+
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+
+        let day_of_month = self.days_of_month & (1 << day_of_month) != 0;
+        let day_of_week = self.days_of_week & (1 << day_of_week) != 0;
+        if self.any_day_of_month || self.any_day_of_week {
+            day_of_month && day_of_week
+        } else {
+            day_of_month || day_of_week
+        }
+    }
+}
+
+/// Month and day of the month of a day since epoch, see
+/// <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn month_and_day(days_since_epoch: u64) -> (u64, u64) {
+    let days = days_since_epoch + 719_468;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months starting from March.
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+
+    (if month < 10 { month + 3 } else { month - 9 }, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01T00:00:00Z, a Monday.
+    const NEW_YEAR_2024: u64 = 1_704_067_200;
+
+    fn next(expr: &str, after: u64) -> u64 {
+        expr.parse::<CronExpr>().unwrap().next_after(after).unwrap()
+    }
+
+    #[test]
+    fn finds_the_next_matching_minute() {
+        assert_eq!(
+            next("*/15 * * * *", NEW_YEAR_2024 + 7 * 60),
+            NEW_YEAR_2024 + 15 * 60
+        );
+        assert_eq!(next("@hourly", NEW_YEAR_2024), NEW_YEAR_2024 + 3600);
+        // Runs strictly after, not at, the given time.
+        assert_eq!(next("0 0 * * *", NEW_YEAR_2024), NEW_YEAR_2024 + 86_400);
+        // Sunday the 7th, as 0 and as 7.
+        assert_eq!(
+            next("30 9 * * 0", NEW_YEAR_2024),
+            NEW_YEAR_2024 + 6 * 86_400 + 9 * 3600 + 1800
+        );
+        assert_eq!(
+            next("30 9 * * 7", NEW_YEAR_2024),
+            NEW_YEAR_2024 + 6 * 86_400 + 9 * 3600 + 1800
+        );
+        // The 3rd, or any Friday, whichever comes first: Wednesday the 3rd.
+        assert_eq!(
+            next("0 12 3 * 5", NEW_YEAR_2024),
+            NEW_YEAR_2024 + 2 * 86_400 + 12 * 3600
+        );
+        // 2024-02-29.
+        assert_eq!(
+            next("0 0 29 2 *", NEW_YEAR_2024),
+            NEW_YEAR_2024 + 59 * 86_400
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expr in [
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "* * 0 * *",
+            "a * * * *",
+        ] {
+            assert!(expr.parse::<CronExpr>().is_err(), "{}", expr);
+        }
+        assert_eq!(
+            "0 0 31 2 *"
+                .parse::<CronExpr>()
+                .unwrap()
+                .next_after(NEW_YEAR_2024),
+            None
+        );
+    }
+}
@@ -0,0 +1,534 @@
+//! Invocation history.
+//!
+//! Every [`FunctionResult`] is a row in a SQLite database, `history.db`, indexed by function,
+//! runtime, input and start time, so recording an invocation doesn't rewrite the history and the
+//! metrics are computed by SQL aggregates. A `data.json` written by earlier versions is imported
+//! the first time the database is opened.
+
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use nebula_lib::models::{FunctionResult, ModuleType, Trigger};
+use rusqlite::{params, params_from_iter, types::Value, Connection, Row};
+use serde::Serialize;
+use tracing::info;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS invocations (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        func_name TEXT NOT NULL,
+        func_type TEXT NOT NULL,
+        input TEXT NOT NULL,
+        base_image TEXT NOT NULL,
+        started_at INTEGER,
+        startup_time INTEGER,
+        total_runtime INTEGER,
+        error TEXT,
+        result TEXT NOT NULL,
+        trigger TEXT NOT NULL DEFAULT 'api'
+    );
+    CREATE INDEX IF NOT EXISTS invocations_function ON invocations (func_name, func_type);
+    CREATE INDEX IF NOT EXISTS invocations_type ON invocations (func_type);
+    CREATE INDEX IF NOT EXISTS invocations_input ON invocations (input);
+    CREATE INDEX IF NOT EXISTS invocations_started_at ON invocations (started_at);
+";
+
+/// Averages of the invocations of a runtime, in microseconds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RuntimeSummary {
+    pub invocations: usize,
+    pub avg_startup: u128,
+    pub avg_runtime: u128,
+    pub avg_total_time: u128,
+}
+
+/// Timings of a group of invocations, in microseconds.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timings {
+    pub avg: f64,
+    pub min: u128,
+    pub max: u128,
+}
+
+/// Timings of the invocations of a function on a runtime, with one input if grouped by input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionStats {
+    pub func_name: String,
+    pub func_type: ModuleType,
+    pub input: Option<String>,
+    pub invocations: usize,
+    pub startup: Timings,
+    pub runtime: Timings,
+    pub total_time: Timings,
+}
+
+/// Latest time in milliseconds since epoch a filter can select, so it fits the microseconds in the
+/// database.
+pub const MAX_TIMESTAMP: u64 = i64::MAX as u64 / 1000;
+
+/// Which invocations to list, every field narrows the selection.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ResultFilter {
+    pub func_name: Option<String>,
+    pub module_type: Option<ModuleType>,
+    pub base_image: Option<String>,
+    pub input: Option<String>,
+    /// Milliseconds since epoch, inclusive.
+    pub since: Option<u64>,
+    /// Milliseconds since epoch, exclusive.
+    pub until: Option<u64>,
+    /// Only invocations that succeeded, or only those that failed.
+    pub success: Option<bool>,
+    pub trigger: Option<Trigger>,
+}
+
+impl ResultFilter {
+    /// Whether `result` is in the selection, for results that aren't in the database yet.
+    pub fn matches(&self, result: &FunctionResult) -> bool {
+        // Microseconds, like `started_at`.
+        let started_at = result
+            .metrics
+            .as_ref()
+            .map(|metrics| metrics.start_since_epoch);
+        let in_range = |bound: Option<u64>, check: fn(u128, u128) -> bool| {
+            bound.is_none_or(|bound| {
+                started_at.is_some_and(|started_at| check(started_at, micros(bound) as u128))
+            })
+        };
+
+        self.func_name
+            .as_ref()
+            .is_none_or(|func_name| *func_name == result.func_name)
+            && self
+                .module_type
+                .is_none_or(|module_type| module_type == result.func_type)
+            && self
+                .base_image
+                .as_ref()
+                .is_none_or(|base_image| *base_image == result.base_image)
+            && self
+                .input
+                .as_ref()
+                .is_none_or(|input| *input == result.input)
+            && in_range(self.since, |started_at, since| started_at >= since)
+            && in_range(self.until, |started_at, until| started_at < until)
+            && self
+                .success
+                .is_none_or(|success| success == result.is_success())
+            && self.trigger.is_none_or(|trigger| trigger == result.trigger)
+    }
+
+    /// The `WHERE` clause selecting the matching invocations, and its parameters.
+    fn to_sql(&self) -> (String, Vec<Value>) {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+
+        let mut condition = |sql: &str, value: Value| {
+            values.push(value);
+            conditions.push(format!("{} ?{}", sql, values.len()));
+        };
+
+        if let Some(func_name) = &self.func_name {
+            condition("func_name =", Value::Text(func_name.clone()));
+        }
+        if let Some(module_type) = self.module_type {
+            condition("func_type =", Value::Text(module_type.to_string()));
+        }
+        if let Some(base_image) = &self.base_image {
+            condition("base_image =", Value::Text(base_image.clone()));
+        }
+        if let Some(input) = &self.input {
+            condition("input =", Value::Text(input.clone()));
+        }
+        if let Some(since) = self.since {
+            condition("started_at >=", Value::Integer(micros(since)));
+        }
+        if let Some(until) = self.until {
+            condition("started_at <", Value::Integer(micros(until)));
+        }
+        if let Some(trigger) = self.trigger {
+            condition("trigger =", Value::Text(trigger.to_string()));
+        }
+        match self.success {
+            Some(true) => conditions.push("error IS NULL".to_string()),
+            Some(false) => conditions.push("error IS NOT NULL".to_string()),
+            None => {}
+        }
+
+        if conditions.is_empty() {
+            (String::new(), values)
+        } else {
+            (format!("WHERE {}", conditions.join(" AND ")), values)
+        }
+    }
+}
+
+/// Milliseconds to microseconds, capped at [`MAX_TIMESTAMP`].
+fn micros(millis: u64) -> i64 {
+    millis.min(MAX_TIMESTAMP) as i64 * 1000
+}
+
+/// A page of invocations, newest first.
+#[derive(Debug, Clone, Default)]
+pub struct Page {
+    pub results: Vec<FunctionResult>,
+    /// Pass as `cursor` to get the next page, `None` on the last page.
+    pub next_cursor: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct History {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl History {
+    /// Opens the database at `path`, creating it and importing the results in `legacy_path` if
+    /// it has no invocations yet. The imported file is renamed to `*.imported`.
+    pub fn open(path: &Path, legacy_path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        migrate(&connection)?;
+        connection.execute_batch(SCHEMA)?;
+
+        let history = History {
+            connection: Arc::new(Mutex::new(connection)),
+        };
+
+        if legacy_path.is_file() && history.count()? == 0 {
+            let mut results: Vec<FunctionResult> =
+                serde_json::from_str(&fs::read_to_string(legacy_path)?)?;
+            // data.json was written newest first.
+            results.reverse();
+
+            history.insert(&results)?;
+            fs::rename(legacy_path, legacy_path.with_extension("json.imported"))?;
+            info!("imported {} results from {:?}", results.len(), legacy_path);
+        }
+
+        Ok(history)
+    }
+
+    fn with_connection<T>(&self, f: impl FnOnce(&mut Connection) -> Result<T>) -> Result<T> {
+        let mut connection = self
+            .connection
+            .lock()
+            .map_err(|_| anyhow!("history database lock poisoned"))?;
+
+        f(&mut connection)
+    }
+
+    /// Adds `results` to the history, in one transaction, returning the ids of their rows.
+    pub fn insert(&self, results: &[FunctionResult]) -> Result<Vec<i64>> {
+        self.with_connection(|connection| {
+            let transaction = connection.transaction()?;
+            let mut ids = Vec::with_capacity(results.len());
+            {
+                let mut statement = transaction.prepare_cached(
+                    "INSERT INTO invocations
+                     (func_name, func_type, input, base_image, started_at, startup_time,
+                      total_runtime, error, result, trigger)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                )?;
+
+                for result in results {
+                    let metrics = result.metrics.as_ref();
+                    statement.execute(params![
+                        result.func_name,
+                        result.func_type.to_string(),
+                        result.input,
+                        result.base_image,
+                        metrics.map(|metrics| metrics.start_since_epoch as i64),
+                        metrics.map(|metrics| metrics.startup_time as i64),
+                        metrics.map(|metrics| metrics.total_runtime as i64),
+                        result.error,
+                        serde_json::to_string(result)?,
+                        result.trigger.to_string(),
+                    ])?;
+                    ids.push(transaction.last_insert_rowid());
+                }
+            }
+            transaction.commit()?;
+
+            Ok(ids)
+        })
+    }
+
+    /// The results in the rows `ids`, in that order.
+    pub fn get(&self, ids: &[i64]) -> Result<Vec<FunctionResult>> {
+        self.with_connection(|connection| {
+            let mut statement =
+                connection.prepare_cached("SELECT result FROM invocations WHERE id = ?1")?;
+
+            ids.iter()
+                .map(|id| {
+                    let result: String = statement.query_row([id], |row| row.get(0))?;
+                    Ok(serde_json::from_str(&result)?)
+                })
+                .collect()
+        })
+    }
+
+    pub fn count(&self) -> Result<usize> {
+        self.with_connection(|connection| {
+            let count: i64 =
+                connection.query_row("SELECT COUNT(*) FROM invocations", [], |row| row.get(0))?;
+
+            Ok(count as usize)
+        })
+    }
+
+    /// Up to `limit` invocations matching `filter`, newest first, starting after `cursor`.
+    pub fn page(&self, filter: &ResultFilter, cursor: Option<i64>, limit: usize) -> Result<Page> {
+        let (mut conditions, mut values) = filter.to_sql();
+        if let Some(cursor) = cursor {
+            values.push(Value::Integer(cursor));
+            let keyword = if conditions.is_empty() {
+                "WHERE"
+            } else {
+                " AND"
+            };
+            conditions.push_str(&format!("{} id < ?{}", keyword, values.len()));
+        }
+        // One more than asked for, to know whether there is a next page.
+        values.push(Value::Integer(limit as i64 + 1));
+        let query = format!(
+            "SELECT id, result FROM invocations {} ORDER BY id DESC LIMIT ?{}",
+            conditions,
+            values.len()
+        );
+
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare_cached(&query)?;
+            let rows = statement.query_map(params_from_iter(values), |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?;
+
+            let mut rows = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+            let next_cursor = if rows.len() > limit {
+                rows.truncate(limit);
+                rows.last().map(|(id, _)| *id)
+            } else {
+                None
+            };
+
+            let results = rows
+                .into_iter()
+                .map(|(_, result)| serde_json::from_str(&result))
+                .collect::<serde_json::Result<_>>()?;
+
+            Ok(Page {
+                results,
+                next_cursor,
+            })
+        })
+    }
+
+    /// Averages of the invocations of `module_type` matching `filter`.
+    pub fn summary(
+        &self,
+        module_type: ModuleType,
+        filter: &ResultFilter,
+    ) -> Result<RuntimeSummary> {
+        let filter = ResultFilter {
+            module_type: Some(module_type),
+            ..filter.clone()
+        };
+        let (conditions, values) = filter.to_sql();
+        let query = format!(
+            "SELECT COUNT(*), AVG(startup_time), AVG(total_runtime - startup_time),
+                    AVG(total_runtime)
+             FROM invocations {} AND startup_time IS NOT NULL",
+            conditions
+        );
+
+        self.with_connection(|connection| {
+            let summary = connection.query_row(&query, params_from_iter(values), |row| {
+                Ok(RuntimeSummary {
+                    invocations: row.get::<_, i64>(0)? as usize,
+                    avg_startup: row.get::<_, Option<f64>>(1)?.unwrap_or(0.0) as u128,
+                    avg_runtime: row.get::<_, Option<f64>>(2)?.unwrap_or(0.0) as u128,
+                    avg_total_time: row.get::<_, Option<f64>>(3)?.unwrap_or(0.0) as u128,
+                })
+            })?;
+
+            Ok(summary)
+        })
+    }
+
+    /// Timings per function and runtime, and per input as well if `by_input` is set.
+    pub fn stats(&self, by_input: bool) -> Result<Vec<FunctionStats>> {
+        let (input, group_by) = if by_input {
+            ("input", "func_name, func_type, input")
+        } else {
+            ("NULL", "func_name, func_type")
+        };
+        let query = format!(
+            "SELECT func_name, func_type, {input}, COUNT(*),
+                    AVG(startup_time), MIN(startup_time), MAX(startup_time),
+                    AVG(total_runtime - startup_time), MIN(total_runtime - startup_time),
+                    MAX(total_runtime - startup_time),
+                    AVG(total_runtime), MIN(total_runtime), MAX(total_runtime)
+             FROM invocations WHERE startup_time IS NOT NULL
+             GROUP BY {group_by}"
+        );
+
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare_cached(&query)?;
+            let rows = statement.query_map([], |row| {
+                let func_type = row.get::<_, String>(1)?.parse().unwrap_or(ModuleType::Wasm);
+
+                Ok(FunctionStats {
+                    func_name: row.get(0)?,
+                    func_type,
+                    input: row.get(2)?,
+                    invocations: row.get::<_, i64>(3)? as usize,
+                    startup: timings(row, 4)?,
+                    runtime: timings(row, 7)?,
+                    total_time: timings(row, 10)?,
+                })
+            })?;
+
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })
+    }
+}
+
+/// Reads the average, minimum and maximum starting at column `index`.
+fn timings(row: &Row, index: usize) -> rusqlite::Result<Timings> {
+    Ok(Timings {
+        avg: row.get(index)?,
+        min: row.get::<_, i64>(index + 1)? as u128,
+        max: row.get::<_, i64>(index + 2)? as u128,
+    })
+}
+
+/// Adds the columns that databases created by earlier versions lack.
+fn migrate(connection: &Connection) -> Result<()> {
+    let mut statement = connection.prepare("SELECT name FROM pragma_table_info('invocations')")?;
+    let columns = statement
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    // A new database gets every column from the schema.
+    if !columns.is_empty() && !columns.iter().any(|column| column == "trigger") {
+        connection.execute_batch(
+            "ALTER TABLE invocations ADD COLUMN trigger TEXT NOT NULL DEFAULT 'api'",
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn result(func_type: ModuleType, input: &str, startup_time: u128) -> FunctionResult {
+        FunctionResult {
+            func_type,
+            input: input.to_string(),
+            ..FunctionResult::test_fixture(startup_time, startup_time + 100)
+        }
+    }
+
+    #[test]
+    fn imports_data_json_and_queries_it() {
+        let dir = TempDir::new("history");
+
+        let legacy_path = dir.join("data.json");
+        let legacy = vec![
+            result(ModuleType::Docker, "5", 3000),
+            result(ModuleType::Wasm, "5", 30),
+            result(ModuleType::Wasm, "4", 10),
+        ];
+        fs::write(&legacy_path, serde_json::to_string(&legacy).unwrap()).unwrap();
+
+        let history = History::open(&dir.join("history.db"), &legacy_path).unwrap();
+        assert!(!legacy_path.exists());
+        let all = ResultFilter::default();
+        assert_eq!(history.page(&all, None, 1).unwrap().results[0].input, "5");
+
+        let ids = history
+            .insert(&[result(ModuleType::Wasm, "4", 20)])
+            .unwrap();
+        assert_eq!(history.count().unwrap(), 4);
+        let inserted = history.get(&ids).unwrap();
+        assert_eq!(inserted.len(), 1);
+        assert_eq!(inserted[0].metrics.as_ref().unwrap().startup_time, 20);
+
+        assert_eq!(
+            history.summary(ModuleType::Wasm, &all).unwrap(),
+            RuntimeSummary {
+                invocations: 3,
+                avg_startup: 20,
+                avg_runtime: 100,
+                avg_total_time: 120,
+            }
+        );
+
+        let stats = history.stats(true).unwrap();
+        let four = stats
+            .iter()
+            .find(|stats| stats.input.as_deref() == Some("4"))
+            .unwrap();
+        assert_eq!(four.invocations, 2);
+        assert_eq!(
+            four.startup,
+            Timings {
+                avg: 15.0,
+                min: 10,
+                max: 20
+            }
+        );
+        assert_eq!(history.stats(false).unwrap().len(), 2);
+
+        let wasm = ResultFilter {
+            module_type: Some(ModuleType::Wasm),
+            ..Default::default()
+        };
+        let first = history.page(&wasm, None, 2).unwrap();
+        assert_eq!(first.results.len(), 2);
+        let last = history.page(&wasm, first.next_cursor, 2).unwrap();
+        assert_eq!(last.results.len(), 1);
+        assert_eq!(last.results[0].input, "4");
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn filters_results_like_the_database() {
+        let mut failed = result(ModuleType::Docker, "5", 3000);
+        failed.error = Some("exit code 1".to_string());
+        failed.metrics.as_mut().unwrap().start_since_epoch = 2_000_000;
+
+        let filter = ResultFilter {
+            module_type: Some(ModuleType::Docker),
+            input: Some("5".to_string()),
+            since: Some(2_000),
+            success: Some(false),
+            ..Default::default()
+        };
+        assert!(filter.matches(&failed));
+        assert!(!filter.matches(&result(ModuleType::Docker, "5", 3000)));
+
+        let until = ResultFilter {
+            until: Some(2_000),
+            ..Default::default()
+        };
+        assert!(!until.matches(&failed));
+        assert!(ResultFilter::default().matches(&failed));
+
+        let far_future = ResultFilter {
+            until: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert!(far_future.matches(&failed));
+        assert_eq!(
+            far_future.to_sql().1,
+            [Value::Integer(MAX_TIMESTAMP as i64 * 1000)]
+        );
+    }
+}
@@ -0,0 +1,358 @@
+//! Asynchronous invocations.
+//!
+//! A submitted [`FunctionRequest`] becomes a [`Job`] that is queued and answered with its id right
+//! away. A fixed number of workers take jobs off the bounded queue and run them like a regular
+//! invocation, so a long running function or a large `num_calls` doesn't hold a request open.
+//! Jobs are written to `jobs.json` on every change, and jobs that hadn't finished when the server
+//! stopped are run again on startup. The results of a job are only kept in the invocation history,
+//! the job points at their rows, so the file stays small however many calls the jobs made.
+
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::StatusCode;
+use nebula_lib::{
+    models::{FunctionResult, Trigger},
+    module_store::parse_reference,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    Mutex, MutexGuard,
+};
+use tracing::{info, info_span, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        call_function::{invoke_request, record_results, validate_request},
+        error::{ApiError, ErrorBody},
+    },
+    models::{AppState, FunctionRequest},
+    utilities::json_file::JsonFile,
+};
+
+/// Finished jobs kept around for polling, older ones are dropped.
+const MAX_FINISHED_JOBS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(self, JobStatus::Succeeded | JobStatus::Failed)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    pub request: FunctionRequest,
+    /// Milliseconds since epoch.
+    pub created_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    /// History rows of the results, one per call, once the job has succeeded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub result_ids: Vec<i64>,
+    /// One result per call, filled in from the history when a single job is returned. Only
+    /// stored with the job if the results couldn't be added to the history.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub results: Vec<FunctionResult>,
+    /// Why the job couldn't be run, set if it failed.
+    #[serde(default)]
+    pub error: Option<ErrorBody>,
+    /// Name of the API key that submitted the job, its compute time counts against that key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct JobQueue {
+    /// Oldest first.
+    jobs: Mutex<Vec<Job>>,
+    sender: mpsc::Sender<String>,
+    file: JsonFile,
+}
+
+impl JobQueue {
+    /// Loads the jobs persisted at `path`, marking unfinished ones as queued again, and creates a
+    /// queue holding up to `capacity` jobs. Pass the receiver to [`spawn_workers`].
+    pub fn new(path: PathBuf, capacity: usize) -> (Self, mpsc::Receiver<String>) {
+        let file = JsonFile::new(path);
+        let mut jobs: Vec<Job> = file.load_list();
+
+        for job in jobs.iter_mut().filter(|job| !job.status.is_finished()) {
+            job.status = JobStatus::Queued;
+            job.started_at = None;
+        }
+
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let queue = JobQueue {
+            jobs: Mutex::new(jobs),
+            sender,
+            file,
+        };
+
+        (queue, receiver)
+    }
+
+    /// Queues `request` on behalf of the key named `api_key`, failing if the queue is full.
+    pub async fn submit(
+        &self,
+        request: FunctionRequest,
+        api_key: Option<String>,
+    ) -> Result<Job, ApiError> {
+        let job = Job {
+            id: Uuid::new_v4().to_string(),
+            status: JobStatus::Queued,
+            request,
+            created_at: unix_millis(),
+            started_at: None,
+            finished_at: None,
+            result_ids: Vec::new(),
+            results: Vec::new(),
+            error: None,
+            api_key,
+        };
+
+        let mut jobs = self.jobs.lock().await;
+
+        match self.sender.try_send(job.id.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                return Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "queue_full",
+                    "Too many jobs are queued, try again later",
+                ))
+            }
+            Err(TrySendError::Closed(_)) => {
+                return Err(ApiError::internal("The job workers have stopped"))
+            }
+        }
+
+        jobs.push(job.clone());
+        self.persist(jobs).await;
+
+        Ok(job)
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Job> {
+        let jobs = self.jobs.lock().await;
+
+        jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    /// Every job newest first, without their results.
+    pub async fn list(&self) -> Vec<Job> {
+        let jobs = self.jobs.lock().await;
+
+        jobs.iter()
+            .rev()
+            .map(|job| Job {
+                results: Vec::new(),
+                ..job.clone()
+            })
+            .collect()
+    }
+
+    pub async fn count(&self, status: JobStatus) -> usize {
+        let jobs = self.jobs.lock().await;
+
+        jobs.iter().filter(|job| job.status == status).count()
+    }
+
+    /// Queues the jobs that were loaded unfinished again. Call once the workers are running.
+    pub async fn requeue_unfinished(&self) {
+        let unfinished: Vec<String> = {
+            let jobs = self.jobs.lock().await;
+            jobs.iter()
+                .filter(|job| !job.status.is_finished())
+                .map(|job| job.id.clone())
+                .collect()
+        };
+
+        if !unfinished.is_empty() {
+            info!("queueing {} unfinished jobs again", unfinished.len());
+        }
+
+        for id in unfinished {
+            if self.sender.send(id).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn update(&self, id: &str, update: impl FnOnce(&mut Job)) -> Option<Job> {
+        let mut jobs = self.jobs.lock().await;
+
+        let job = jobs.iter_mut().find(|job| job.id == id)?;
+        update(job);
+        let job = job.clone();
+
+        self.persist(jobs).await;
+
+        Some(job)
+    }
+
+    /// Drops the oldest finished jobs beyond [`MAX_FINISHED_JOBS`] and writes the jobs once
+    /// `jobs` is unlocked.
+    async fn persist(&self, mut jobs: MutexGuard<'_, Vec<Job>>) {
+        let finished = jobs.iter().filter(|job| job.status.is_finished()).count();
+        let mut excess = finished.saturating_sub(MAX_FINISHED_JOBS);
+        jobs.retain(|job| {
+            let drop = excess > 0 && job.status.is_finished();
+            if drop {
+                excess -= 1;
+            }
+            !drop
+        });
+
+        let snapshot = self.file.snapshot(jobs.clone());
+        drop(jobs);
+        self.file.write(snapshot).await;
+    }
+}
+
+/// Starts `workers` tasks running the jobs sent through `receiver`.
+pub fn spawn_workers(state: Arc<AppState>, receiver: mpsc::Receiver<String>, workers: usize) {
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for _ in 0..workers.max(1) {
+        let state = state.clone();
+        let receiver = receiver.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let next = receiver.lock().await.recv().await;
+                let Some(id) = next else {
+                    break;
+                };
+
+                let span = info_span!("job", id = %id);
+                run_job(&state, &id).instrument(span).await;
+            }
+        });
+    }
+}
+
+async fn run_job(state: &AppState, id: &str) {
+    let Some(job) = state
+        .jobs
+        .update(id, |job| {
+            job.status = JobStatus::Running;
+            job.started_at = Some(unix_millis());
+        })
+        .await
+    else {
+        return;
+    };
+
+    let mut request = job.request;
+    request.trigger = Trigger::Job;
+    let (func_name, _) = parse_reference(&request.function_name);
+    // The manifest may have changed since the job was submitted.
+    let outcome = match validate_request(state, &request) {
+        Ok(limits) => match state
+            .limits
+            .acquire_queued(request.module_type, func_name)
+            .instrument(info_span!("queue"))
+            .await
+        {
+            Ok(permit) => invoke_request(state, request.clone(), limits, permit).await,
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err),
+    };
+
+    let mut result_ids = None;
+    if let Ok(ref results) = outcome {
+        if let Some(api_key) = &job.api_key {
+            state.api_keys.record_compute(api_key, results);
+        }
+        result_ids = record_results(state, results).await;
+    }
+
+    state
+        .jobs
+        .update(id, |job| {
+            job.finished_at = Some(unix_millis());
+            match outcome {
+                Ok(results) => {
+                    job.status = JobStatus::Succeeded;
+                    match result_ids {
+                        Some(ids) => job.result_ids = ids,
+                        None => job.results = results,
+                    }
+                }
+                Err(err) => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(ErrorBody {
+                        code: err.code.to_string(),
+                        message: err.message,
+                    });
+                }
+            }
+        })
+        .await;
+}
+
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use nebula_lib::models::ModuleType;
+
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn request() -> FunctionRequest {
+        FunctionRequest {
+            function_name: "factorial".to_string(),
+            input: "5".to_string(),
+            module_type: ModuleType::Wasm,
+            num_calls: 1,
+            base_image: "debian".to_string(),
+            trigger: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn requeues_unfinished_jobs_after_restart() {
+        let dir = TempDir::new("jobs");
+        let path = dir.join("jobs.json");
+
+        let (queue, _receiver) = JobQueue::new(path.clone(), 1);
+        let job = queue.submit(request(), None).await.unwrap();
+        queue
+            .update(&job.id, |job| job.status = JobStatus::Running)
+            .await;
+
+        let err = queue.submit(request(), None).await.unwrap_err();
+        assert_eq!(err.code, "queue_full");
+
+        let (restarted, mut receiver) = JobQueue::new(path.clone(), 1);
+        assert_eq!(
+            restarted.get(&job.id).await.map(|job| job.status),
+            Some(JobStatus::Queued)
+        );
+
+        restarted.requeue_unfinished().await;
+        assert_eq!(receiver.recv().await, Some(job.id));
+    }
+}
@@ -1,5 +1,20 @@
 pub mod api;
+pub mod api_keys;
 pub mod components;
+pub mod concurrency;
+pub mod config;
+pub mod cron;
+pub mod history;
+pub mod jobs;
+pub mod live;
 pub mod models;
+pub mod mqtt;
 pub mod pages;
+pub mod pipelines;
+pub mod prometheus;
+pub mod schedules;
+pub mod telemetry;
+#[cfg(test)]
+mod test_utils;
 pub mod utilities;
+pub mod watch;
@@ -0,0 +1,280 @@
+//! Results as they come in, streamed as Server-Sent Events.
+//!
+//! Every recorded batch of results is broadcast to the subscribers of `GET /api/results/stream`.
+//! A subscriber gets the results matching its filters as `result` events, followed by a
+//! `summary` event with the averages of everything matching, read from the history once per batch
+//! for all the subscribers with the same filters. Subscribers that fall behind skip the results
+//! they missed rather than holding up invocations.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use askama::Template;
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::Stream;
+use nebula_lib::models::{FunctionResult, ModuleType};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    OnceCell,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    api::{
+        error::ApiError,
+        results::{empty_as_none, ResultsQuery},
+    },
+    history::{History, ResultFilter, RuntimeSummary},
+    models::{AppState, FCList, ResultsSummary},
+    utilities::negotiate::ResponseFormat,
+};
+
+/// Batches a subscriber can fall behind by before it skips some.
+const CAPACITY: usize = 256;
+
+type Summary = (RuntimeSummary, RuntimeSummary);
+
+/// Results recorded together.
+#[derive(Debug, Default)]
+pub struct Batch {
+    results: Vec<FunctionResult>,
+    /// Summaries after the batch, by the filters of the subscribers that asked for them.
+    summaries: Mutex<HashMap<ResultFilter, Arc<OnceCell<Option<Summary>>>>>,
+}
+
+impl Batch {
+    /// The Wasm and Docker averages of everything matching `filter` once the batch is recorded.
+    /// Read from the history by the first subscriber asking, the others get the same summary.
+    async fn summary(&self, history: &History, filter: &ResultFilter) -> Option<Summary> {
+        let summary = self
+            .summaries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(filter.clone())
+            .or_default()
+            .clone();
+
+        summary
+            .get_or_init(|| async {
+                summarize(history.clone(), filter.clone())
+                    .await
+                    .map_err(|err| warn!("failed to summarize the streamed results: {:#}", err))
+                    .ok()
+            })
+            .await
+            .clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct LiveResults {
+    sender: broadcast::Sender<Arc<Batch>>,
+}
+
+impl Default for LiveResults {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        LiveResults { sender }
+    }
+}
+
+impl LiveResults {
+    /// Sends `results` to every subscriber.
+    pub fn publish(&self, results: &[FunctionResult]) {
+        // Only fails if nobody is subscribed.
+        let _ = self.sender.send(Arc::new(Batch {
+            results: results.to_vec(),
+            summaries: Default::default(),
+        }));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Batch>> {
+        self.sender.subscribe()
+    }
+}
+
+/// Query parameters of `GET /api/results/stream`, the filters of `GET /api/results`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StreamQuery {
+    /// Send the results and averages as HTML fragments rather than JSON.
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub html: Option<bool>,
+    #[serde(flatten)]
+    pub filters: ResultsQuery,
+}
+
+impl StreamQuery {
+    pub fn url(&self) -> String {
+        format!(
+            "/api/results/stream?{}",
+            serde_urlencoded::to_string(self).unwrap_or_default()
+        )
+    }
+}
+
+/// Data of the `summary` events when JSON is streamed, in microseconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct SummaryEvent {
+    pub wasm: RuntimeSummary,
+    pub docker: RuntimeSummary,
+}
+
+/// Streams the results matching the query parameters as they're recorded.
+///
+/// Each batch of results is sent as a `result` event per result, with the [`FunctionResult`] as
+/// JSON, followed by a `summary` event with a [`SummaryEvent`]. With `html=true` a batch is sent
+/// as a single `result` event with the results fragment, newest first, and the `summary` event
+/// holds the averages fragment, for the htmx `sse` extension. Fails with `400 invalid_request`
+/// if a parameter can't be parsed.
+pub async fn stream_results(
+    State(state): State<Arc<AppState>>,
+    format: ResponseFormat,
+    query: Result<Query<StreamQuery>, QueryRejection>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, ApiError> {
+    let Query(query) = query.map_err(|rejection| {
+        ApiError::invalid_request(rejection.body_text()).with_format(format)
+    })?;
+    let html = query.html.unwrap_or(false);
+    let filter = query
+        .filters
+        .filter()
+        .map_err(|err| err.with_format(format))?;
+    let mut receiver = state.live.subscribe();
+    let history = state.history.clone();
+
+    let events = async_stream::stream! {
+        loop {
+            let batch = match receiver.recv().await {
+                Ok(batch) => batch,
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("results stream fell behind, skipped {} batches", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let mut results: Vec<FunctionResult> = batch
+                .results
+                .iter()
+                .filter(|result| filter.matches(result))
+                .cloned()
+                .collect();
+            if results.is_empty() {
+                continue;
+            }
+
+            if html {
+                results.reverse();
+                yield render_event("result", &results_fragment(results));
+            } else {
+                for result in &results {
+                    yield Event::default().event("result").json_data(result);
+                }
+            }
+
+            match batch.summary(&history, &filter).await {
+                Some((wasm, docker)) if html => {
+                    yield render_event("summary", &ResultsSummary::new(wasm, docker));
+                }
+                Some((wasm, docker)) => {
+                    yield Event::default()
+                        .event("summary")
+                        .json_data(SummaryEvent { wasm, docker });
+                }
+                None => {}
+            }
+        }
+    };
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// The results, without the averages or the next page.
+fn results_fragment(function_results: Vec<FunctionResult>) -> FCList {
+    FCList {
+        function_results,
+        next_url: None,
+        stream_url: None,
+        total_wasm_invocations: 0,
+        total_docker_invocations: 0,
+        avg_wasm_startup: 0,
+        avg_wasm_total_time: 0,
+        avg_wasm_runtime: 0,
+        avg_docker_startup: 0,
+        avg_docker_runtime: 0,
+        avg_docker_total_time: 0,
+    }
+}
+
+fn render_event(name: &str, template: &impl Template) -> Result<Event, axum::Error> {
+    let html = template.render().map_err(axum::Error::new)?;
+    Ok(Event::default().event(name).data(html))
+}
+
+async fn summarize(
+    history: History,
+    filter: ResultFilter,
+) -> anyhow::Result<(RuntimeSummary, RuntimeSummary)> {
+    tokio::task::spawn_blocking(move || {
+        Ok((
+            history.summary(ModuleType::Wasm, &filter)?,
+            history.summary(ModuleType::Docker, &filter)?,
+        ))
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[tokio::test]
+    async fn summarizes_a_batch_once_per_filter() {
+        let dir = TempDir::new("live");
+        let history = History::open(&dir.join("history.db"), &dir.join("data.json")).unwrap();
+        history
+            .insert(&[FunctionResult::test_fixture(100, 1_000)])
+            .unwrap();
+
+        let batch = Batch::default();
+        let all = ResultFilter::default();
+        let first = batch.summary(&history, &all).await.unwrap();
+
+        history
+            .insert(&[FunctionResult::test_fixture(300, 1_000)])
+            .unwrap();
+        assert_eq!(batch.summary(&history, &all).await.unwrap(), first);
+
+        let wasm = ResultFilter {
+            module_type: Some(ModuleType::Wasm),
+            ..Default::default()
+        };
+        assert_ne!(batch.summary(&history, &wasm).await.unwrap(), first);
+    }
+
+    #[test]
+    fn parses_the_format_with_the_filters() {
+        let Query(query) = Query::<StreamQuery>::try_from_uri(
+            &"/api/results/stream?html=true&module_type=Docker&function_name="
+                .parse()
+                .unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(query.html, Some(true));
+        assert_eq!(query.filters.module_type, Some(ModuleType::Docker));
+        assert_eq!(query.filters.function_name, None);
+        assert_eq!(
+            query.url(),
+            "/api/results/stream?html=true&module_type=Docker"
+        );
+    }
+}
@@ -1,39 +1,76 @@
-use clap::{arg, Parser};
+use clap::{Parser, Subcommand};
 use std::{
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
-use tokio::{net::TcpListener, sync::Mutex};
+use tokio::net::TcpListener;
 use tower_livereload::LiveReloadLayer;
 // use tower_livereload::LiveReloadLayer;
 
 use anyhow::Context;
 use axum::{
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{any, get, post, put},
     Router,
 };
+use nebula_lib::{deploy::DeployDirs, module_store::ModuleStore, runtime::RuntimeRegistry};
 use nebula_server::{
-    api::call_function::{call_function, call_function_headless},
-    components::function_results::get_function_results,
+    api::{
+        call_function::call_function,
+        compare::compare,
+        deploy::{deploy_function, MAX_MODULE_SIZE},
+        docker_images::{get_docker_images, provision_docker_images},
+        fan_out::fan_out,
+        http_trigger::invoke_http,
+        jobs::{get_job, get_job_results, list_jobs, submit_job},
+        manifests::get_manifest,
+        pipelines::{delete_pipeline, get_pipeline, invoke_pipeline, list_pipelines, put_pipeline},
+        results::get_results,
+        schedules::{
+            create_schedule, delete_schedule, get_schedule, list_schedules, pause_schedule,
+            resume_schedule,
+        },
+        usage::get_usage,
+        versions::{get_versions, rollback, set_alias},
+    },
+    api_keys::{require_api_key, spawn_usage_writer, ApiKeys, KeysFile, Quota},
+    concurrency::{parse_function_limit, ConcurrencyLimits, LimitsConfig},
+    config::{Dirs, DirsConfig},
+    history::History,
+    jobs::{spawn_workers, JobQueue},
+    live::stream_results,
     models::AppState,
-    pages::{about, docker_page, index, metrics, wasm_page},
-    utilities::{persist::load_results, serialize_modules::serialize_modules},
+    mqtt::{default_client_id, spawn_mqtt, MqttConfig, MqttTrigger},
+    pages::{about, admin, compare_page, docker_page, index, metrics, wasm_page},
+    pipelines::Pipelines,
+    prometheus::get_prometheus,
+    schedules::{spawn_scheduler, Schedules},
+    telemetry::{self, TraceExport},
+    utilities::{provision_images::provision_images, serialize_modules::serialize_modules},
+    watch::{spawn_module_watcher, stream_module_events},
 };
-use tower_http::services::ServeDir;
-use tracing::info;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tower_http::{
+    services::ServeDir,
+    trace::{DefaultMakeSpan, TraceLayer},
+};
+use tracing::{info, Level};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let options = ServerArgs::parse();
+    let dirs = options.dirs()?;
 
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "nebula_server=debug,tower_livereload=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    if let Some(Command::Keys { command }) = options.command {
+        return manage_keys(&dirs, command);
+    }
+
+    telemetry::init(&TraceExport {
+        otlp_endpoint: options.otlp_endpoint.clone(),
+        file: options.trace_file.clone(),
+    })?;
 
     // Leave out https redirect for now
     //
@@ -48,32 +85,154 @@ async fn main() -> anyhow::Result<()> {
 
     info!("initializing router...");
 
-    let api_router = Router::new()
-        .route("/results", get(get_function_results))
-        .route("/wasm", post(call_function))
-        .route("/wasm_headless", post(call_function_headless))
-        .route("/docker", post(call_function))
-        .route("/wasm/all", post(call_function));
+    dirs.create()?;
+    info!("keeping data in {:?}", dirs.data_dir);
 
-    let stored_function_calls = match load_results() {
-        Ok(func_calls) => func_calls,
-        Err(_) => vec![],
-    };
+    let history =
+        History::open(&dirs.history_path(), &dirs.data_path()).context("opening the history")?;
+
+    let modules = ModuleStore::open(
+        DeployDirs {
+            serialized_dir: dirs.serialized_dir(),
+            archive_dir: dirs.module_archive_dir(),
+        },
+        dirs.module_registry_path(),
+    )?;
 
-    serialize_modules();
+    let store = modules.clone();
+    let wasm_module_dir = dirs.wasm_module_dir.clone();
+    tokio::task::spawn_blocking(move || serialize_modules(&store, &wasm_module_dir)).await?;
+
+    let docker_module_dir = dirs.docker_module_dir.clone();
+    tokio::task::spawn_blocking(move || provision_images(&docker_module_dir)).await?;
+
+    if options.deploy_token.is_none() {
+        info!("no deploy token set, deploying functions is disabled");
+    }
+
+    let (jobs, job_receiver) = JobQueue::new(dirs.jobs_path(), options.job_queue_size);
+
+    let anonymous = Quota {
+        invocations_per_minute: options.anonymous_invocations_per_minute,
+        compute_seconds_per_day: options.anonymous_compute_seconds_per_day,
+    };
+    let api_keys = ApiKeys::new(
+        dirs.api_keys_path(),
+        dirs.usage_path(),
+        (!anonymous.is_unlimited()).then_some(anonymous),
+    );
+    if !api_keys.enabled() {
+        info!("no API keys configured, the API is open to everyone");
+    }
 
     let app_state = Arc::new(AppState {
-        function_calls: Mutex::new(stored_function_calls),
+        history,
+        prometheus: Default::default(),
+        live: Default::default(),
+        module_events: Default::default(),
+        runtimes: RuntimeRegistry::with_defaults(modules.clone()),
+        modules,
+        deploy_token: options.deploy_token,
+        jobs,
+        schedules: Schedules::load(dirs.schedules_path()),
+        pipelines: Pipelines::load(dirs.pipelines_path()),
+        limits: ConcurrencyLimits::new(LimitsConfig {
+            max_concurrency: options.max_concurrency,
+            max_concurrency_per_function: options.max_concurrency_per_function,
+            function_limits: options.function_limit.into_iter().collect(),
+            max_queued: options.max_queued,
+        }),
+        api_keys,
+        dirs,
     });
 
+    let api_router = Router::new()
+        .route("/results", get(get_results))
+        .route("/results/stream", get(stream_results))
+        .route("/prometheus", get(get_prometheus))
+        .route("/wasm", post(call_function))
+        .route("/wasm_headless", post(call_function))
+        .route("/docker", post(call_function))
+        .route("/docker/images", get(get_docker_images))
+        .route("/docker/provision", post(provision_docker_images))
+        .route("/wasm/all", post(fan_out))
+        .route("/compare", post(compare))
+        .route(
+            "/wasm/deploy",
+            post(deploy_function).layer(DefaultBodyLimit::max(MAX_MODULE_SIZE)),
+        )
+        .route("/wasm/:name/versions", get(get_versions))
+        .route("/wasm/:name/aliases/:alias", put(set_alias))
+        .route("/wasm/:name/rollback", post(rollback))
+        .route("/functions/:name/manifest", get(get_manifest))
+        .route("/modules/stream", get(stream_module_events))
+        .route("/jobs", get(list_jobs).post(submit_job))
+        .route("/jobs/:id", get(get_job))
+        .route("/jobs/:id/results", get(get_job_results))
+        .route("/schedules", get(list_schedules).post(create_schedule))
+        .route("/schedules/:id", get(get_schedule).delete(delete_schedule))
+        .route("/schedules/:id/pause", post(pause_schedule))
+        .route("/schedules/:id/resume", post(resume_schedule))
+        .route("/pipelines", get(list_pipelines))
+        .route(
+            "/pipelines/:name",
+            get(get_pipeline).put(put_pipeline).delete(delete_pipeline),
+        )
+        .route("/pipelines/:name/run", post(invoke_pipeline))
+        .route("/admin/usage", get(get_usage))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            require_api_key,
+        ));
+
+    let fn_router = Router::new()
+        .route("/:name", any(invoke_http))
+        .route("/:name/*path", any(invoke_http))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            require_api_key,
+        ));
+
+    spawn_workers(app_state.clone(), job_receiver, options.workers);
+    spawn_scheduler(app_state.clone());
+    spawn_usage_writer(app_state.clone());
+    if options.watch_interval > 0 {
+        spawn_module_watcher(
+            app_state.clone(),
+            Duration::from_secs(options.watch_interval),
+        );
+    }
+    if let Some(broker) = options.mqtt_broker {
+        spawn_mqtt(
+            app_state.clone(),
+            MqttConfig {
+                broker,
+                client_id: options
+                    .mqtt_client_id
+                    .unwrap_or_else(|| default_client_id(&app_state.dirs.data_dir)),
+                credentials: options.mqtt_username.zip(options.mqtt_password),
+                api_key: options.mqtt_api_key,
+                triggers: options.mqtt_trigger,
+            },
+        )
+        .context("connecting to the MQTT broker")?;
+    }
+    let state = app_state.clone();
+    tokio::spawn(async move { state.jobs.requeue_unfinished().await });
+    let state = app_state.clone();
+
     let mut router = Router::new()
         .nest("/api", api_router)
+        .nest("/fn", fn_router)
         .route("/", get(index::home))
         .route("/about", get(about::about))
         .route("/metrics", get(metrics::metrics))
+        .route("/admin", get(admin::admin))
         .route("/wasm", get(wasm_page::wasm))
         .route("/docker", get(docker_page::docker))
+        .route("/compare", get(compare_page::compare))
         .nest_service("/assets", ServeDir::new(options.assets_path))
+        .layer(TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::new().level(Level::INFO)))
         .with_state(app_state);
 
     #[cfg(debug_assertions)]
@@ -92,9 +251,15 @@ async fn main() -> anyhow::Result<()> {
         .unwrap();
 
     axum::serve(listener, router.into_make_service())
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
         .await
         .context("error while starting server")?;
 
+    tokio::task::spawn_blocking(move || state.api_keys.save_usage()).await?;
+    telemetry::shutdown();
+
     Ok(())
 }
 
@@ -111,4 +276,200 @@ pub struct ServerArgs {
     /// Asset location
     #[arg(short = 'a', long, default_value = "./assets")]
     pub assets_path: String,
+
+    /// TOML file setting `data_dir`, `wasm_module_dir` and `docker_module_dir`, which the flags
+    /// below override.
+    #[arg(short = 'c', long, env = "NEBULA_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Directory for deployed functions, API keys, history and other state [default: ~/.nebula]
+    #[arg(long, env = "NEBULA_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Directory of Wasm modules imported on startup [default: ~/modules/wasm]
+    #[arg(long, env = "NEBULA_WASM_MODULE_DIR")]
+    pub wasm_module_dir: Option<PathBuf>,
+
+    /// Directory of Docker image archives loaded on startup [default: ~/modules/docker]
+    #[arg(long, env = "NEBULA_DOCKER_MODULE_DIR")]
+    pub docker_module_dir: Option<PathBuf>,
+
+    /// Seconds between checks of the module directories for added, changed or deleted modules,
+    /// 0 only loads them on startup.
+    #[arg(long, default_value = "2")]
+    pub watch_interval: u64,
+
+    /// Token required by `/api/wasm/deploy`, deploying is disabled if it isn't set.
+    #[arg(long, env = "NEBULA_DEPLOY_TOKEN", hide_env_values = true)]
+    pub deploy_token: Option<String>,
+
+    /// Workers running asynchronous invocations.
+    #[arg(long, default_value = "4")]
+    pub workers: usize,
+
+    /// Asynchronous invocations that can be queued before new ones are rejected.
+    #[arg(long, default_value = "256")]
+    pub job_queue_size: usize,
+
+    /// Invocations running at once across all functions.
+    #[arg(long, default_value = "16")]
+    pub max_concurrency: usize,
+
+    /// Invocations of a single function running at once, unlimited if not set.
+    #[arg(long)]
+    pub max_concurrency_per_function: Option<usize>,
+
+    /// Concurrency limit for one function as `name=limit`, can be repeated.
+    #[arg(long, value_parser = parse_function_limit)]
+    pub function_limit: Vec<(String, usize)>,
+
+    /// Invocations waiting for a free slot before new ones are rejected with 429.
+    #[arg(long, default_value = "64")]
+    pub max_queued: usize,
+
+    /// Invocations per minute shared by requests without an API key. Requests without a key are
+    /// rejected once keys are configured, unless an anonymous quota is set.
+    #[arg(long)]
+    pub anonymous_invocations_per_minute: Option<u32>,
+
+    /// Compute seconds per day shared by requests without an API key.
+    #[arg(long)]
+    pub anonymous_compute_seconds_per_day: Option<u64>,
+
+    /// OTLP/gRPC endpoint to export traces to, e.g. http://localhost:4317.
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// File to append traces to as JSON lines, if no OTLP endpoint is set.
+    #[arg(long, env = "NEBULA_TRACE_FILE")]
+    pub trace_file: Option<PathBuf>,
+
+    /// MQTT broker to subscribe to for `--mqtt-trigger`s, as `host` or `host:port`.
+    #[arg(long, env = "NEBULA_MQTT_BROKER")]
+    pub mqtt_broker: Option<String>,
+
+    /// Credentials for the MQTT broker.
+    #[arg(long, env = "NEBULA_MQTT_USERNAME", requires = "mqtt_password")]
+    pub mqtt_username: Option<String>,
+
+    #[arg(long, env = "NEBULA_MQTT_PASSWORD", hide_env_values = true)]
+    pub mqtt_password: Option<String>,
+
+    /// Client id to connect to the MQTT broker with. Defaults to one derived from the data
+    /// directory with a random suffix, so servers sharing a broker don't disconnect each other.
+    #[arg(long, env = "NEBULA_MQTT_CLIENT_ID", requires = "mqtt_broker")]
+    pub mqtt_client_id: Option<String>,
+
+    /// Name of the API key MQTT messages invoke functions on behalf of, within its quota. Without
+    /// one they share the anonymous quota, and are rejected if API keys are configured without
+    /// an anonymous quota.
+    #[arg(long, env = "NEBULA_MQTT_API_KEY", requires = "mqtt_broker")]
+    pub mqtt_api_key: Option<String>,
+
+    /// Invokes a Wasm function with the payload of every message on a topic, as
+    /// `topic=function[,reply_topic]`. The topic may contain `+` and `#` wildcards, the outcome
+    /// is published to the reply topic.
+    #[arg(long, requires = "mqtt_broker")]
+    pub mqtt_trigger: Vec<MqttTrigger>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl ServerArgs {
+    /// The directories from the flags, then the config file, then the defaults.
+    fn dirs(&self) -> anyhow::Result<Dirs> {
+        let file = match &self.config {
+            Some(path) => DirsConfig::load(path)?,
+            None => DirsConfig::default(),
+        };
+
+        DirsConfig {
+            data_dir: self.data_dir.clone(),
+            wasm_module_dir: self.wasm_module_dir.clone(),
+            docker_module_dir: self.docker_module_dir.clone(),
+        }
+        .or(file)
+        .resolve()
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage the API keys in keys.json in the data directory, a running server picks up changes
+    /// right away.
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeysCommand {
+    /// Add a key and print it, it can't be shown again.
+    Add {
+        name: String,
+        /// Invocations the key may start per minute.
+        #[arg(long)]
+        invocations_per_minute: Option<u32>,
+        /// Seconds of function runtime the key may use per day.
+        #[arg(long)]
+        compute_seconds_per_day: Option<u64>,
+        /// Allow the key to see the usage of every key.
+        #[arg(long)]
+        admin: bool,
+    },
+    /// List the keys and their quotas.
+    List,
+    /// Remove a key.
+    Remove { name: String },
+}
+
+fn manage_keys(dirs: &Dirs, command: KeysCommand) -> anyhow::Result<()> {
+    let path = dirs.api_keys_path();
+    let mut file = KeysFile::load(&path)?;
+
+    match command {
+        KeysCommand::Add {
+            name,
+            invocations_per_minute,
+            compute_seconds_per_day,
+            admin,
+        } => {
+            let quota = Quota {
+                invocations_per_minute,
+                compute_seconds_per_day,
+            };
+            let key = file.add(&name, quota, admin)?;
+            file.save(&path)?;
+            println!("{}", key);
+        }
+        KeysCommand::List => {
+            for key in &file.keys {
+                let per_minute = key
+                    .quota
+                    .invocations_per_minute
+                    .map_or("unlimited".to_string(), |limit| limit.to_string());
+                let per_day = key
+                    .quota
+                    .compute_seconds_per_day
+                    .map_or("unlimited".to_string(), |limit| format!("{}s", limit));
+                println!(
+                    "{}{}\tinvocations/minute: {}\tcompute/day: {}",
+                    key.name,
+                    if key.admin { " (admin)" } else { "" },
+                    per_minute,
+                    per_day
+                );
+            }
+        }
+        KeysCommand::Remove { name } => {
+            if !file.remove(&name) {
+                anyhow::bail!("no key named {:?}", name);
+            }
+            file.save(&path)?;
+        }
+    }
+
+    Ok(())
 }
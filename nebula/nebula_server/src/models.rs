@@ -1,19 +1,52 @@
 use askama::Template;
-use nebula_lib::models::{FunctionResult, ModuleType};
-use serde::Deserialize;
-use tokio::sync::Mutex;
+use nebula_lib::{
+    models::{FunctionResult, ModuleType, Trigger},
+    module_store::ModuleStore,
+    runtime::RuntimeRegistry,
+};
+use serde::{Deserialize, Serialize};
 
-use crate::utilities::format::format_micro_to_milli;
+use crate::{
+    api_keys::ApiKeys,
+    concurrency::ConcurrencyLimits,
+    config::Dirs,
+    history::{History, RuntimeSummary},
+    jobs::JobQueue,
+    live::LiveResults,
+    pipelines::Pipelines,
+    prometheus::InvocationMetrics,
+    schedules::Schedules,
+    utilities::format::format_micro_to_milli,
+    watch::ModuleEvents,
+};
 
 #[derive(Debug)]
 pub struct AppState {
-    pub function_calls: Mutex<Vec<FunctionResult>>,
+    pub history: History,
+    pub prometheus: InvocationMetrics,
+    pub live: LiveResults,
+    pub module_events: ModuleEvents,
+    pub runtimes: RuntimeRegistry,
+    pub modules: ModuleStore,
+    pub jobs: JobQueue,
+    pub schedules: Schedules,
+    pub pipelines: Pipelines,
+    pub limits: ConcurrencyLimits,
+    pub api_keys: ApiKeys,
+    pub dirs: Dirs,
+    /// Token required to deploy functions, deploying is disabled without one.
+    pub deploy_token: Option<String>,
 }
 
 #[derive(Template, Debug)]
 #[template(path = "components/function_results.rs.html")]
 pub struct FCList {
     pub function_results: Vec<FunctionResult>,
+    /// Where to load the next page from, `None` on the last page.
+    pub next_url: Option<String>,
+    /// Where the first page subscribes to the averages and the results as they come in, `None`
+    /// on the pages after it.
+    pub stream_url: Option<String>,
     pub total_wasm_invocations: usize,
     pub total_docker_invocations: usize,
     pub avg_wasm_startup: u128,
@@ -30,7 +63,40 @@ impl FCList {
     }
 }
 
-#[derive(Deserialize, Clone)]
+/// The averages above the results, sent on their own as invocations come in.
+#[derive(Template, Debug, Default)]
+#[template(path = "components/results_summary.rs.html")]
+pub struct ResultsSummary {
+    pub total_wasm_invocations: usize,
+    pub total_docker_invocations: usize,
+    pub avg_wasm_startup: u128,
+    pub avg_wasm_total_time: u128,
+    pub avg_wasm_runtime: u128,
+    pub avg_docker_startup: u128,
+    pub avg_docker_runtime: u128,
+    pub avg_docker_total_time: u128,
+}
+
+impl ResultsSummary {
+    pub fn new(wasm: RuntimeSummary, docker: RuntimeSummary) -> Self {
+        ResultsSummary {
+            total_wasm_invocations: wasm.invocations,
+            total_docker_invocations: docker.invocations,
+            avg_wasm_startup: wasm.avg_startup,
+            avg_wasm_runtime: wasm.avg_runtime,
+            avg_wasm_total_time: wasm.avg_total_time,
+            avg_docker_startup: docker.avg_startup,
+            avg_docker_runtime: docker.avg_runtime,
+            avg_docker_total_time: docker.avg_total_time,
+        }
+    }
+
+    fn format_time(&self, time: &u128) -> String {
+        format_micro_to_milli(*time)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct FunctionRequest {
     pub function_name: String,
     pub input: String,
@@ -39,17 +105,15 @@ pub struct FunctionRequest {
     pub num_calls: u8,
     #[serde(default = "default_image")]
     pub base_image: String,
+    /// Set by the server, recorded in every result.
+    #[serde(skip)]
+    pub trigger: Trigger,
 }
 
 fn default_num_calls() -> u8 {
     1
 }
 
-fn default_image() -> String {
+pub(crate) fn default_image() -> String {
     "debian".to_string()
 }
-
-pub fn verify_image(image: &str) -> bool {
-    let valid_images = ["debian", "ubuntu", "archlinux"];
-    valid_images.contains(&image)
-}
@@ -0,0 +1,408 @@
+//! MQTT triggers.
+//!
+//! With `--mqtt-broker`, the server subscribes to the topic filter of every `--mqtt-trigger` and
+//! invokes the mapped Wasm function once per message, with the payload as input. A trigger with a
+//! reply topic publishes the outcome there, with the body `/api/wasm` would respond with:
+//! `{"results": [...]}`, or `{"error": {...}}` if the function couldn't be invoked. Results are
+//! recorded with [`Trigger::Mqtt`]. With API keys, messages count against the quota of the key
+//! named by `--mqtt-api-key`, or the anonymous quota without one.
+
+use std::{path::Path, str::FromStr, sync::Arc, time::Duration};
+
+use nebula_lib::models::{FunctionResult, ModuleType, Trigger};
+use rumqttc::{
+    matches, valid_filter, valid_topic, AsyncClient, Event, MqttOptions, Packet, QoS,
+    SubscribeFilter,
+};
+use sha2::{Digest, Sha256};
+use tracing::{info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        call_function::{run_request, InvocationResponse},
+        error::{ApiError, ErrorBody, ErrorResponse},
+    },
+    models::{AppState, FunctionRequest},
+};
+
+const DEFAULT_PORT: u16 = 1883;
+/// Requests, subscriptions and replies, waiting for the event loop.
+const CHANNEL_CAPACITY: usize = 64;
+/// Wait before reconnecting to the broker after the connection failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A `topic=function[,reply_topic]` mapping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttTrigger {
+    /// Topic filter, with `+` and `#` wildcards.
+    pub filter: String,
+    /// Function name, optionally with a version or alias after an `@`.
+    pub function_name: String,
+    pub reply_topic: Option<String>,
+}
+
+impl FromStr for MqttTrigger {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (filter, function) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected topic=function[,reply_topic], got {:?}", value))?;
+        let (function_name, reply_topic) = match function.split_once(',') {
+            Some((function_name, reply_topic)) => (function_name, Some(reply_topic)),
+            None => (function, None),
+        };
+
+        if !valid_filter(filter) {
+            return Err(format!("invalid topic filter {:?}", filter));
+        }
+        if function_name.is_empty() {
+            return Err(format!("missing function for {}", filter));
+        }
+        if let Some(reply_topic) = reply_topic.filter(|topic| !valid_topic(topic)) {
+            return Err(format!("invalid reply topic {:?}", reply_topic));
+        }
+        // Each reply would trigger the function again.
+        if let Some(reply_topic) = reply_topic.filter(|topic| matches(topic, filter)) {
+            return Err(format!("reply topic {:?} matches {}", reply_topic, filter));
+        }
+
+        Ok(MqttTrigger {
+            filter: filter.to_string(),
+            function_name: function_name.to_string(),
+            reply_topic: reply_topic.map(str::to_string),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    /// `host` or `host:port`.
+    pub broker: String,
+    /// Has to be unique among the clients of the broker, which disconnects the older of two
+    /// clients with the same id.
+    pub client_id: String,
+    pub credentials: Option<(String, String)>,
+    /// Name of the API key the functions are invoked on behalf of.
+    pub api_key: Option<String>,
+    pub triggers: Vec<MqttTrigger>,
+}
+
+impl MqttConfig {
+    fn options(&self) -> anyhow::Result<MqttOptions> {
+        let (host, port) = match self.broker.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse()?),
+            None => (self.broker.as_str(), DEFAULT_PORT),
+        };
+
+        let mut options = MqttOptions::new(&self.client_id, host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some((username, password)) = &self.credentials {
+            options.set_credentials(username, password);
+        }
+
+        Ok(options)
+    }
+}
+
+/// A client id for the server with the data directory `data_dir`: a hash of the directory and a
+/// random suffix, 22 characters, which every broker accepts.
+pub fn default_client_id(data_dir: &Path) -> String {
+    let dir_hash = Sha256::digest(data_dir.to_string_lossy().as_bytes());
+    let suffix = Uuid::new_v4().simple().to_string();
+
+    format!("nebula-{}-{}", hex::encode(&dir_hash[..4]), &suffix[..6])
+}
+
+/// Connects to the broker and starts the task invoking the triggers' functions. The connection is
+/// retried, and the topics subscribed to again, whenever it fails.
+pub fn spawn_mqtt(state: Arc<AppState>, config: MqttConfig) -> anyhow::Result<()> {
+    let (client, mut event_loop) = AsyncClient::new(config.options()?, CHANNEL_CAPACITY);
+    let triggers = Arc::new(config.triggers);
+    let api_key = config.api_key.map(Arc::<str>::from);
+
+    tokio::spawn(async move {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("connected to the MQTT broker at {}", config.broker);
+                    let filters = triggers
+                        .iter()
+                        .map(|trigger| {
+                            SubscribeFilter::new(trigger.filter.clone(), QoS::AtLeastOnce)
+                        })
+                        .collect::<Vec<_>>();
+                    // The event loop has to keep running to send the subscription.
+                    let client = client.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = client.subscribe_many(filters).await {
+                            warn!("failed to subscribe to the MQTT triggers: {}", err);
+                        }
+                    });
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let input = String::from_utf8_lossy(&publish.payload).into_owned();
+
+                    for trigger in triggers
+                        .iter()
+                        .filter(|trigger| matches(&publish.topic, &trigger.filter))
+                    {
+                        let span = info_span!("mqtt", topic = %publish.topic);
+                        tokio::spawn(
+                            handle_message(
+                                state.clone(),
+                                client.clone(),
+                                trigger.clone(),
+                                api_key.clone(),
+                                input.clone(),
+                            )
+                            .instrument(span),
+                        );
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!("MQTT connection to {} failed: {}", config.broker, err);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+async fn handle_message(
+    state: Arc<AppState>,
+    client: AsyncClient,
+    trigger: MqttTrigger,
+    api_key: Option<Arc<str>>,
+    input: String,
+) {
+    let request = FunctionRequest {
+        function_name: trigger.function_name.clone(),
+        input,
+        module_type: ModuleType::Wasm,
+        num_calls: 1,
+        base_image: String::new(),
+        trigger: Trigger::Mqtt,
+    };
+    let outcome = match state.api_keys.caller_named(api_key.as_deref()) {
+        Ok(caller) => run_request(&state, caller.as_ref(), request).await,
+        Err(err) => Err(err),
+    };
+
+    if let Err(err) = &outcome {
+        warn!(
+            "failed to invoke {} for an MQTT message: {}",
+            trigger.function_name, err.message
+        );
+    }
+
+    if let Some(reply_topic) = trigger.reply_topic {
+        if let Err(err) = client
+            .publish(reply_topic, QoS::AtLeastOnce, false, reply_payload(outcome))
+            .await
+        {
+            warn!("failed to publish the MQTT reply: {}", err);
+        }
+    }
+}
+
+fn reply_payload(outcome: Result<Vec<FunctionResult>, ApiError>) -> Vec<u8> {
+    let reply = match outcome {
+        Ok(results) => serde_json::to_vec(&InvocationResponse { results }),
+        Err(err) => serde_json::to_vec(&ErrorResponse {
+            error: ErrorBody {
+                code: err.code.to_string(),
+                message: err.message,
+            },
+        }),
+    };
+
+    reply.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        api_keys::{KeysFile, Quota},
+        test_utils::{app_state, TempDir},
+    };
+
+    /// Prints `pong`.
+    const PONG: &str = r#"
+        (module
+          (import "wasi_snapshot_preview1" "fd_write"
+            (func $fd_write (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 16) "pong")
+          (func (export "_start")
+            (i32.store (i32.const 0) (i32.const 16))
+            (i32.store (i32.const 4) (i32.const 4))
+            (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))
+    "#;
+
+    /// Reads an MQTT packet, returning its first byte and what follows the length.
+    async fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let header = stream.read_u8().await.unwrap();
+        let (mut length, mut shift) = (0, 0);
+        loop {
+            let byte = stream.read_u8().await.unwrap();
+            length |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+        (header, body)
+    }
+
+    /// Plays the broker: accepts the connection and subscription, publishes `payload` to `topic`
+    /// and returns the topic and payload of the first message published back.
+    async fn broker_round_trip(
+        broker: TcpListener,
+        topic: &str,
+        payload: &str,
+    ) -> (String, String) {
+        let (mut stream, _) = broker.accept().await.unwrap();
+
+        let (connect, _) = read_packet(&mut stream).await;
+        assert_eq!(connect >> 4, 1);
+        stream.write_all(&[0x20, 2, 0, 0]).await.unwrap();
+
+        let (subscribe, body) = read_packet(&mut stream).await;
+        assert_eq!(subscribe >> 4, 8);
+        stream
+            .write_all(&[0x90, 3, body[0], body[1], 1])
+            .await
+            .unwrap();
+
+        let mut publish = vec![0x30, (2 + topic.len() + payload.len()) as u8, 0];
+        publish.push(topic.len() as u8);
+        publish.extend_from_slice(topic.as_bytes());
+        publish.extend_from_slice(payload.as_bytes());
+        stream.write_all(&publish).await.unwrap();
+
+        loop {
+            let (header, body) = read_packet(&mut stream).await;
+            if header >> 4 != 3 {
+                continue;
+            }
+
+            let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+            let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+            // QoS 1 messages carry a packet id, which is acknowledged.
+            let mut payload_start = 2 + topic_len;
+            if (header >> 1) & 3 == 1 {
+                let packet_id = &body[payload_start..payload_start + 2];
+                stream
+                    .write_all(&[0x40, 2, packet_id[0], packet_id[1]])
+                    .await
+                    .unwrap();
+                payload_start += 2;
+            }
+
+            return (
+                topic,
+                String::from_utf8(body[payload_start..].to_vec()).unwrap(),
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_with_the_results_of_the_function() {
+        let dir = TempDir::new("mqtt");
+        let state = app_state(&dir);
+        state.modules.deploy("pong", None, PONG.as_bytes()).unwrap();
+
+        let broker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        spawn_mqtt(
+            state.clone(),
+            MqttConfig {
+                broker: broker.local_addr().unwrap().to_string(),
+                client_id: default_client_id(&state.dirs.data_dir),
+                credentials: None,
+                api_key: None,
+                triggers: vec!["sensors/+/ping=pong,sensors/pong".parse().unwrap()],
+            },
+        )
+        .unwrap();
+
+        let (topic, reply) = tokio::time::timeout(
+            Duration::from_secs(30),
+            broker_round_trip(broker, "sensors/kitchen/ping", "ping"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(topic, "sensors/pong");
+
+        let reply: InvocationResponse = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply.results[0].result, "pong");
+        assert_eq!(reply.results[0].input, "ping");
+        assert_eq!(reply.results[0].trigger, Trigger::Mqtt);
+
+        // With API keys, messages run on behalf of `--mqtt-api-key` and count against its quota.
+        let mut keys = KeysFile::default();
+        keys.add("sensors", Quota::default(), false).unwrap();
+        keys.save(&state.dirs.api_keys_path()).unwrap();
+        let (client, _) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 1);
+        let trigger: MqttTrigger = "sensors/+/ping=pong".parse().unwrap();
+        let handle = |api_key: Option<&str>| {
+            handle_message(
+                state.clone(),
+                client.clone(),
+                trigger.clone(),
+                api_key.map(Arc::from),
+                "ping".to_string(),
+            )
+        };
+        handle(None).await;
+        handle(Some("sensors")).await;
+        assert_eq!(state.api_keys.report()[0].usage.total_invocations, 1);
+    }
+
+    #[test]
+    fn parses_triggers_and_replies() {
+        let trigger: MqttTrigger = "sensors/+/temperature=convert@stable,readings/celsius"
+            .parse()
+            .unwrap();
+        assert_eq!(trigger.function_name, "convert@stable");
+        assert_eq!(trigger.reply_topic.as_deref(), Some("readings/celsius"));
+        assert!(matches("sensors/kitchen/temperature", &trigger.filter));
+
+        let trigger: MqttTrigger = "events/#=log".parse().unwrap();
+        assert_eq!(trigger.reply_topic, None);
+
+        for invalid in [
+            "events",
+            "events/#/more=log",
+            "events=",
+            "events=log,replies/+",
+            "events/#=log,events/out",
+        ] {
+            assert!(invalid.parse::<MqttTrigger>().is_err(), "{}", invalid);
+        }
+
+        let reply = reply_payload(Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "function_not_found",
+            "Unknown wasm function log",
+        )));
+        assert_eq!(
+            String::from_utf8(reply).unwrap(),
+            r#"{"error":{"code":"function_not_found","message":"Unknown wasm function log"}}"#
+        );
+    }
+}
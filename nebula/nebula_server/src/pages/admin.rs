@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    extract::State,
+    http::{header::WWW_AUTHENTICATE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use crate::{api_keys::KeyReport, models::AppState, utilities::html_template::HtmlTemplate};
+
+#[derive(Template)]
+#[template(path = "pages/admin.rs.html")]
+pub struct AdminTemplate {
+    pub keys: Vec<KeyReport>,
+    pub keys_enabled: bool,
+}
+
+impl AdminTemplate {
+    fn format_seconds(&self, micros: &u128) -> String {
+        format!("{:.1}s", *micros as f64 / 1_000_000.0)
+    }
+}
+
+/// Usage per API key. Browsers are asked for basic auth credentials, with an admin key as the
+/// password, once keys are configured.
+pub async fn admin(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    match state.api_keys.authenticate(&headers) {
+        Ok(None) => HtmlTemplate(AdminTemplate {
+            keys: state.api_keys.report(),
+            keys_enabled: false,
+        })
+        .into_response(),
+        Ok(Some(caller)) if caller.admin => HtmlTemplate(AdminTemplate {
+            keys: state.api_keys.report(),
+            keys_enabled: true,
+        })
+        .into_response(),
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            [(WWW_AUTHENTICATE, "Basic realm=\"Nebula admin\"")],
+            "An admin API key is required",
+        )
+            .into_response(),
+    }
+}
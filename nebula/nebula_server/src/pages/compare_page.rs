@@ -0,0 +1,51 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{extract::State, response::IntoResponse};
+use nebula_lib::docker_images::{group_by_function, list_function_images};
+use tracing::warn;
+
+use crate::{
+    api::compare::MAX_ROUNDS, components::function_input::InputWidget, models::AppState,
+    pages::wasm_page::manifest, utilities::html_template::HtmlTemplate,
+};
+
+#[derive(Template)]
+#[template(path = "pages/compare.rs.html")]
+pub struct CompareTemplate {
+    /// Functions deployed on both runtimes, with their base images joined by commas.
+    pub functions: Vec<(InputWidget, String)>,
+    pub max_rounds: u8,
+}
+
+pub async fn compare(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let images = match tokio::task::spawn_blocking(list_function_images).await {
+        Ok(Ok(images)) => images,
+        Ok(Err(err)) => {
+            warn!("failed to list docker images: {:#}", err);
+            vec![]
+        }
+        Err(err) => {
+            warn!("failed to list docker images: {}", err);
+            vec![]
+        }
+    };
+    let wasm = state.modules.snapshot();
+
+    let template = CompareTemplate {
+        functions: group_by_function(&images)
+            .into_iter()
+            .filter(|(name, _)| {
+                wasm.functions
+                    .get(name)
+                    .is_some_and(|function| function.latest().is_some())
+            })
+            .map(|(name, base_images)| {
+                let widget = InputWidget::new(&name, manifest(&state, &name).as_ref());
+                (widget, base_images.join(", "))
+            })
+            .collect(),
+        max_rounds: MAX_ROUNDS,
+    };
+    HtmlTemplate(template)
+}
@@ -1,30 +1,43 @@
-use std::path::Path;
+use std::sync::Arc;
 
 use askama::Template;
-use axum::response::IntoResponse;
-use nebula_lib::list_files::list_files;
+use axum::{extract::State, response::IntoResponse};
+use nebula_lib::docker_images::{group_by_function, list_function_images};
+use tracing::warn;
+
+use crate::{
+    components::function_input::InputWidget, models::AppState, pages::wasm_page::manifest,
+    utilities::html_template::HtmlTemplate,
+};
 
-use crate::utilities::html_template::HtmlTemplate;
 #[derive(Template)]
 #[template(path = "pages/docker.rs.html")]
 pub struct DockerTemplate {
-    pub images: Vec<String>,
+    /// Base images available for each function.
+    pub functions: Vec<(InputWidget, Vec<String>)>,
 }
 
-pub async fn docker() -> impl IntoResponse {
-    let home_dir = home::home_dir().expect("Home dir not found");
-
-    let wasm_module_dir = home_dir.join("modules/wasm");
-
-    let images =
-        list_files(wasm_module_dir.to_str().unwrap()).expect("there to be modules on the server");
-
-    let images: Vec<String> = images
-        .iter()
-        .filter_map(|path| Path::new(path).file_stem())
-        .map(|name| name.to_str().unwrap().to_string())
-        .collect();
+pub async fn docker(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let images = match tokio::task::spawn_blocking(list_function_images).await {
+        Ok(Ok(images)) => images,
+        Ok(Err(err)) => {
+            warn!("failed to list docker images: {:#}", err);
+            vec![]
+        }
+        Err(err) => {
+            warn!("failed to list docker images: {}", err);
+            vec![]
+        }
+    };
 
-    let template = DockerTemplate { images };
+    let template = DockerTemplate {
+        functions: group_by_function(&images)
+            .into_iter()
+            .map(|(name, base_images)| {
+                let widget = InputWidget::new(&name, manifest(&state, &name).as_ref());
+                (widget, base_images)
+            })
+            .collect(),
+    };
     HtmlTemplate(template)
 }
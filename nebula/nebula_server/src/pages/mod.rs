@@ -1,4 +1,6 @@
 pub mod about;
+pub mod admin;
+pub mod compare_page;
 pub mod docker_page;
 pub mod index;
 pub mod metrics;
@@ -1,29 +1,42 @@
-use std::path::Path;
+use std::sync::Arc;
 
 use askama::Template;
-use axum::response::IntoResponse;
-use nebula_lib::list_files::list_files;
+use axum::{extract::State, response::IntoResponse};
+use nebula_lib::manifest::FunctionManifest;
+use tracing::warn;
 
-use crate::utilities::html_template::HtmlTemplate;
+use crate::{
+    components::function_input::InputWidget, models::AppState,
+    utilities::html_template::HtmlTemplate,
+};
 
 #[derive(Template)]
 #[template(path = "pages/wasm.rs.html")]
 pub struct WasmTemplate {
-    pub modules: Vec<String>,
+    /// Functions with the short version `latest` points at.
+    pub modules: Vec<(InputWidget, String)>,
 }
 
-pub async fn wasm() -> impl IntoResponse {
-    let home_dir = home::home_dir().expect("Home dir not found");
-
-    let wasm_module_dir = home_dir.join("modules/wasm");
-
-    let modules =
-        list_files(wasm_module_dir.to_str().unwrap()).expect("There to be modules on the server");
-    let modules: Vec<String> = modules
+pub async fn wasm(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let modules = state
+        .modules
+        .snapshot()
+        .functions
         .iter()
-        .filter_map(|path| Path::new(path).file_stem())
-        .map(|name| name.to_str().unwrap().to_string())
+        .filter_map(|(name, function)| {
+            let latest = function.latest()?;
+            let widget = InputWidget::new(name, manifest(&state, name).as_ref());
+            Some((widget, latest.short_version().to_string()))
+        })
         .collect();
-    let template = WasmTemplate { modules };
-    HtmlTemplate(template)
+
+    HtmlTemplate(WasmTemplate { modules })
+}
+
+/// The manifest of `name`, logging rather than failing the page if it's invalid.
+pub fn manifest(state: &AppState, name: &str) -> Option<FunctionManifest> {
+    state.modules.manifest(name).unwrap_or_else(|err| {
+        warn!("{}: {}", name, err);
+        None
+    })
 }
@@ -0,0 +1,397 @@
+//! Function pipelines.
+//!
+//! A [`Pipeline`] is a named sequence of functions, Wasm or Docker, where each stage gets the
+//! result of the previous one as its input. A run stops at the first stage that fails. Every stage
+//! is an invocation like any other and its result is recorded with [`Trigger::Pipeline`].
+//! Pipelines are written to `pipelines.json` on every change.
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use axum::http::StatusCode;
+use nebula_lib::models::{FunctionResult, ModuleType, Trigger};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    api::{
+        call_function::{run_reserved, validate_request},
+        error::{ApiError, ErrorBody},
+    },
+    api_keys::{self, Caller},
+    jobs::unix_millis,
+    models::{default_image, AppState, FunctionRequest},
+    utilities::json_file::JsonFile,
+};
+
+/// Most stages a pipeline can have.
+pub const MAX_STAGES: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PipelineStage {
+    /// Function name, optionally with a version or alias after an `@`.
+    pub function_name: String,
+    pub module_type: ModuleType,
+    #[serde(default = "default_image")]
+    pub base_image: String,
+}
+
+impl PipelineStage {
+    fn request(&self, input: String) -> FunctionRequest {
+        FunctionRequest {
+            function_name: self.function_name.clone(),
+            input,
+            module_type: self.module_type,
+            num_calls: 1,
+            base_image: self.base_image.clone(),
+            trigger: Trigger::Pipeline,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pipeline {
+    pub name: String,
+    pub stages: Vec<PipelineStage>,
+    /// Milliseconds since epoch.
+    pub updated_at: u64,
+    /// Name of the API key that created the pipeline, only it and admin keys may change it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+}
+
+/// Body of `PUT /api/pipelines/{name}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewPipeline {
+    pub stages: Vec<PipelineStage>,
+}
+
+/// Body of `POST /api/pipelines/{name}/run`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineInput {
+    pub input: String,
+}
+
+/// Outcome of running a pipeline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineRun {
+    pub pipeline: String,
+    pub input: String,
+    /// Result of the last stage, `None` if a stage failed.
+    pub output: Option<String>,
+    /// Result of every stage that ran, in order.
+    pub stages: Vec<FunctionResult>,
+    /// Index of the stage that failed, which may not have a result if it couldn't be started.
+    pub failed_stage: Option<usize>,
+    pub error: Option<ErrorBody>,
+    pub metrics: PipelineMetrics,
+}
+
+impl PipelineRun {
+    fn fail(&mut self, stage: usize, code: &str, message: String) {
+        self.failed_stage = Some(stage);
+        self.error = Some(ErrorBody {
+            code: code.to_string(),
+            message,
+        });
+    }
+}
+
+/// End-to-end metrics of a run, in microseconds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PipelineMetrics {
+    /// From the start of the run to the end of its last stage, including the time in between.
+    pub total_time: u128,
+    /// Sum of the stages' `total_runtime`.
+    pub compute_time: u128,
+    /// Sum of the stages' `startup_time`.
+    pub startup_time: u128,
+    /// Sum of the stages' `queue_time`.
+    pub queue_time: u128,
+}
+
+impl PipelineMetrics {
+    fn new(stages: &[FunctionResult], elapsed: Duration) -> Self {
+        stages
+            .iter()
+            .filter_map(|result| result.metrics.as_ref())
+            .fold(
+                PipelineMetrics {
+                    total_time: elapsed.as_micros(),
+                    ..Default::default()
+                },
+                |mut totals, metrics| {
+                    totals.compute_time += metrics.total_runtime;
+                    totals.startup_time += metrics.startup_time;
+                    totals.queue_time += metrics.queue_time.unwrap_or_default();
+                    totals
+                },
+            )
+    }
+}
+
+/// Runs the stages of `pipeline` one after the other, each with the result of the one before.
+/// The first stage is validated before every stage is counted against the quota of `caller`, and
+/// the stages that don't run are given back. Fails like `/api/wasm` if the first stage can't be
+/// started, later failures end the run and are reported in the [`PipelineRun`].
+pub async fn run_pipeline(
+    state: &AppState,
+    caller: Option<&Caller>,
+    pipeline: &Pipeline,
+    input: String,
+) -> Result<PipelineRun, ApiError> {
+    let Some(first) = pipeline.stages.first() else {
+        return Err(ApiError::invalid_request(format!(
+            "The pipeline {} has no stages",
+            pipeline.name
+        )));
+    };
+    validate_request(state, &first.request(input.clone()))?;
+    let reserved = pipeline.stages.len() as u32;
+    api_keys::reserve(state, caller, reserved)?;
+
+    let started = Instant::now();
+    let mut run = PipelineRun {
+        pipeline: pipeline.name.clone(),
+        input: input.clone(),
+        output: None,
+        stages: Vec::new(),
+        failed_stage: None,
+        error: None,
+        metrics: Default::default(),
+    };
+
+    let mut next_input = input;
+    for (index, stage) in pipeline.stages.iter().enumerate() {
+        let span = info_span!("stage", index, function_name = %stage.function_name);
+        let request = stage.request(std::mem::take(&mut next_input));
+        let outcome = match validate_request(state, &request) {
+            Ok(limits) => {
+                run_reserved(state, caller, request, limits)
+                    .instrument(span)
+                    .await
+            }
+            Err(err) => Err(err),
+        }
+        .and_then(|mut results| {
+            results
+                .pop()
+                .ok_or_else(|| ApiError::internal("The function returned no result"))
+        });
+
+        let result = match outcome {
+            Ok(result) => result,
+            Err(err) if index == 0 => {
+                api_keys::refund(state, caller, reserved);
+                return Err(err);
+            }
+            Err(err) => {
+                run.fail(index, err.code, err.message);
+                break;
+            }
+        };
+
+        next_input = result.result.clone();
+        let error = result.error.clone();
+        run.stages.push(result);
+
+        if let Some(error) = error {
+            run.fail(index, "function_failed", error);
+            break;
+        }
+    }
+
+    // Only the stages that returned a result were invoked.
+    api_keys::refund(state, caller, reserved - run.stages.len() as u32);
+
+    if run.failed_stage.is_none() {
+        run.output = Some(next_input);
+    }
+    run.metrics = PipelineMetrics::new(&run.stages, started.elapsed());
+
+    Ok(run)
+}
+
+#[derive(Debug)]
+pub struct Pipelines {
+    pipelines: Mutex<BTreeMap<String, Pipeline>>,
+    file: JsonFile,
+}
+
+impl Pipelines {
+    pub fn load(path: PathBuf) -> Self {
+        let file = JsonFile::new(path);
+        let pipelines = file
+            .load_list::<Pipeline>()
+            .into_iter()
+            .map(|pipeline| (pipeline.name.clone(), pipeline))
+            .collect();
+
+        Pipelines {
+            pipelines: Mutex::new(pipelines),
+            file,
+        }
+    }
+
+    /// Every pipeline, by name.
+    pub async fn list(&self) -> Vec<Pipeline> {
+        self.pipelines.lock().await.values().cloned().collect()
+    }
+
+    pub async fn get(&self, name: &str) -> Option<Pipeline> {
+        self.pipelines.lock().await.get(name).cloned()
+    }
+
+    /// Adds the pipeline `name` for `caller`, or replaces its stages. The stages have to be
+    /// validated. Fails with `403 forbidden` if the pipeline was created by another key.
+    pub async fn put(
+        &self,
+        name: &str,
+        stages: Vec<PipelineStage>,
+        caller: Option<&Caller>,
+    ) -> Result<Pipeline, ApiError> {
+        let mut pipelines = self.pipelines.lock().await;
+
+        let api_key = match pipelines.get(name) {
+            Some(existing) => {
+                check_owner(existing, caller)?;
+                existing.api_key.clone()
+            }
+            None => caller.map(|caller| caller.name.clone()),
+        };
+        let pipeline = Pipeline {
+            name: name.to_string(),
+            stages,
+            updated_at: unix_millis(),
+            api_key,
+        };
+        pipelines.insert(name.to_string(), pipeline.clone());
+        self.persist(pipelines).await;
+
+        Ok(pipeline)
+    }
+
+    /// Removes the pipeline `name`, `None` if there is none. Fails with `403 forbidden` if it was
+    /// created by another key than `caller`.
+    pub async fn remove(
+        &self,
+        name: &str,
+        caller: Option<&Caller>,
+    ) -> Result<Option<Pipeline>, ApiError> {
+        let mut pipelines = self.pipelines.lock().await;
+
+        let Some(existing) = pipelines.get(name) else {
+            return Ok(None);
+        };
+        check_owner(existing, caller)?;
+        let pipeline = pipelines.remove(name);
+        self.persist(pipelines).await;
+
+        Ok(pipeline)
+    }
+
+    /// Writes the pipelines once `pipelines` is unlocked.
+    async fn persist(&self, pipelines: MutexGuard<'_, BTreeMap<String, Pipeline>>) {
+        let snapshot = self
+            .file
+            .snapshot(pipelines.values().cloned().collect::<Vec<_>>());
+        drop(pipelines);
+        self.file.write(snapshot).await;
+    }
+}
+
+fn check_owner(pipeline: &Pipeline, caller: Option<&Caller>) -> Result<(), ApiError> {
+    if api_keys::may_access(caller, pipeline.api_key.as_deref()) {
+        return Ok(());
+    }
+
+    Err(ApiError::new(
+        StatusCode::FORBIDDEN,
+        "forbidden",
+        format!("Pipeline {} belongs to another API key", pipeline.name),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[tokio::test]
+    async fn persists_pipelines_and_sums_their_metrics() {
+        let dir = TempDir::new("pipelines");
+        let path = dir.join("pipelines.json");
+
+        let stages: Vec<PipelineStage> = serde_json::from_value(serde_json::json!([
+            { "function_name": "prime-number", "module_type": "Wasm" },
+            { "function_name": "factorial", "module_type": "Docker" },
+        ]))
+        .unwrap();
+        assert_eq!(stages[1].base_image, "debian");
+
+        let caller = |name: &str| Caller {
+            name: name.to_string(),
+            quota: Default::default(),
+            admin: false,
+        };
+        let (alice, bob) = (caller("alice"), caller("bob"));
+
+        let pipelines = Pipelines::load(path.clone());
+        pipelines
+            .put("prime-factorial", stages.clone(), Some(&alice))
+            .await
+            .unwrap();
+        pipelines
+            .put("removed", stages.clone(), Some(&alice))
+            .await
+            .unwrap();
+        let err = pipelines.remove("removed", Some(&bob)).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        assert!(pipelines
+            .put("removed", Vec::new(), Some(&bob))
+            .await
+            .is_err());
+        assert!(pipelines
+            .remove("removed", Some(&alice))
+            .await
+            .unwrap()
+            .is_some());
+
+        let reloaded = Pipelines::load(path.clone());
+        let names: Vec<String> = reloaded
+            .list()
+            .await
+            .into_iter()
+            .map(|pipeline| pipeline.name)
+            .collect();
+        assert_eq!(names, ["prime-factorial"]);
+        assert_eq!(
+            reloaded.get("prime-factorial").await.unwrap().stages,
+            stages
+        );
+
+        let stage = |startup_time, total_runtime| {
+            let mut result = FunctionResult::test_fixture(startup_time, total_runtime);
+            if let Some(metrics) = &mut result.metrics {
+                metrics.queue_time = Some(5);
+            }
+            result
+        };
+        assert_eq!(
+            PipelineMetrics::new(
+                &[stage(10, 100), stage(20, 200)],
+                Duration::from_micros(400)
+            ),
+            PipelineMetrics {
+                total_time: 400,
+                compute_time: 300,
+                startup_time: 30,
+                queue_time: 10,
+            }
+        );
+    }
+}
@@ -0,0 +1,288 @@
+//! Invocation metrics in the OpenMetrics text format, for Prometheus and similar scrapers.
+//!
+//! Counters and histograms are kept in memory from the results of every invocation and start
+//! from zero when the server starts, which scrapers handle as a counter reset. Gauges are read
+//! from the concurrency limits and the job queue when scraped.
+
+use std::{collections::BTreeMap, fmt::Write, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::header::CONTENT_TYPE,
+    response::{IntoResponse, Response},
+};
+use nebula_lib::models::{FunctionResult, ModuleType};
+
+use crate::{jobs::JobStatus, models::AppState};
+
+pub const CONTENT_TYPE_OPENMETRICS: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Upper bounds of the histogram buckets in seconds, Wasm starts in well under a millisecond
+/// while containers take about a second.
+const BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Labels {
+    function: String,
+    runtime: &'static str,
+    /// Empty for Wasm functions.
+    base_image: String,
+}
+
+impl Labels {
+    fn new(result: &FunctionResult) -> Self {
+        let (runtime, base_image) = match result.func_type {
+            ModuleType::Wasm => ("wasm", String::new()),
+            ModuleType::Docker => ("docker", result.base_image.clone()),
+        };
+
+        Labels {
+            function: result.func_name.clone(),
+            runtime,
+            base_image,
+        }
+    }
+
+    fn to_openmetrics(&self) -> String {
+        format!(
+            "function=\"{}\",runtime=\"{}\",base_image=\"{}\"",
+            escape(&self.function),
+            self.runtime,
+            escape(&self.base_image)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative.
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Series {
+    invocations: u64,
+    errors: u64,
+    startup: Histogram,
+    runtime: Histogram,
+}
+
+/// Counters and histograms by function, runtime and base image.
+#[derive(Debug, Default)]
+pub struct InvocationMetrics {
+    series: std::sync::Mutex<BTreeMap<Labels, Series>>,
+}
+
+/// Gauges read when the metrics are scraped.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Gauges {
+    pub in_flight: usize,
+    pub queued: usize,
+    pub jobs_queued: usize,
+}
+
+impl InvocationMetrics {
+    pub fn record(&self, results: &[FunctionResult]) {
+        let mut series = self
+            .series
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        for result in results {
+            let series = series.entry(Labels::new(result)).or_default();
+            series.invocations += 1;
+            if !result.is_success() {
+                series.errors += 1;
+            }
+
+            if let Some(metrics) = &result.metrics {
+                let runtime = metrics.total_runtime.saturating_sub(metrics.startup_time);
+                series.startup.observe(metrics.startup_time as f64 / 1e6);
+                series.runtime.observe(runtime as f64 / 1e6);
+            }
+        }
+    }
+
+    /// Every metric in the OpenMetrics text format.
+    pub fn render(&self, gauges: Gauges) -> String {
+        let series = self
+            .series
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+        let mut out = String::new();
+
+        write_header(&mut out, "nebula_invocations", "counter", "Invocations.");
+        for (labels, series) in &series {
+            let labels = labels.to_openmetrics();
+            let _ = writeln!(
+                out,
+                "nebula_invocations_total{{{}}} {}",
+                labels, series.invocations
+            );
+        }
+
+        write_header(
+            &mut out,
+            "nebula_invocation_errors",
+            "counter",
+            "Invocations that failed.",
+        );
+        for (labels, series) in &series {
+            let labels = labels.to_openmetrics();
+            let _ = writeln!(
+                out,
+                "nebula_invocation_errors_total{{{}}} {}",
+                labels, series.errors
+            );
+        }
+
+        let name = "nebula_startup_seconds";
+        write_header(
+            &mut out,
+            name,
+            "histogram",
+            "Time until the function started.",
+        );
+        for (labels, series) in &series {
+            write_histogram(&mut out, name, &labels.to_openmetrics(), &series.startup);
+        }
+
+        let name = "nebula_runtime_seconds";
+        write_header(
+            &mut out,
+            name,
+            "histogram",
+            "Time the function ran after it started.",
+        );
+        for (labels, series) in &series {
+            write_histogram(&mut out, name, &labels.to_openmetrics(), &series.runtime);
+        }
+
+        for (name, help, value) in [
+            (
+                "nebula_invocations_in_flight",
+                "Invocations running.",
+                gauges.in_flight,
+            ),
+            (
+                "nebula_invocations_queued",
+                "Invocations waiting for a free slot.",
+                gauges.queued,
+            ),
+            (
+                "nebula_jobs_queued",
+                "Jobs waiting for a worker.",
+                gauges.jobs_queued,
+            ),
+        ] {
+            write_header(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    let mut cumulative = 0;
+    for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+        cumulative += count;
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"{:?}\"}} {}",
+            name, labels, bound, cumulative
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{}_bucket{{{},le=\"+Inf\"}} {}",
+        name, labels, histogram.count
+    );
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Invocation metrics in the OpenMetrics text format.
+pub async fn get_prometheus(State(state): State<Arc<AppState>>) -> Response {
+    let gauges = Gauges {
+        in_flight: state.limits.in_flight(),
+        queued: state.limits.queued(),
+        jobs_queued: state.jobs.count(JobStatus::Queued).await,
+    };
+
+    (
+        [(CONTENT_TYPE, CONTENT_TYPE_OPENMETRICS)],
+        state.prometheus.render(gauges),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(error: Option<&str>, startup_time: u128) -> FunctionResult {
+        FunctionResult {
+            error: error.map(str::to_string),
+            ..FunctionResult::test_fixture(startup_time, startup_time + 2_000)
+        }
+    }
+
+    #[test]
+    fn renders_openmetrics() {
+        let metrics = InvocationMetrics::default();
+        metrics.record(&[result(None, 300), result(Some("boom"), 800)]);
+
+        let rendered = metrics.render(Gauges {
+            in_flight: 1,
+            ..Default::default()
+        });
+        let labels = r#"function="factorial",runtime="wasm",base_image="""#;
+
+        assert!(rendered.contains(&format!("nebula_invocations_total{{{}}} 2\n", labels)));
+        assert!(rendered.contains(&format!("nebula_invocation_errors_total{{{}}} 1\n", labels)));
+        assert!(rendered.contains(&format!(
+            "nebula_startup_seconds_bucket{{{},le=\"0.0005\"}} 1\n",
+            labels
+        )));
+        assert!(rendered.contains(&format!(
+            "nebula_startup_seconds_bucket{{{},le=\"0.001\"}} 2\n",
+            labels
+        )));
+        assert!(rendered.contains(&format!(
+            "nebula_runtime_seconds_bucket{{{},le=\"0.0025\"}} 2\n",
+            labels
+        )));
+        assert!(rendered.contains("nebula_invocations_in_flight 1\n"));
+        assert!(rendered.ends_with("# EOF\n"));
+    }
+}
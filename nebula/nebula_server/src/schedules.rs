@@ -0,0 +1,296 @@
+//! Scheduled invocations.
+//!
+//! A [`Schedule`] invokes a function with a fixed input whenever its cron expression matches, in
+//! UTC. Each run is an invocation like any other and its results are recorded with
+//! [`Trigger::Cron`] and counted against the quota of the key that created the schedule. A run
+//! that can't be started, e.g. because the function or the key was removed or the quota is used
+//! up, is kept as the schedule's `last_error`. Schedules are written to `schedules.json` on every
+//! change. Runs missed while the server was stopped aren't made up for.
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use nebula_lib::models::Trigger;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, MutexGuard, Notify};
+use tracing::{info_span, warn, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    api::{call_function::run_request, error::ErrorBody},
+    cron::CronExpr,
+    jobs::unix_millis,
+    models::{AppState, FunctionRequest},
+    utilities::json_file::JsonFile,
+};
+
+/// Longest the scheduler sleeps before looking at the schedules again.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: String,
+    /// Cron expression, see [`crate::cron`].
+    pub cron: String,
+    #[serde(flatten)]
+    pub request: FunctionRequest,
+    pub paused: bool,
+    /// Milliseconds since epoch.
+    pub created_at: u64,
+    pub last_run_at: Option<u64>,
+    /// `None` while paused.
+    pub next_run_at: Option<u64>,
+    /// Why the last run couldn't be started, `None` if it ran.
+    #[serde(default)]
+    pub last_error: Option<ErrorBody>,
+    /// Name of the API key that created the schedule, its runs count against that key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+}
+
+impl Schedule {
+    fn next_run_after(&self, after: u64) -> Option<u64> {
+        if self.paused {
+            return None;
+        }
+
+        let cron: CronExpr = self.cron.parse().ok()?;
+        cron.next_after(after / 1000).map(|next| next * 1000)
+    }
+}
+
+/// Body of `POST /api/schedules`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewSchedule {
+    pub cron: String,
+    #[serde(flatten)]
+    pub request: FunctionRequest,
+    #[serde(default)]
+    pub paused: bool,
+}
+
+#[derive(Debug)]
+pub struct Schedules {
+    /// Oldest first.
+    schedules: Mutex<Vec<Schedule>>,
+    file: JsonFile,
+    /// Wakes the scheduler when the schedules change.
+    changed: Notify,
+}
+
+impl Schedules {
+    /// Loads the schedules persisted at `path`, they next run after now.
+    pub fn load(path: PathBuf) -> Self {
+        let file = JsonFile::new(path);
+        let mut schedules: Vec<Schedule> = file.load_list();
+
+        let now = unix_millis();
+        for schedule in &mut schedules {
+            schedule.next_run_at = schedule.next_run_after(now);
+        }
+
+        Schedules {
+            schedules: Mutex::new(schedules),
+            file,
+            changed: Notify::new(),
+        }
+    }
+
+    /// Adds a schedule created by the key named `api_key`. The cron expression has to be valid.
+    pub async fn add(&self, new: NewSchedule, api_key: Option<String>) -> Schedule {
+        let now = unix_millis();
+        let mut schedule = Schedule {
+            id: Uuid::new_v4().to_string(),
+            cron: new.cron,
+            request: new.request,
+            paused: new.paused,
+            created_at: now,
+            last_run_at: None,
+            next_run_at: None,
+            last_error: None,
+            api_key,
+        };
+        schedule.next_run_at = schedule.next_run_after(now);
+
+        let mut schedules = self.schedules.lock().await;
+        schedules.push(schedule.clone());
+        self.persist(schedules).await;
+        self.changed.notify_one();
+
+        schedule
+    }
+
+    pub async fn list(&self) -> Vec<Schedule> {
+        self.schedules.lock().await.clone()
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Schedule> {
+        let schedules = self.schedules.lock().await;
+
+        schedules.iter().find(|schedule| schedule.id == id).cloned()
+    }
+
+    pub async fn remove(&self, id: &str) -> Option<Schedule> {
+        let mut schedules = self.schedules.lock().await;
+
+        let index = schedules.iter().position(|schedule| schedule.id == id)?;
+        let schedule = schedules.remove(index);
+        self.persist(schedules).await;
+
+        Some(schedule)
+    }
+
+    /// Pauses or resumes a schedule, a resumed schedule next runs after now.
+    pub async fn set_paused(&self, id: &str, paused: bool) -> Option<Schedule> {
+        let schedule = self
+            .update(id, |schedule| {
+                schedule.paused = paused;
+                schedule.next_run_at = schedule.next_run_after(unix_millis());
+            })
+            .await;
+        self.changed.notify_one();
+
+        schedule
+    }
+
+    async fn update(&self, id: &str, update: impl FnOnce(&mut Schedule)) -> Option<Schedule> {
+        let mut schedules = self.schedules.lock().await;
+
+        let schedule = schedules.iter_mut().find(|schedule| schedule.id == id)?;
+        update(schedule);
+        let schedule = schedule.clone();
+        self.persist(schedules).await;
+
+        Some(schedule)
+    }
+
+    /// The schedules due at `now`, moving them on to their next run.
+    async fn take_due(&self, now: u64) -> Vec<Schedule> {
+        let mut schedules = self.schedules.lock().await;
+
+        let mut due = Vec::new();
+        for schedule in schedules.iter_mut() {
+            if schedule.next_run_at.is_some_and(|next| next <= now) {
+                schedule.last_run_at = Some(now);
+                schedule.next_run_at = schedule.next_run_after(now);
+                due.push(schedule.clone());
+            }
+        }
+
+        if !due.is_empty() {
+            self.persist(schedules).await;
+        }
+
+        due
+    }
+
+    /// Writes the schedules once `schedules` is unlocked.
+    async fn persist(&self, schedules: MutexGuard<'_, Vec<Schedule>>) {
+        let snapshot = self.file.snapshot(schedules.clone());
+        drop(schedules);
+        self.file.write(snapshot).await;
+    }
+
+    async fn next_run_at(&self) -> Option<u64> {
+        let schedules = self.schedules.lock().await;
+
+        schedules
+            .iter()
+            .filter_map(|schedule| schedule.next_run_at)
+            .min()
+    }
+}
+
+/// Starts the task running the schedules when they're due.
+pub fn spawn_scheduler(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        loop {
+            for schedule in state.schedules.take_due(unix_millis()).await {
+                let state = state.clone();
+                let span = info_span!("schedule", id = %schedule.id);
+                tokio::spawn(async move { run_schedule(&state, schedule).await }.instrument(span));
+            }
+
+            let sleep = match state.schedules.next_run_at().await {
+                Some(next) => Duration::from_millis(next.saturating_sub(unix_millis())),
+                None => MAX_SLEEP,
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(sleep.min(MAX_SLEEP)) => {}
+                _ = state.schedules.changed.notified() => {}
+            }
+        }
+    });
+}
+
+async fn run_schedule(state: &AppState, schedule: Schedule) {
+    let mut request = schedule.request;
+    request.trigger = Trigger::Cron;
+
+    // Runs on behalf of the key that created the schedule, within its quota.
+    let outcome = match state.api_keys.caller_named(schedule.api_key.as_deref()) {
+        Ok(caller) => run_request(state, caller.as_ref(), request).await,
+        Err(err) => Err(err),
+    };
+
+    state
+        .schedules
+        .update(&schedule.id, |schedule| {
+            schedule.last_error = outcome.err().map(|err| {
+                warn!("scheduled run failed: {}", err.message);
+                ErrorBody {
+                    code: err.code.to_string(),
+                    message: err.message,
+                }
+            });
+        })
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use nebula_lib::models::ModuleType;
+
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[tokio::test]
+    async fn runs_due_schedules_and_persists_them() {
+        let dir = TempDir::new("schedules");
+        let path = dir.join("schedules.json");
+
+        let schedules = Schedules::load(path.clone());
+        let new = NewSchedule {
+            cron: "* * * * *".to_string(),
+            request: serde_json::from_value(serde_json::json!({
+                "function_name": "factorial",
+                "input": "5",
+                "module_type": ModuleType::Wasm,
+            }))
+            .unwrap(),
+            paused: false,
+        };
+        let every_minute = schedules.add(new.clone(), None).await;
+        let paused = schedules
+            .add(
+                NewSchedule {
+                    paused: true,
+                    ..new
+                },
+                None,
+            )
+            .await;
+        assert_eq!(paused.next_run_at, None);
+
+        let next = every_minute.next_run_at.unwrap();
+        assert!(schedules.take_due(next - 1).await.is_empty());
+        let due = schedules.take_due(next).await;
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, every_minute.id);
+
+        let reloaded = Schedules::load(path.clone());
+        let every_minute = reloaded.get(&every_minute.id).await.unwrap();
+        assert_eq!(every_minute.last_run_at, Some(next));
+        assert_eq!(every_minute.request.num_calls, 1);
+    }
+}
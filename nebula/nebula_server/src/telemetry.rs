@@ -0,0 +1,207 @@
+//! Logging and trace export.
+//!
+//! Every request is traced: the HTTP handling span holds the spans for queueing, the
+//! invocation with its module load, instantiate, execute and output parse, and persisting the
+//! results. The `invocation` spans carry the fields of the [`FunctionResult`] they produced.
+//! Spans are exported over OTLP to a collector, or written to a JSON lines file, one span per
+//! line, when either is configured.
+//!
+//! [`FunctionResult`]: nebula_lib::models::FunctionResult
+
+use std::{
+    fs::{File, OpenOptions},
+    future::{ready, Future},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    pin::Pin,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use opentelemetry::{
+    global,
+    trace::{Status, TraceError, TracerProvider as _},
+    KeyValue, Value,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    export::trace::{ExportResult, SpanData, SpanExporter},
+    runtime,
+    trace::{Config, Tracer, TracerProvider},
+    Resource,
+};
+use serde_json::{json, Map};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+const SERVICE_NAME: &str = "nebula";
+
+/// Where spans are exported to, nowhere if both are `None`.
+#[derive(Debug, Clone, Default)]
+pub struct TraceExport {
+    /// OTLP/gRPC endpoint of a collector, e.g. `http://localhost:4317`.
+    pub otlp_endpoint: Option<String>,
+    /// File the spans are appended to as JSON lines.
+    pub file: Option<PathBuf>,
+}
+
+/// Installs the global subscriber, logging to stdout and exporting spans as configured.
+pub fn init(export: &TraceExport) -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        "nebula_server=debug,nebula_lib=debug,tower_http=info,tower_livereload=debug".into()
+    });
+
+    let tracer = match (&export.otlp_endpoint, &export.file) {
+        (Some(endpoint), _) => Some(otlp_tracer(endpoint)?),
+        (None, Some(path)) => Some(file_tracer(path)?),
+        (None, None) => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .init();
+
+    Ok(())
+}
+
+/// Exports the spans that haven't been exported yet, call before exiting.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+fn config() -> Config {
+    Config::default().with_resource(Resource::new([KeyValue::new("service.name", SERVICE_NAME)]))
+}
+
+fn otlp_tracer(endpoint: &str) -> Result<Tracer, TraceError> {
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(config())
+        .install_batch(runtime::Tokio)
+}
+
+fn file_tracer(path: &PathBuf) -> Result<Tracer> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening trace file {:?}", path))?;
+
+    let provider = TracerProvider::builder()
+        .with_simple_exporter(JsonLinesExporter {
+            writer: BufWriter::new(file),
+        })
+        .with_config(config())
+        .build();
+    let tracer = provider.tracer(SERVICE_NAME);
+    global::set_tracer_provider(provider);
+
+    Ok(tracer)
+}
+
+/// Writes every span as a line of JSON.
+#[derive(Debug)]
+struct JsonLinesExporter {
+    writer: BufWriter<File>,
+}
+
+impl SpanExporter for JsonLinesExporter {
+    fn export(
+        &mut self,
+        batch: Vec<SpanData>,
+    ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        let written = batch
+            .iter()
+            .try_for_each(|span| writeln!(self.writer, "{}", span_to_json(span)))
+            .and_then(|_| self.writer.flush())
+            .map_err(|err| TraceError::Other(Box::new(err)));
+
+        Box::pin(ready(written))
+    }
+}
+
+fn span_to_json(span: &SpanData) -> serde_json::Value {
+    let status = match &span.status {
+        Status::Unset => json!("unset"),
+        Status::Ok => json!("ok"),
+        Status::Error { description } => json!({ "error": description }),
+    };
+    let events: Vec<_> = span
+        .events
+        .iter()
+        .map(|event| {
+            json!({
+                "name": event.name,
+                "time_unix_nano": unix_nanos(event.timestamp),
+                "attributes": attributes(&event.attributes),
+            })
+        })
+        .collect();
+
+    json!({
+        "trace_id": span.span_context.trace_id().to_string(),
+        "span_id": span.span_context.span_id().to_string(),
+        "parent_span_id": span.parent_span_id.to_string(),
+        "name": span.name,
+        "start_time_unix_nano": unix_nanos(span.start_time),
+        "end_time_unix_nano": unix_nanos(span.end_time),
+        "attributes": attributes(&span.attributes),
+        "events": events,
+        "status": status,
+    })
+}
+
+fn attributes(attributes: &[KeyValue]) -> Map<String, serde_json::Value> {
+    attributes
+        .iter()
+        .map(|attribute| {
+            let value = match &attribute.value {
+                Value::Bool(value) => json!(value),
+                Value::I64(value) => json!(value),
+                Value::F64(value) => json!(value),
+                value => json!(value.as_str()),
+            };
+            (attribute.key.to_string(), value)
+        })
+        .collect()
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::{Tracer as _, TracerProvider as _};
+
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn writes_spans_as_json_lines() {
+        let dir = TempDir::new("telemetry");
+        let path = dir.join("spans.jsonl");
+
+        let file = File::create(&path).unwrap();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(JsonLinesExporter {
+                writer: BufWriter::new(file),
+            })
+            .build();
+        provider.tracer("test").in_span("invocation", |_| {});
+        drop(provider);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let span: serde_json::Value =
+            serde_json::from_str(contents.lines().next().unwrap()).unwrap();
+        assert_eq!(span["name"], "invocation");
+        assert_eq!(span["trace_id"].as_str().unwrap().len(), 32);
+    }
+}
@@ -0,0 +1,86 @@
+//! Helpers shared by the tests.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use nebula_lib::{deploy::DeployDirs, module_store::ModuleStore, runtime::RuntimeRegistry};
+
+use crate::{
+    api_keys::ApiKeys,
+    concurrency::{ConcurrencyLimits, LimitsConfig},
+    config::Dirs,
+    history::History,
+    jobs::JobQueue,
+    models::AppState,
+    pipelines::Pipelines,
+    schedules::Schedules,
+};
+
+/// An empty directory under the system's temporary directory, removed when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` has to be unique among the tests, which run in parallel.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("nebula-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        TempDir(path)
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A server state keeping its files in `dir`, without API keys or deployed functions.
+pub fn app_state(dir: &TempDir) -> Arc<AppState> {
+    let dirs = Dirs {
+        data_dir: dir.join("data"),
+        wasm_module_dir: dir.join("modules/wasm"),
+        docker_module_dir: dir.join("modules/docker"),
+    };
+    dirs.create().unwrap();
+
+    let modules = ModuleStore::open(
+        DeployDirs {
+            serialized_dir: dirs.serialized_dir(),
+            archive_dir: dirs.module_archive_dir(),
+        },
+        dirs.module_registry_path(),
+    )
+    .unwrap();
+    let (jobs, _) = JobQueue::new(dirs.jobs_path(), 16);
+
+    Arc::new(AppState {
+        history: History::open(&dirs.history_path(), &dirs.data_path()).unwrap(),
+        prometheus: Default::default(),
+        live: Default::default(),
+        module_events: Default::default(),
+        runtimes: RuntimeRegistry::with_defaults(modules.clone()),
+        modules,
+        jobs,
+        schedules: Schedules::load(dirs.schedules_path()),
+        pipelines: Pipelines::load(dirs.pipelines_path()),
+        limits: ConcurrencyLimits::new(LimitsConfig {
+            max_concurrency: 4,
+            max_concurrency_per_function: None,
+            function_limits: HashMap::new(),
+            max_queued: 16,
+        }),
+        api_keys: ApiKeys::new(dirs.api_keys_path(), dirs.usage_path(), None),
+        deploy_token: None,
+        dirs,
+    })
+}
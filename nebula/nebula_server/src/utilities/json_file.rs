@@ -0,0 +1,155 @@
+//! State kept in a JSON file: the jobs, schedules and pipelines.
+//!
+//! The state is snapshotted while it's locked and written after the lock is released, on the
+//! blocking pool, through a temporary file renamed over the old one, so a crash mid-write leaves
+//! the previous version. Snapshots are numbered in the order they were taken, and a snapshot
+//! older than the one on disk is never written over it.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::Context;
+use nebula_lib::deploy::write_atomic;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+#[derive(Debug)]
+pub struct JsonFile {
+    path: PathBuf,
+    /// Number of the last snapshot taken.
+    taken: AtomicU64,
+    /// Number of the last snapshot written, held while writing.
+    written: Mutex<u64>,
+}
+
+/// State to write, see [`JsonFile::snapshot`].
+#[derive(Debug)]
+pub struct Snapshot<T> {
+    number: u64,
+    value: T,
+}
+
+impl JsonFile {
+    pub fn new(path: PathBuf) -> Self {
+        JsonFile {
+            path,
+            taken: AtomicU64::new(0),
+            written: Mutex::new(0),
+        }
+    }
+
+    /// Reads the list in the file, empty if there is none. Items that can't be parsed are
+    /// skipped. A file that isn't a list at all is moved aside to `{file}.corrupt` rather than
+    /// overwritten by the next write.
+    pub fn load_list<T: DeserializeOwned>(&self) -> Vec<T> {
+        let items: Vec<serde_json::Value> = self.load().unwrap_or_default();
+
+        items
+            .into_iter()
+            .filter_map(|item| {
+                serde_json::from_value(item)
+                    .map_err(|err| warn!("skipping an entry of {:?}: {}", self.path, err))
+                    .ok()
+            })
+            .collect()
+    }
+
+    /// Reads the file, `None` if there is none or it's moved aside because it can't be parsed.
+    pub fn load<T: DeserializeOwned>(&self) -> Option<T> {
+        if !self.path.exists() {
+            return None;
+        }
+
+        let parsed = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read {:?}", self.path))
+            .and_then(|contents| {
+                serde_json::from_str(&contents)
+                    .with_context(|| format!("Failed to parse {:?}", self.path))
+            });
+
+        match parsed {
+            Ok(value) => Some(value),
+            Err(err) => {
+                let corrupt = corrupt_path(&self.path);
+                warn!("{:#}, moving it to {:?}", err, corrupt);
+                if let Err(err) = fs::rename(&self.path, &corrupt) {
+                    warn!("failed to move {:?} aside: {}", self.path, err);
+                }
+                None
+            }
+        }
+    }
+
+    /// Takes a snapshot of `value` to [`write`](JsonFile::write), call it while `value` is
+    /// still locked so snapshots are numbered in the order of the changes.
+    pub fn snapshot<T>(&self, value: T) -> Snapshot<T> {
+        Snapshot {
+            number: self.taken.fetch_add(1, Ordering::SeqCst) + 1,
+            value,
+        }
+    }
+
+    /// Writes `snapshot`, unless a newer one has been written already. Failures are logged.
+    pub async fn write<T: Serialize + Send + 'static>(&self, snapshot: Snapshot<T>) {
+        let mut written = self.written.lock().await;
+        if *written > snapshot.number {
+            return;
+        }
+
+        let path = self.path.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            write_atomic(&path, serde_json::to_string(&snapshot.value)?.as_bytes())
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|result| result);
+
+        match result {
+            Ok(()) => *written = snapshot.number,
+            Err(err) => warn!("failed to save {:?}: {:#}", self.path, err),
+        }
+    }
+}
+
+fn corrupt_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".corrupt");
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Item {
+        id: u32,
+    }
+
+    #[tokio::test]
+    async fn keeps_the_newest_snapshot_and_parseable_items() {
+        let dir = TempDir::new("json-file");
+        let file = JsonFile::new(dir.join("items.json"));
+        assert!(file.load_list::<Item>().is_empty());
+
+        let older = file.snapshot(vec![Item { id: 1 }]);
+        let newer = file.snapshot(vec![Item { id: 1 }, Item { id: 2 }]);
+        file.write(newer).await;
+        file.write(older).await;
+        assert_eq!(file.load_list::<Item>(), [Item { id: 1 }, Item { id: 2 }]);
+
+        fs::write(dir.join("items.json"), r#"[{"id": 1}, {"id": "two"}]"#).unwrap();
+        assert_eq!(file.load_list::<Item>(), [Item { id: 1 }]);
+
+        fs::write(dir.join("items.json"), r#"[{"id": 1}, {"id""#).unwrap();
+        assert!(file.load_list::<Item>().is_empty());
+        assert!(dir.join("items.json.corrupt").is_file());
+    }
+}
@@ -1,7 +1,7 @@
 pub mod format;
-pub mod get_file_path;
 pub mod html_template;
-pub mod persist;
+pub mod json_file;
+pub mod negotiate;
+pub mod provision_images;
 pub mod redirect_http_to_https;
-pub mod sanitize_input;
 pub mod serialize_modules;
@@ -0,0 +1,132 @@
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        request::Parts,
+        HeaderMap,
+    },
+    Form, Json,
+};
+use serde::de::DeserializeOwned;
+
+use crate::api::error::ApiError;
+
+/// The representation a client asked for: htmx requests and browsers get HTML fragments,
+/// everything else gets JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Html,
+    Json,
+}
+
+impl ResponseFormat {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        if headers.contains_key("hx-request") {
+            return ResponseFormat::Html;
+        }
+
+        let accept = headers
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or_default();
+
+        // Whichever of the two the client lists first wins, JSON if it lists neither.
+        match (accept.find("text/html"), accept.find("application/json")) {
+            (Some(html), Some(json)) if html < json => ResponseFormat::Html,
+            (Some(_), None) => ResponseFormat::Html,
+            _ => ResponseFormat::Json,
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ResponseFormat
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ResponseFormat::from_headers(&parts.headers))
+    }
+}
+
+/// Request body accepted both as `application/json` and as a url-encoded form.
+pub struct Payload<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for Payload<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let format = ResponseFormat::from_headers(req.headers());
+        let is_json = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("application/json"));
+
+        let payload = if is_json {
+            Json::<T>::from_request(req, state)
+                .await
+                .map(|Json(payload)| payload)
+                .map_err(|rejection| ApiError::invalid_request(rejection.body_text()))
+        } else {
+            Form::<T>::from_request(req, state)
+                .await
+                .map(|Form(payload)| payload)
+                .map_err(|rejection| ApiError::invalid_request(rejection.body_text()))
+        };
+
+        payload.map(Payload).map_err(|err| err.with_format(format))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn negotiates_response_format() {
+        assert_eq!(
+            ResponseFormat::from_headers(&headers(&[("hx-request", "true")])),
+            ResponseFormat::Html
+        );
+        assert_eq!(
+            ResponseFormat::from_headers(&headers(&[(
+                "accept",
+                "text/html,application/xhtml+xml,*/*;q=0.8"
+            )])),
+            ResponseFormat::Html
+        );
+        assert_eq!(
+            ResponseFormat::from_headers(&headers(&[("accept", "application/json, text/html")])),
+            ResponseFormat::Json
+        );
+        assert_eq!(
+            ResponseFormat::from_headers(&headers(&[("accept", "*/*")])),
+            ResponseFormat::Json
+        );
+        assert_eq!(
+            ResponseFormat::from_headers(&HeaderMap::new()),
+            ResponseFormat::Json
+        );
+    }
+}
@@ -0,0 +1,22 @@
+use nebula_lib::docker_images::{provision_docker_modules, ProvisionReport};
+use std::path::Path;
+use tracing::{info, warn};
+
+pub fn provision_images(docker_module_dir: &Path) -> ProvisionReport {
+    let report = match provision_docker_modules(docker_module_dir) {
+        Ok(report) => report,
+        Err(err) => {
+            warn!("failed to provision docker images: {:#}", err);
+            return ProvisionReport::default();
+        }
+    };
+
+    for image in &report.loaded {
+        info!("loaded docker image {}", image);
+    }
+    for (image, reason) in &report.failed {
+        warn!("failed to load docker image {}: {}", image, reason);
+    }
+
+    report
+}
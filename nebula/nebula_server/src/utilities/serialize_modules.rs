@@ -1,14 +1,21 @@
-use std::fs;
+use nebula_lib::module_store::ModuleStore;
+use std::path::Path;
+use tracing::{info, warn};
 
-use nebula_lib::wasm_runner::serialize_wasm_modules;
+/// Precompiles the modules copied into the Wasm module directory that the store doesn't have yet.
+pub fn serialize_modules(modules: &ModuleStore, wasm_module_dir: &Path) {
+    let imported = match modules.import_dir(wasm_module_dir) {
+        Ok(imported) => imported,
+        Err(err) => {
+            warn!("failed to import wasm modules: {:#}", err);
+            return;
+        }
+    };
 
-pub fn serialize_modules() {
-    fs::create_dir_all("./nebula/serialized").unwrap();
-    let home_dir = home::home_dir().expect("Home dir not found");
-
-    let wasm_module_dir = home_dir.join("modules/wasm");
-
-    let serialized_tar_dir = home_dir.join(".nebula/serialized");
-
-    let _ = serialize_wasm_modules(wasm_module_dir, serialized_tar_dir);
+    for module in imported {
+        match module {
+            Ok(module) => info!("imported {}@{}", module.name, module.short_version()),
+            Err(err) => warn!("failed to import wasm module: {}", err),
+        }
+    }
 }
@@ -0,0 +1,531 @@
+//! Hot reload of the module directories.
+//!
+//! The Wasm and Docker module directories are polled for changes. A file is only picked up once
+//! its size and modification time are the same on two polls in a row, so a module that is still
+//! being copied isn't read half-written.
+//!
+//! An added or changed `{name}.wasm` is deployed like an upload, precompiled and made `latest`.
+//! Deleting it removes the versions the watcher deployed from it along with their precompiled
+//! modules, and `latest` moves back to the newest version left. Versions that were already
+//! deployed, on startup or through the API, stay. `{name}.nebula.toml` manifests are set and
+//! removed the same way. An added or changed Docker tarball is loaded, and deleting it removes
+//! its image.
+//!
+//! Every change is broadcast as a [`ModuleEvent`] to the subscribers of `GET /api/modules/stream`,
+//! which the Wasm and Docker pages use to refresh their functions.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::Stream;
+use nebula_lib::{
+    deploy::DeployError,
+    docker_images::{load_image, remove_image, DockerImage},
+    manifest::MANIFEST_SUFFIX,
+    models::ModuleType,
+    module_store::{content_version, ModuleStore},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, info, warn};
+
+use crate::{models::AppState, utilities::provision_images::provision_images};
+
+/// Events a subscriber can fall behind by before it skips some.
+const CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModuleChange {
+    Added,
+    Changed,
+    Removed,
+    /// The file changed, but couldn't be deployed or removed.
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModuleEvent {
+    /// What happened to the file.
+    pub change: ModuleChange,
+    pub module_type: ModuleType,
+    /// Function name.
+    pub name: String,
+    /// Version of a Wasm function, `None` for manifests.
+    pub version: Option<String>,
+    /// Base image of a Docker function.
+    pub base_image: Option<String>,
+    pub error: Option<String>,
+}
+
+impl ModuleEvent {
+    fn new(change: ModuleChange, module_type: ModuleType, name: &str) -> Self {
+        ModuleEvent {
+            change,
+            module_type,
+            name: name.to_string(),
+            version: None,
+            base_image: None,
+            error: None,
+        }
+    }
+
+    fn failed(mut self, error: impl ToString) -> Self {
+        self.change = ModuleChange::Failed;
+        self.error = Some(error.to_string());
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct ModuleEvents {
+    sender: broadcast::Sender<ModuleEvent>,
+}
+
+impl Default for ModuleEvents {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        ModuleEvents { sender }
+    }
+}
+
+impl ModuleEvents {
+    pub fn publish(&self, event: ModuleEvent) {
+        match event.change {
+            ModuleChange::Failed => warn!(
+                "failed to reload {:?} function {}: {}",
+                event.module_type,
+                event.name,
+                event.error.as_deref().unwrap_or_default()
+            ),
+            change => info!(
+                "{:?} function {}: {:?}",
+                event.module_type, event.name, change
+            ),
+        }
+
+        // Only fails if nobody is subscribed.
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ModuleEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// Streams every [`ModuleEvent`] as a `module` event.
+pub async fn stream_module_events(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let mut receiver = state.module_events.subscribe();
+
+    let events = async_stream::stream! {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => yield Event::default().event("module").json_data(event),
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("module stream fell behind, skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Starts the task polling the module directories every `interval`.
+pub fn spawn_module_watcher(state: Arc<AppState>, interval: Duration) {
+    tokio::spawn(async move {
+        let wasm_module_dir = state.dirs.wasm_module_dir.clone();
+        let docker_module_dir = state.dirs.docker_module_dir.clone();
+        let Ok(mut watchers) = tokio::task::spawn_blocking(move || {
+            (
+                WasmWatcher::new(wasm_module_dir),
+                DirWatcher::new(docker_module_dir),
+            )
+        })
+        .await
+        else {
+            return;
+        };
+
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+
+            let modules = state.modules.clone();
+            let polled = tokio::task::spawn_blocking(move || {
+                let (mut wasm, mut docker) = watchers;
+                let mut events = wasm.poll(&modules);
+                reload_docker(&mut docker, &mut events);
+                ((wasm, docker), events)
+            })
+            .await;
+
+            let (polled_watchers, events) = match polled {
+                Ok(polled) => polled,
+                Err(err) => {
+                    warn!("module watcher stopped: {}", err);
+                    return;
+                }
+            };
+            watchers = polled_watchers;
+
+            for event in events {
+                state.module_events.publish(event);
+            }
+        }
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileState {
+    modified: SystemTime,
+    len: u64,
+}
+
+type Snapshot = BTreeMap<PathBuf, FileState>;
+
+/// The files directly in `dir`, none if it can't be read.
+fn scan(dir: &Path) -> Snapshot {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Snapshot::new();
+    };
+
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let metadata = entry
+                .metadata()
+                .ok()
+                .filter(|metadata| metadata.is_file())?;
+            let state = FileState {
+                modified: metadata.modified().ok()?,
+                len: metadata.len(),
+            };
+            Some((entry.path(), state))
+        })
+        .collect()
+}
+
+/// Files that differ from `applied` and look the same in `previous` and `current`.
+fn settled(
+    applied: &Snapshot,
+    previous: &Snapshot,
+    current: &Snapshot,
+) -> Vec<(PathBuf, ModuleChange)> {
+    let mut changes = Vec::new();
+
+    for (path, state) in current {
+        if previous.get(path) != Some(state) || applied.get(path) == Some(state) {
+            continue;
+        }
+        let change = match applied.contains_key(path) {
+            true => ModuleChange::Changed,
+            false => ModuleChange::Added,
+        };
+        changes.push((path.clone(), change));
+    }
+
+    for path in applied.keys() {
+        if !current.contains_key(path) && !previous.contains_key(path) {
+            changes.push((path.clone(), ModuleChange::Removed));
+        }
+    }
+
+    changes
+}
+
+struct DirWatcher {
+    dir: PathBuf,
+    /// The files as of the last changes picked up.
+    applied: Snapshot,
+    /// The files as of the last poll.
+    previous: Snapshot,
+}
+
+impl DirWatcher {
+    /// Starts from the files in `dir` now, they were loaded on startup.
+    fn new(dir: PathBuf) -> Self {
+        let files = scan(&dir);
+
+        DirWatcher {
+            dir,
+            applied: files.clone(),
+            previous: files,
+        }
+    }
+
+    /// Files that changed and have settled since the last poll.
+    fn poll(&mut self) -> Vec<(PathBuf, ModuleChange)> {
+        let current = scan(&self.dir);
+        let changes = settled(&self.applied, &self.previous, &current);
+
+        for (path, _) in &changes {
+            match current.get(path) {
+                Some(state) => self.applied.insert(path.clone(), *state),
+                None => self.applied.remove(path),
+            };
+        }
+        self.previous = current;
+
+        changes
+    }
+}
+
+struct WasmWatcher {
+    files: DirWatcher,
+    /// Versions the watcher deployed from every module file, oldest first, to remove them with
+    /// it. Versions that were already deployed, on startup or through the API, are left alone.
+    versions: HashMap<PathBuf, Vec<String>>,
+}
+
+impl WasmWatcher {
+    fn new(dir: PathBuf) -> Self {
+        WasmWatcher {
+            files: DirWatcher::new(dir),
+            versions: HashMap::new(),
+        }
+    }
+
+    fn poll(&mut self, modules: &ModuleStore) -> Vec<ModuleEvent> {
+        self.files
+            .poll()
+            .into_iter()
+            .filter_map(|(path, change)| {
+                let file_name = path.file_name()?.to_str()?.to_string();
+
+                if let Some(name) = file_name.strip_suffix(MANIFEST_SUFFIX) {
+                    return Some(reload_manifest(modules, &path, name, change));
+                }
+                let name = file_name.strip_suffix(".wasm")?;
+                Some(self.reload_module(modules, &path, name, change))
+            })
+            .collect()
+    }
+
+    fn reload_module(
+        &mut self,
+        modules: &ModuleStore,
+        path: &Path,
+        name: &str,
+        change: ModuleChange,
+    ) -> ModuleEvent {
+        let event = ModuleEvent::new(change, ModuleType::Wasm, name);
+
+        if change == ModuleChange::Removed {
+            let mut event = event;
+            for version in self.versions.remove(path).unwrap_or_default() {
+                match modules.remove_version(name, &version) {
+                    Ok((removed, _)) => event.version = Some(removed.version),
+                    // Removed through the API in the meantime.
+                    Err(DeployError::UnknownFunction(_) | DeployError::UnknownVersion(_, _)) => {}
+                    Err(err) => return event.failed(err),
+                }
+            }
+            return event;
+        }
+
+        let deployed = fs::read(path)
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| {
+                let known = is_deployed(modules, name, &content_version(&bytes));
+                modules
+                    .deploy(name, None, &bytes)
+                    .map(|deployed| (deployed, known))
+                    .map_err(|err| err.to_string())
+            });
+
+        match deployed {
+            Ok((deployed, known)) => {
+                let versions = self.versions.entry(path.to_path_buf()).or_default();
+                if !known && !versions.contains(&deployed.version) {
+                    versions.push(deployed.version.clone());
+                }
+                ModuleEvent {
+                    version: Some(deployed.version),
+                    ..event
+                }
+            }
+            Err(err) => event.failed(err),
+        }
+    }
+}
+
+fn is_deployed(modules: &ModuleStore, name: &str, version: &str) -> bool {
+    modules.snapshot().get(name).is_ok_and(|function| {
+        function
+            .versions
+            .iter()
+            .any(|deployed| deployed.version == version)
+    })
+}
+
+fn reload_manifest(
+    modules: &ModuleStore,
+    path: &Path,
+    name: &str,
+    change: ModuleChange,
+) -> ModuleEvent {
+    let event = ModuleEvent::new(change, ModuleType::Wasm, name);
+
+    let result = match change {
+        ModuleChange::Removed => modules.remove_manifest(name).map(|_| ()),
+        _ => fs::read_to_string(path)
+            .map_err(|err| DeployError::Storage(err.into()))
+            .and_then(|contents| modules.set_manifest(name, &contents).map(|_| ())),
+    };
+
+    match result {
+        Ok(()) => event,
+        Err(err) => event.failed(err),
+    }
+}
+
+/// Loads and removes the images of the changed tarballs, and provisions the directory again if
+/// any did.
+fn reload_docker(docker: &mut DirWatcher, events: &mut Vec<ModuleEvent>) {
+    let changes: Vec<(PathBuf, DockerImage, ModuleChange)> = docker
+        .poll()
+        .into_iter()
+        .filter_map(|(path, change)| {
+            DockerImage::from_tarball(&path).map(|image| (path, image, change))
+        })
+        .collect();
+    if changes.is_empty() {
+        return;
+    }
+
+    let mut failed = HashMap::new();
+    for (path, image, change) in &changes {
+        let result = match change {
+            ModuleChange::Removed => remove_image(&image.image_name),
+            _ => load_image(path),
+        };
+        if let Err(err) = result {
+            failed.insert(image.image_name.clone(), format!("{:#}", err));
+        }
+    }
+
+    provision_images(&docker.dir);
+
+    for (_, image, change) in changes {
+        let event = ModuleEvent {
+            base_image: Some(image.base_image.clone()),
+            ..ModuleEvent::new(change, ModuleType::Docker, &image.func_name)
+        };
+        events.push(match failed.remove(&image.image_name) {
+            Some(err) => event.failed(err),
+            None => event,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{app_state, TempDir};
+
+    /// Exits with `code`.
+    fn command(code: u8) -> String {
+        format!(
+            r#"(module
+              (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+              (memory (export "memory") 1)
+              (func (export "_start") i32.const {} call $exit))"#,
+            code
+        )
+    }
+
+    #[test]
+    fn picks_up_files_once_they_settle() {
+        let at = |secs, len| FileState {
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            len,
+        };
+        let snapshot = |files: &[(&str, FileState)]| -> Snapshot {
+            files
+                .iter()
+                .map(|(path, state)| (PathBuf::from(path), *state))
+                .collect()
+        };
+
+        let applied = snapshot(&[("kept.wasm", at(1, 10)), ("edited.wasm", at(1, 10))]);
+        let previous = snapshot(&[
+            ("kept.wasm", at(1, 10)),
+            ("edited.wasm", at(2, 20)),
+            ("new.wasm", at(2, 5)),
+        ]);
+        let current = snapshot(&[
+            ("edited.wasm", at(2, 20)),
+            ("new.wasm", at(3, 50)),
+            ("copied.wasm", at(3, 50)),
+        ]);
+
+        // `new.wasm` and `copied.wasm` are still being written, `kept.wasm` was only just
+        // deleted.
+        assert_eq!(
+            settled(&applied, &previous, &current),
+            [(PathBuf::from("edited.wasm"), ModuleChange::Changed)]
+        );
+
+        let applied = snapshot(&[("kept.wasm", at(1, 10)), ("edited.wasm", at(2, 20))]);
+        assert_eq!(
+            settled(&applied, &current, &current),
+            [
+                (PathBuf::from("copied.wasm"), ModuleChange::Added),
+                (PathBuf::from("new.wasm"), ModuleChange::Added),
+                (PathBuf::from("kept.wasm"), ModuleChange::Removed),
+            ]
+        );
+    }
+    #[test]
+    fn only_removes_the_versions_it_deployed() {
+        let dir = TempDir::new("watch");
+        let state = app_state(&dir);
+        let modules = &state.modules;
+        let wasm_dir = dir.join("modules/wasm");
+        fs::create_dir_all(&wasm_dir).unwrap();
+
+        let uploaded = modules.deploy("noop", None, command(0).as_bytes()).unwrap();
+        let mut watcher = WasmWatcher::new(wasm_dir.clone());
+        let settle = |watcher: &mut WasmWatcher| {
+            assert!(watcher.poll(modules).is_empty());
+            watcher.poll(modules)
+        };
+        let versions = || -> Vec<String> {
+            let registry = modules.snapshot();
+            let function = registry.get("noop").unwrap();
+            function
+                .versions
+                .iter()
+                .map(|deployed| deployed.version.clone())
+                .collect()
+        };
+
+        fs::write(wasm_dir.join("noop.wasm"), command(0)).unwrap();
+        assert_eq!(settle(&mut watcher)[0].change, ModuleChange::Added);
+        fs::write(wasm_dir.join("noop.wasm"), command(10)).unwrap();
+        let changed = settle(&mut watcher);
+        assert_eq!(changed[0].change, ModuleChange::Changed);
+        assert_eq!(versions().len(), 2);
+
+        fs::remove_file(wasm_dir.join("noop.wasm")).unwrap();
+        let removed = settle(&mut watcher);
+        assert_eq!(removed[0].change, ModuleChange::Removed);
+        assert_eq!(removed[0].error, None);
+        assert_eq!(versions(), [uploaded.version]);
+    }
+}